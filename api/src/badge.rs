@@ -0,0 +1,110 @@
+/// shields.io's named colors
+const NAMED_COLORS: &[(&str, &str)] = &[
+    ("brightgreen", "#4c1"),
+    ("green", "#97ca00"),
+    ("yellowgreen", "#a4a61d"),
+    ("yellow", "#dfb317"),
+    ("orange", "#fe7d37"),
+    ("red", "#e05d44"),
+    ("blue", "#007ec6"),
+    ("informational", "#007ec6"),
+    ("lightgrey", "#9f9f9f"),
+    ("grey", "#555"),
+];
+
+/// A `NAMED_COLORS` name, or a hex color without the `#` since that can't go in a URL as-is
+pub fn parse_color(color: &str) -> Option<String> {
+    if let Some(&(_, hex)) = NAMED_COLORS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(color))
+    {
+        return Some(hex.to_string());
+    }
+
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    (matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| format!("#{}", hex.to_ascii_lowercase()))
+}
+
+/// What [`compact_number`] divides by for each suffix, smallest first
+const SUFFIXES: &[(usize, &str)] = &[(1_000, "k"), (1_000_000, "M"), (1_000_000_000, "G")];
+
+/// `15685` as `15.7k`, like shields.io shortens numbers. Rounded to tenths before picking the suffix, so
+/// `999950` is `1.0M` rather than `1000.0k`.
+pub fn compact_number(n: usize) -> String {
+    if n < 1_000 {
+        return n.to_string();
+    }
+
+    let tenths = |scale: usize| (n as u128 * 10 + scale as u128 / 2) / scale as u128;
+    let (scale, suffix) = SUFFIXES
+        .iter()
+        .copied()
+        .find(|&(scale, _)| tenths(scale) < 10_000)
+        .unwrap_or(SUFFIXES[SUFFIXES.len() - 1]);
+    let tenths = tenths(scale);
+    format!("{}.{}{suffix}", tenths / 10, tenths % 10)
+}
+
+/// A flat badge the way shields.io draws them, `label` on grey and `message` on `color`
+pub fn render(label: &str, message: &str, color: &str) -> String {
+    let label_width = text_width(label) + 10;
+    let message_width = text_width(message) + 10;
+    let width = label_width + message_width;
+    let (label, message) = (escape(label), escape(message));
+    // Text is drawn at 10x and scaled down, which lines it up on the pixel grid like shields.io does
+    let label_x = label_width * 5;
+    let message_x = label_width * 10 + message_width * 5;
+    let label_length = (label_width - 10) * 10;
+    let message_length = (message_width - 10) * 10;
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" text-rendering="geometricPrecision" font-size="110"><text aria-hidden="true" x="{label_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{label_length}">{label}</text><text x="{label_x}" y="140" transform="scale(.1)" fill="#fff" textLength="{label_length}">{label}</text><text aria-hidden="true" x="{message_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{message_length}">{message}</text><text x="{message_x}" y="140" transform="scale(.1)" fill="#fff" textLength="{message_length}">{message}</text></g></svg>"##
+    )
+}
+
+/// Roughly how wide `text` is in 11px Verdana, which is all the badge needs to fit it
+fn text_width(text: &str) -> usize {
+    let tenths = text
+        .chars()
+        .map(|c| match c {
+            'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '\'' | '|' | '!' => 35,
+            ' ' | 'f' | 'r' | 't' | 'I' | '(' | ')' | '[' | ']' | '-' => 45,
+            'm' | 'w' | 'M' | 'W' => 100,
+            'A'..='Z' => 75,
+            '0'..='9' => 70,
+            _ => 65,
+        })
+        .sum::<usize>();
+    tenths.div_ceil(10)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_rounded_before_picking_the_suffix() {
+        for (n, compact) in [
+            (999, "999"),
+            (1_000, "1.0k"),
+            (15_685, "15.7k"),
+            (999_949, "999.9k"),
+            (999_950, "1.0M"),
+            (999_999, "1.0M"),
+            (1_250_000, "1.3M"),
+            (999_949_999, "999.9M"),
+            (999_950_000, "1.0G"),
+            (12_345_678_901, "12.3G"),
+        ] {
+            assert_eq!(compact_number(n), compact, "{n}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use common::{
+    config::{flag, parsed},
+    store::StoreKind,
+};
+use serde::{Serialize, Serializer};
+
+/// How long clients may cache the current stats
+pub const CACHE_MAX_AGE: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long an object is served from memory before it's fetched again, picking up the job's latest run
+pub const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+/// How long after a failed refresh of a cached object it's tried again
+pub const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long clients may cache anything built from the archive, where new snapshots show up at most daily
+pub const ARCHIVE_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Every setting the API reads from the environment, parsed once at startup. Serialized as-is by
+/// `GET /config`, so secrets have to be redacted.
+#[derive(Debug, Serialize)]
+pub struct Config {
+    /// Where the objects are read from
+    pub storage_backend: StoreKind,
+    /// Only required with the `s3` backend
+    pub bucket_name: Option<String>,
+    pub fallback_bucket_name: Option<String>,
+    /// Every object is read from under `{environment}/`
+    pub environment: Option<String>,
+    /// Origins allowed by CORS, any origin when empty
+    pub allowed_origins: Vec<String>,
+    pub cors_max_age_secs: u64,
+    pub cache_max_age_secs: u64,
+    pub archive_cache_max_age_secs: u64,
+    pub cache_ttl_secs: u64,
+    /// Only used by dev, release runs on lambda
+    pub bind_addr: String,
+    pub port: String,
+    /// Object each endpoint serves, by path
+    pub object_keys: BTreeMap<&'static str, &'static str>,
+    /// Leave repos which aren't in the per-repo stats out of `?repos=` instead of failing with a 404
+    pub ignore_unknown_repos: bool,
+    /// Has to be sent as a bearer token to `GET /config`, which is disabled when unset
+    #[serde(serialize_with = "redacted")]
+    pub config_secret: Option<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let mut problems = Vec::new();
+        common::config::load_file(&mut problems);
+        if !problems.is_empty() {
+            return Err(problems.join(", "));
+        }
+
+        let storage_backend = StoreKind::from_env().unwrap_or_else(|e| {
+            problems.push(e);
+            StoreKind::default()
+        });
+        let bucket_name = var("BUCKET_NAME");
+        if storage_backend == StoreKind::S3 && bucket_name.is_none() {
+            problems.push("BUCKET_NAME must be set for the s3 storage backend".to_string());
+        }
+
+        let config = Self {
+            storage_backend,
+            bucket_name,
+            fallback_bucket_name: common::FALLBACK_BUCKET_NAME.clone(),
+            environment: common::ENVIRONMENT.clone(),
+            allowed_origins: var("ALLOWED_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect(),
+            // Browsers can skip preflights for a day by default
+            cors_max_age_secs: parsed("CORS_MAX_AGE_SECS", 24 * 60 * 60, &mut problems),
+            cache_max_age_secs: CACHE_MAX_AGE.as_secs(),
+            archive_cache_max_age_secs: ARCHIVE_CACHE_MAX_AGE.as_secs(),
+            cache_ttl_secs: CACHE_TTL.as_secs(),
+            bind_addr: var("BIND_ADDR").unwrap_or_else(|| "127.0.0.1".to_string()),
+            port: var("PORT").unwrap_or_else(|| "3000".to_string()),
+            object_keys: BTreeMap::from([
+                ("/total", common::TOTAL_STATS_OBJ_NAME),
+                ("/per-repo", common::PER_REPO_OBJ_NAME),
+                ("/by-year", common::BY_YEAR_OBJ_NAME),
+                ("/by-domain", common::BY_DOMAIN_OBJ_NAME),
+                ("/tests", common::TEST_VS_SOURCE_OBJ_NAME),
+                ("/docs", common::DOCS_OBJ_NAME),
+                ("/meta", common::META_OBJ_NAME),
+                ("/summary", common::SUMMARY_OBJ_NAME),
+                ("/matrix", common::MATRIX_OBJ_NAME),
+                ("/changes", common::CHANGES_OBJ_NAME),
+                ("/empty-repos", common::EMPTY_REPOS_OBJ_NAME),
+                ("/errors", common::ERRORS_OBJ_NAME),
+                ("/archive", common::ARCHIVE_PREFIX),
+                ("/history", common::HISTORY_INDEX_OBJ_NAME),
+            ]),
+            ignore_unknown_repos: flag("IGNORE_UNKNOWN_REPOS", false, &mut problems),
+            config_secret: var("CONFIG_SECRET"),
+        };
+        if !problems.is_empty() {
+            return Err(problems.join(", "));
+        }
+
+        config.storage_backend.install();
+        Ok(config)
+    }
+}
+
+fn var(name: &str) -> Option<String> {
+    common::config::var(name)
+}
+
+/// Only says whether a secret is set
+fn redacted<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| "***").serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_settings_are_problems() {
+        std::env::set_var("CORS_MAX_AGE_SECS", "a day");
+        std::env::set_var("IGNORE_UNKNOWN_REPOS", "maybe");
+        let problems = Config::from_env().unwrap_err();
+        std::env::remove_var("CORS_MAX_AGE_SECS");
+        std::env::remove_var("IGNORE_UNKNOWN_REPOS");
+
+        assert!(problems.contains("CORS_MAX_AGE_SECS"), "{problems}");
+        assert!(problems.contains("IGNORE_UNKNOWN_REPOS"), "{problems}");
+    }
+}
@@ -1,73 +1,1179 @@
-use std::
-    time::Duration
-;
+mod badge;
+mod config;
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
 use axum::{
+    async_trait,
     body::{Body, Bytes},
-    response::Response,
-    routing::get,
-    Router,
+    extract::{
+        rejection::{PathRejection, QueryRejection},
+        FromRequestParts, Path, Query, State,
+    },
+    handler::Handler,
+    http::request::Parts,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, MethodRouter},
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use common::stats::{
+    Changes, CodeUnit, Domain, EmptyRepo, HistoryPoint, Matrix, Meta, PerRepo, RepoError,
+    SimpleLanguage, Summary, TestVsSource, Total, SCHEMA_VERSION,
+};
+use config::{Config, ARCHIVE_CACHE_MAX_AGE, CACHE_MAX_AGE, CACHE_TTL, REFRESH_RETRY_DELAY};
+use futures_util::{stream, StreamExt};
+use headers::{
+    authorization::Bearer, Authorization, CacheControl, ETag, HeaderMapExt, IfNoneMatch,
+};
+use lambda_http::Error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokei::LanguageType;
+use tokio::sync::Mutex;
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    set_header::SetResponseHeaderLayer,
 };
-use headers::{CacheControl, HeaderMapExt};
-use lambda_http::{run, Error};
-use once_cell::sync::OnceCell;
-use tower_http::cors::CorsLayer;
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
-static TOTAL: OnceCell<Bytes> = OnceCell::new();
-async fn total() -> Result<Response, String> {
-    let total = if let Some(total) = TOTAL.get() {
-        total.clone()
+/// An object from the bucket, along with its parsed contents
+struct Cached<T> {
+    bytes: Bytes,
+    /// Of `bytes`, computed once here rather than on every request
+    etag: ETag,
+    value: T,
+    fetched_at: Instant,
+}
+
+impl<T> Cached<T> {
+    fn json_response(&self, headers: &HeaderMap) -> Response {
+        json_with_etag(headers, self.bytes.clone(), self.etag.clone())
+    }
+}
+
+/// The latest fetch of an object, which is fetched again in the background once it's older than `CACHE_TTL` so a
+/// warm container picks up the job's new stats
+struct CachedObject<T> {
+    current: RwLock<Option<Arc<Cached<T>>>>,
+    /// Held while fetching, so concurrent requests share a single fetch
+    fetching: Mutex<()>,
+    /// When the last background refresh started, so a failing one isn't retried by every request
+    refresh_started_at: std::sync::Mutex<Option<Instant>>,
+}
+
+impl<T> CachedObject<T> {
+    const fn new() -> Self {
+        Self {
+            current: RwLock::new(None),
+            fetching: Mutex::const_new(()),
+            refresh_started_at: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Whether a background refresh may start now, which is then recorded so the next one waits for
+    /// `REFRESH_RETRY_DELAY`
+    fn start_refresh(&self) -> bool {
+        let mut started_at = self.refresh_started_at.lock().unwrap();
+        if started_at.is_some_and(|at| at.elapsed() < REFRESH_RETRY_DELAY) {
+            return false;
+        }
+        *started_at = Some(Instant::now());
+        true
+    }
+
+    fn current(&self) -> Option<Arc<Cached<T>>> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn set(&self, cached: Arc<Cached<T>>) {
+        *self.current.write().unwrap() = Some(cached);
+    }
+}
+
+/// Every failed request gets a body of `{"error": "...", "code": "..."}`
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    /// Stable, machine readable reason for the failure
+    code: &'static str,
+    error: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, error: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            error: error.into(),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            rejection.body_text(),
+        )
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status(), "invalid_path", rejection.body_text())
+    }
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody<'a> {
+    error: &'a str,
+    code: &'a str,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ApiErrorBody {
+            error: &self.error,
+            code: self.code,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Fetches an object from the bucket the first time it's requested, then keeps it in memory. Concurrent first
+/// requests share a single fetch. Past `CACHE_TTL` the cached object is still served, while one request starts
+/// fetching it again in the background. Objects which don't parse as `T` aren't cached, so a later request can
+/// try again, and a failed refresh keeps the old object around until one succeeds, trying again at most every
+/// `REFRESH_RETRY_DELAY`.
+async fn cached_object<T: DeserializeOwned + Send + Sync + 'static>(
+    cell: &'static CachedObject<T>,
+    key: &str,
+) -> Result<Arc<Cached<T>>, ApiError> {
+    if let Some(cached) = cell.current() {
+        if cached.fetched_at.elapsed() > CACHE_TTL {
+            if let Some(fetching) = cell
+                .fetching
+                .try_lock()
+                .ok()
+                .filter(|_| cell.start_refresh())
+            {
+                let key = key.to_string();
+                tokio::spawn(async move {
+                    // Failures are logged by `fetch_object`
+                    if let Ok(fresh) = fetch_object(&key).await {
+                        cell.set(Arc::new(fresh));
+                    }
+                    drop(fetching);
+                });
+            }
+        }
+        return Ok(cached);
+    }
+
+    let _fetching = cell.fetching.lock().await;
+    // Whoever held the lock before could have just fetched it
+    if let Some(cached) = cell.current() {
+        return Ok(cached);
+    }
+    let cached = Arc::new(fetch_object(key).await?);
+    cell.set(cached.clone());
+    Ok(cached)
+}
+
+async fn fetch_object<T: DeserializeOwned>(key: &str) -> Result<Cached<T>, ApiError> {
+    let bytes = Bytes::from(common::get_object(key).await.map_err(|e| {
+        tracing::error!("{e:?}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "object_unavailable",
+            format!("Failed to get {key}"),
+        )
+    })?);
+    parse_object(key, bytes)
+}
+
+fn parse_object<T: DeserializeOwned>(key: &str, bytes: Bytes) -> Result<Cached<T>, ApiError> {
+    let value = serde_json::from_slice(&bytes).map_err(|e| {
+        tracing::error!("{key} is invalid: {e:?}");
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "object_invalid",
+            format!("Stored {key} is invalid"),
+        )
+    })?;
+
+    Ok(Cached {
+        etag: etag_of(&bytes),
+        bytes,
+        value,
+        fetched_at: Instant::now(),
+    })
+}
+
+/// Query params shared by the endpoints. Unknown params, invalid values and filters on endpoints which
+/// can't apply them are all rejected, so a mistyped param doesn't silently do nothing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatsQuery {
+    /// Only repos with this topic
+    topic: Option<String>,
+    /// Only repos which are, or aren't, featured
+    featured: Option<bool>,
+    /// Only repos with this primary framework, any case
+    framework: Option<String>,
+    /// Only these repos, comma separated
+    repos: Option<String>,
+    /// The archived snapshot of this day instead of the latest stats, `YYYY-MM-DD`
+    date: Option<NaiveDate>,
+    /// Only history from this time on, `YYYY-MM-DD` or RFC 3339
+    from: Option<String>,
+    /// Only history up to this time, a `YYYY-MM-DD` includes the whole day
+    to: Option<String>,
+    /// How `GET /matrix` lays out its cells
+    layout: Option<MatrixLayout>,
+}
+
+impl StatsQuery {
+    fn has_filters(&self) -> bool {
+        self.topic.is_some()
+            || self.featured.is_some()
+            || self.framework.is_some()
+            || self.repos.is_some()
+    }
+
+    fn repo_names(&self) -> Option<impl Iterator<Item = &str>> {
+        self.repos
+            .as_deref()
+            .map(|repos| repos.split(',').map(str::trim))
+    }
+
+    fn matches(&self, repo: &PerRepo) -> bool {
+        self.topic
+            .as_ref()
+            .is_none_or(|topic| repo.topics.contains(topic))
+            && self
+                .featured
+                .is_none_or(|featured| repo.featured == featured)
+            && self.framework.as_ref().is_none_or(|framework| {
+                repo.primary_framework
+                    .as_ref()
+                    .is_some_and(|primary| primary.eq_ignore_ascii_case(framework))
+            })
+            && self
+                .repo_names()
+                .is_none_or(|mut names| names.any(|name| name == repo.name))
+    }
+
+    /// For endpoints without any repos to filter
+    fn no_filters(&self) -> Result<(), ApiError> {
+        if self.has_filters() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "This endpoint doesn't support filtering",
+            ));
+        }
+
+        self.no_date()
+    }
+
+    /// For endpoints which only serve the latest stats
+    fn no_date(&self) -> Result<(), ApiError> {
+        if self.date.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "This endpoint doesn't support date",
+            ));
+        }
+
+        self.no_range()
+    }
+
+    /// For everything but `GET /history`
+    fn no_range(&self) -> Result<(), ApiError> {
+        if self.from.is_some() || self.to.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "This endpoint doesn't support from or to",
+            ));
+        }
+
+        self.no_layout()
+    }
+
+    /// For everything but `GET /matrix`
+    fn no_layout(&self) -> Result<(), ApiError> {
+        if self.layout.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "This endpoint doesn't support layout",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for StatsQuery {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<StatsQuery>::from_request_parts(parts, state).await?;
+        if query
+            .topic
+            .as_deref()
+            .is_some_and(|topic| topic.trim().is_empty())
+        {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "topic can't be empty",
+            ));
+        }
+        if query
+            .framework
+            .as_deref()
+            .is_some_and(|framework| framework.trim().is_empty())
+        {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "framework can't be empty",
+            ));
+        }
+        // The same characters GitHub allows in repo names
+        if let Some(invalid) = query.repo_names().and_then(|mut names| {
+            names.find(|name| {
+                name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            })
+        }) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                format!("\"{invalid}\" isn't a valid repo name"),
+            ));
+        }
+
+        Ok(query)
+    }
+}
+
+fn with_cache_header(mut res: Response) -> Response {
+    let cache_header = CacheControl::new().with_max_age(CACHE_MAX_AGE);
+    res.headers_mut().typed_insert(cache_header);
+    res
+}
+
+/// Weak since compression changes the bytes sent
+fn etag_of(json: &[u8]) -> ETag {
+    format!("W/\"{:016x}\"", common::fnv1a(json))
+        .parse()
+        .expect("hex is a valid ETag")
+}
+
+/// JSON tagged with `etag`, its ETag, or `304 Not Modified` when `If-None-Match` says the client already has it
+fn json_with_etag(headers: &HeaderMap, json: Bytes, etag: ETag) -> Response {
+    let not_modified = headers
+        .typed_get::<IfNoneMatch>()
+        .is_some_and(|if_none_match| !if_none_match.precondition_passes(&etag));
+
+    let mut res = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
     } else {
-        TOTAL
-            .set(
-                common::get_total_stats()
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("{e:?}");
-                        "Failed to get total stats".to_string()
-                    })?
-                    .into(),
-            )
-            .ok();
-        TOTAL.get().unwrap().clone()
+        let mut res = Body::from(json).into_response();
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        res
     };
+    res.headers_mut().typed_insert(etag);
+    res
+}
+
+/// JSON of a response built for the request, which has to be hashed for its ETag every time
+fn json_response(headers: &HeaderMap, value: &impl Serialize) -> Result<Response, ApiError> {
+    let json = to_json_bytes(value)?;
+    let etag = etag_of(&json);
+    Ok(json_with_etag(headers, json, etag))
+}
+
+fn to_json_bytes(value: &impl Serialize) -> Result<Bytes, ApiError> {
+    serde_json::to_vec(value).map(Bytes::from).map_err(|e| {
+        tracing::error!("Failed to serialize a response: {e:?}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "serialize_failed",
+            "Failed to serialize the response",
+        )
+    })
+}
+
+static TOTAL: CachedObject<Total> = CachedObject::new();
+/// With filters, the sum of the matching repos in the per-repo stats. Those never have private repos, excluded
+/// repos or languages past `MAX_LANGUAGES_PER_REPO`, so the sum of every repo doesn't match the unfiltered total.
+async fn total(
+    query: StatsQuery,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    query.no_range()?;
+    if let Some(date) = query.date {
+        if query.has_filters() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "date can't be combined with filters",
+            ));
+        }
+        let total = archived_total(date, true).await?;
+        let cache_header = CacheControl::new().with_max_age(ARCHIVE_CACHE_MAX_AGE);
+        let mut res = total.json_response(&headers);
+        res.headers_mut().typed_insert(cache_header);
+        return Ok(res);
+    }
+
+    if !query.has_filters() {
+        let total = cached_object(&TOTAL, common::TOTAL_STATS_OBJ_NAME).await?;
+        return Ok(with_cache_header(total.json_response(&headers)));
+    }
+
+    let per_repo = &cached_object(&PER_REPO, common::PER_REPO_OBJ_NAME)
+        .await?
+        .value;
+    if let Some(names) = query.repo_names() {
+        let unknown = names
+            .filter(|&name| !per_repo.iter().any(|repo| repo.name == name))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() && !config.ignore_unknown_repos {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_repo",
+                format!("Unknown repos: {}", unknown.join(", ")),
+            ));
+        }
+    }
+
+    let mut total = Vec::<SimpleLanguage>::new();
+    for repo in per_repo.iter().filter(|repo| query.matches(repo)) {
+        for lang in &repo.languages {
+            match total.iter_mut().find(|total| total.name == lang.name) {
+                Some(total) => total.merge(lang),
+                None => total.push(lang.clone()),
+            }
+        }
+    }
+    for lang in &mut total {
+        lang.fill_derived();
+    }
+    total.sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+
+    Ok(with_cache_header(json_response(&headers, &total)?))
+}
+
+#[derive(Debug, Serialize)]
+struct TotalCount {
+    code: usize,
+    comments: usize,
+    blanks: usize,
+    files: usize,
+}
+
+async fn total_count(query: StatsQuery) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let total = &cached_object(&TOTAL, common::TOTAL_STATS_OBJ_NAME)
+        .await?
+        .value;
+
+    let other = total.other.as_ref();
+    let count = TotalCount {
+        code: total.code(),
+        comments: total
+            .languages
+            .iter()
+            .map(|lang| lang.comments)
+            .sum::<usize>()
+            + other.map_or(0, |other| other.comments),
+        blanks: total
+            .languages
+            .iter()
+            .map(|lang| lang.blanks)
+            .sum::<usize>()
+            + other.map_or(0, |other| other.blanks),
+        files: total.languages.iter().map(|lang| lang.files).sum::<usize>()
+            + other.map_or(0, |other| other.files),
+    };
+    Ok(with_cache_header(Json(count).into_response()))
+}
+
+/// A series in the shape Grafana's JSON datasource expects
+#[derive(Debug, Serialize)]
+struct GrafanaTarget {
+    target: String,
+    /// `[value, unix timestamp in milliseconds]` pairs
+    datapoints: Vec<(usize, i64)>,
+}
+
+/// The totals for Grafana's JSON datasource, one target per language with a single datapoint of its code at the
+/// time the stats were generated, e.g. `[{"target": "Rust", "datapoints": [[15674, 1718841600000]]}]`
+async fn grafana(query: StatsQuery) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let total = &cached_object(&TOTAL, common::TOTAL_STATS_OBJ_NAME)
+        .await?
+        .value;
+    let generated_at = cached_object(&META, common::META_OBJ_NAME)
+        .await?
+        .value
+        .generated_at
+        .timestamp_millis();
+
+    let targets = total
+        .languages
+        .iter()
+        .map(|lang| GrafanaTarget {
+            target: lang
+                .display_name
+                .clone()
+                .unwrap_or_else(|| lang.name.to_string()),
+            datapoints: vec![(lang.code, generated_at)],
+        })
+        .chain(total.other.as_ref().map(|other| GrafanaTarget {
+            target: "Other".to_string(),
+            datapoints: vec![(other.code, generated_at)],
+        }))
+        .collect::<Vec<_>>();
+    Ok(with_cache_header(Json(targets).into_response()))
+}
+
+type ArchivedTotal = Arc<Cached<Total>>;
+
+/// How many archived totals [`archived_total`] keeps, over a year of daily snapshots for `/history/{language}`
+const ARCHIVED_TOTALS_CACHED: usize = 400;
+/// Most recently used first. Only snapshots of days before today are kept, since every run of the job today
+/// overwrites today's, while earlier ones never change. They're only evicted to bound the memory.
+static ARCHIVED_TOTALS: std::sync::Mutex<VecDeque<(NaiveDate, ArchivedTotal)>> =
+    std::sync::Mutex::new(VecDeque::new());
+
+/// The archived total of a day, 404 when there's no snapshot of it. The lock is only held to look it up and
+/// store it, so concurrent misses of the same day can both fetch it. A miss is only kept with `keep`.
+async fn archived_total(date: NaiveDate, keep: bool) -> Result<ArchivedTotal, ApiError> {
+    {
+        let mut cached = ARCHIVED_TOTALS.lock().unwrap();
+        if let Some(i) = cached.iter().position(|(cached, _)| *cached == date) {
+            let entry = cached.remove(i).unwrap();
+            let total = entry.1.clone();
+            cached.push_front(entry);
+            return Ok(total);
+        }
+    }
+
+    let key = common::archive_key(date, common::TOTAL_STATS_OBJ_NAME);
+    let bytes = common::get_object(&key).await.map_err(|e| {
+        if e.is_not_found() {
+            return ApiError::new(
+                StatusCode::NOT_FOUND,
+                "snapshot_not_found",
+                format!("There's no snapshot of {date}"),
+            );
+        }
+        tracing::error!("{e:?}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "object_unavailable",
+            format!("Failed to get {key}"),
+        )
+    })?;
+    let total = Arc::new(parse_object(&key, Bytes::from(bytes))?);
+
+    // The job names snapshots by the UTC day
+    if keep && date < Utc::now().date_naive() {
+        let mut cached = ARCHIVED_TOTALS.lock().unwrap();
+        if !cached.iter().any(|(cached, _)| *cached == date) {
+            cached.truncate(ARCHIVED_TOTALS_CACHED - 1);
+            cached.push_front((date, total.clone()));
+        }
+    }
+    Ok(total)
+}
+
+static PER_REPO: CachedObject<Vec<PerRepo>> = CachedObject::new();
+async fn per_repo(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_date()?;
+    let per_repo = cached_object(&PER_REPO, common::PER_REPO_OBJ_NAME).await?;
+
+    if !query.has_filters() {
+        return Ok(with_cache_header(per_repo.json_response(&headers)));
+    }
+
+    let matching = per_repo
+        .value
+        .iter()
+        .filter(|repo| query.matches(repo))
+        .collect::<Vec<_>>();
+    Ok(with_cache_header(json_response(&headers, &matching)?))
+}
+
+/// One repo per line, so clients can start on the first repos before the rest arrive
+async fn per_repo_ndjson(query: StatsQuery) -> Result<Response, ApiError> {
+    query.no_date()?;
+    let per_repo = cached_object(&PER_REPO, common::PER_REPO_OBJ_NAME).await?;
+
+    // The body outlives the handler, so it holds onto the cached repos while it's sent
+    let repos = per_repo.clone();
+    let matching = (0..per_repo.value.len()).filter(move |&i| query.matches(&repos.value[i]));
+    let lines = stream::iter(matching).map(move |i| {
+        serde_json::to_vec(&per_repo.value[i]).map(|mut line| {
+            line.push(b'\n');
+            line
+        })
+    });
+
+    let mut res = Body::from_stream(lines).into_response();
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    Ok(with_cache_header(res))
+}
+
+/// One repo of the per-repo stats, which never has private or excluded repos, so those are a 404 like missing ones
+async fn repo(
+    name: Result<Path<String>, PathRejection>,
+    query: StatsQuery,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Path(name) = name?;
+    query.no_filters()?;
+    let per_repo = &cached_object(&PER_REPO, common::PER_REPO_OBJ_NAME)
+        .await?
+        .value;
+    let repo = per_repo
+        .iter()
+        .find(|repo| repo.name == name)
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_repo",
+                format!("Unknown repo: {name}"),
+            )
+        })?;
+
+    Ok(with_cache_header(json_response(&headers, repo)?))
+}
+
+static BY_YEAR: CachedObject<BTreeMap<i32, Vec<SimpleLanguage>>> = CachedObject::new();
+async fn by_year(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let by_year = cached_object(&BY_YEAR, common::BY_YEAR_OBJ_NAME).await?;
+    Ok(with_cache_header(by_year.json_response(&headers)))
+}
+
+static BY_DOMAIN: CachedObject<BTreeMap<String, Domain>> = CachedObject::new();
+async fn by_domain(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let by_domain = cached_object(&BY_DOMAIN, common::BY_DOMAIN_OBJ_NAME).await?;
+    Ok(with_cache_header(by_domain.json_response(&headers)))
+}
+
+static TEST_VS_SOURCE: CachedObject<TestVsSource> = CachedObject::new();
+async fn tests(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let tests = cached_object(&TEST_VS_SOURCE, common::TEST_VS_SOURCE_OBJ_NAME).await?;
+    Ok(with_cache_header(tests.json_response(&headers)))
+}
+
+static CHANGES: CachedObject<Changes> = CachedObject::new();
+async fn changes(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let changes = cached_object(&CHANGES, common::CHANGES_OBJ_NAME).await?;
+    Ok(with_cache_header(changes.json_response(&headers)))
+}
 
-    // 6hrs cache
-    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(6 * 60 * 60));
-    let mut res = Response::builder().body(Body::from(total)).unwrap();
+static EMPTY_REPOS: CachedObject<Vec<EmptyRepo>> = CachedObject::new();
+async fn empty_repos(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let empty_repos = cached_object(&EMPTY_REPOS, common::EMPTY_REPOS_OBJ_NAME).await?;
+    Ok(with_cache_header(empty_repos.json_response(&headers)))
+}
+
+static ERRORS: CachedObject<Vec<RepoError>> = CachedObject::new();
+async fn errors(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let errors = cached_object(&ERRORS, common::ERRORS_OBJ_NAME).await?;
+    Ok(with_cache_header(errors.json_response(&headers)))
+}
+
+static DOCS: CachedObject<Vec<SimpleLanguage>> = CachedObject::new();
+async fn docs(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let docs = cached_object(&DOCS, common::DOCS_OBJ_NAME).await?;
+    Ok(with_cache_header(docs.json_response(&headers)))
+}
+
+static SUMMARY: CachedObject<Summary> = CachedObject::new();
+async fn summary(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let summary = cached_object(&SUMMARY, common::SUMMARY_OBJ_NAME).await?;
+    Ok(with_cache_header(summary.json_response(&headers)))
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MatrixLayout {
+    /// As stored, only the cells with code
+    #[default]
+    Sparse,
+    /// Every cell, zeros included
+    Dense,
+}
+
+/// [`Matrix`] with `code[repo][language]` in place of the cells
+#[derive(Serialize)]
+struct DenseMatrix<'a> {
+    repos: &'a [String],
+    languages: &'a [LanguageType],
+    repo_totals: &'a [usize],
+    language_totals: &'a [usize],
+    code: Vec<Vec<usize>>,
+}
+
+static MATRIX: CachedObject<Matrix> = CachedObject::new();
+async fn matrix(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    let layout = query.layout.unwrap_or_default();
+    StatsQuery {
+        layout: None,
+        ..query
+    }
+    .no_filters()?;
+    let matrix = cached_object(&MATRIX, common::MATRIX_OBJ_NAME).await?;
+    if let MatrixLayout::Sparse = layout {
+        return Ok(with_cache_header(matrix.json_response(&headers)));
+    }
+
+    let Matrix {
+        repos,
+        languages,
+        repo_totals,
+        language_totals,
+        cells,
+    } = &matrix.value;
+    let mut code = vec![vec![0; languages.len()]; repos.len()];
+    for cell in cells {
+        if let Some(code) = code
+            .get_mut(cell.repo)
+            .and_then(|row| row.get_mut(cell.language))
+        {
+            *code = cell.code;
+        }
+    }
+
+    Ok(with_cache_header(
+        Json(DenseMatrix {
+            repos,
+            languages,
+            repo_totals,
+            language_totals,
+            code,
+        })
+        .into_response(),
+    ))
+}
+
+static META: CachedObject<Meta> = CachedObject::new();
+async fn meta(query: StatsQuery, headers: HeaderMap) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let meta = cached_object(&META, common::META_OBJ_NAME).await?;
+    Ok(with_cache_header(meta.json_response(&headers)))
+}
+
+async fn archive(query: StatsQuery) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let dates = common::list_archive_dates().await.map_err(|e| {
+        tracing::error!("{e:?}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "archive_unavailable",
+            "Failed to list archived snapshots",
+        )
+    })?;
+
+    let cache_header = CacheControl::new().with_max_age(ARCHIVE_CACHE_MAX_AGE);
+    let mut res = Json(dates).into_response();
     res.headers_mut().typed_insert(cache_header);
     Ok(res)
 }
 
-static PER_REPO: OnceCell<Bytes> = OnceCell::new();
-async fn per_repo() -> Result<Response, String> {
-    let total = if let Some(total) = PER_REPO.get() {
-        total.clone()
+#[derive(Debug, Serialize)]
+struct LanguageHistoryPoint {
+    date: String,
+    code: usize,
+}
+
+/// How many archived snapshots `/history/{language}` gets at once
+const ARCHIVED_TOTALS_CONCURRENCY: usize = 16;
+
+/// Code in one language for every archived snapshot, oldest first. Snapshots without the language count as 0,
+/// ones which are missing or can't be read are left out.
+async fn language_history(
+    language: Result<Path<String>, PathRejection>,
+    query: StatsQuery,
+) -> Result<Response, ApiError> {
+    let Path(language) = language?;
+    query.no_filters()?;
+    // Either the serialized name (`Sh`) or the display name (`Shell`)
+    let language =
+        serde_json::from_value::<LanguageType>(serde_json::Value::String(language.clone()))
+            .ok()
+            .or_else(|| language.parse().ok())
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "unknown_language",
+                    format!("Unknown language \"{language}\""),
+                )
+            })?;
+
+    let dates = common::list_archive_dates().await.map_err(|e| {
+        tracing::error!("{e:?}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "archive_unavailable",
+            "Failed to list archived snapshots",
+        )
+    })?;
+
+    // Going through more snapshots than are kept would only evict every one of them before it's used again
+    let keep = dates.len() <= ARCHIVED_TOTALS_CACHED;
+    let history = stream::iter(dates)
+        .filter_map(|date| async move {
+            let Ok(day) = NaiveDate::parse_from_str(&date, "%Y-%m-%d") else {
+                tracing::warn!("Skipping archived snapshot with invalid date \"{date}\"");
+                return None;
+            };
+            Some((date, day))
+        })
+        .map(|(date, day)| async move {
+            match archived_total(day, keep).await {
+                Ok(total) => {
+                    let code = total
+                        .value
+                        .languages
+                        .iter()
+                        .find(|lang| lang.name == language)
+                        .map_or(0, |lang| lang.code);
+                    Some(LanguageHistoryPoint { date, code })
+                }
+                // Already logged when it's more than a missing snapshot
+                Err(e) => {
+                    tracing::warn!("Skipping archived snapshot of {date}: {}", e.error);
+                    None
+                }
+            }
+        })
+        // In order, so the history stays oldest first
+        .buffered(ARCHIVED_TOTALS_CONCURRENCY)
+        .filter_map(|point| async move { point })
+        .collect::<Vec<_>>()
+        .await;
+
+    let cache_header = CacheControl::new().with_max_age(ARCHIVE_CACHE_MAX_AGE);
+    let mut res = Json(history).into_response();
+    res.headers_mut().typed_insert(cache_header);
+    Ok(res)
+}
+
+/// Query params of the badges, which take none of the usual ones
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BadgeQuery {
+    /// Text on the left instead of the badge's own
+    label: Option<String>,
+    /// A shields.io color name like `brightgreen`, or hex without the `#`. Parsed to hex once extracted.
+    color: Option<String>,
+}
+
+/// Labels longer than this are rejected, no badge needs one
+const MAX_BADGE_LABEL_LEN: usize = 64;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for BadgeQuery {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(mut query) = Query::<BadgeQuery>::from_request_parts(parts, state).await?;
+        if query
+            .label
+            .as_deref()
+            .is_some_and(|label| label.chars().count() > MAX_BADGE_LABEL_LEN)
+        {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                format!("label can't be over {MAX_BADGE_LABEL_LEN} characters"),
+            ));
+        }
+        if let Some(color) = &query.color {
+            query.color = Some(badge::parse_color(color).ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_query",
+                    format!("\"{color}\" isn't a color name or a hex color"),
+                )
+            })?);
+        }
+
+        Ok(query)
+    }
+}
+
+/// How much code there is in total, like `lines of code | 15.7k`, or `bytes of code` when that's what was counted
+async fn total_lines_badge(query: BadgeQuery) -> Result<Response, ApiError> {
+    let total = &cached_object(&TOTAL, common::TOTAL_STATS_OBJ_NAME)
+        .await?
+        .value;
+    let label = match cached_object(&META, common::META_OBJ_NAME)
+        .await?
+        .value
+        .unit
+    {
+        CodeUnit::Lines => "lines of code",
+        CodeUnit::Bytes => "bytes of code",
+    };
+    Ok(badge_response(
+        query,
+        label,
+        &badge::compact_number(total.code()),
+    ))
+}
+
+/// The language with the most code, like `top language | Rust`
+async fn top_language_badge(query: BadgeQuery) -> Result<Response, ApiError> {
+    let total = &cached_object(&TOTAL, common::TOTAL_STATS_OBJ_NAME)
+        .await?
+        .value;
+    let top = total
+        .languages
+        .iter()
+        .filter(|lang| lang.code > 0)
+        .max_by_key(|lang| lang.code)
+        .map_or_else(
+            || "none".to_string(),
+            |lang| {
+                lang.display_name
+                    .clone()
+                    .unwrap_or_else(|| lang.name.name().to_string())
+            },
+        );
+    Ok(badge_response(query, "top language", &top))
+}
+
+fn badge_response(query: BadgeQuery, default_label: &str, message: &str) -> Response {
+    let svg = badge::render(
+        query.label.as_deref().unwrap_or(default_label),
+        message,
+        query.color.as_deref().unwrap_or("#007ec6"),
+    );
+    let mut res = svg.into_response();
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("image/svg+xml"),
+    );
+    with_cache_header(res)
+}
+
+static HISTORY: CachedObject<Vec<HistoryPoint>> = CachedObject::new();
+/// The code in each language of every `HISTORY_SNAPSHOTS` run between `from` and `to`, oldest first
+async fn history(query: StatsQuery) -> Result<Response, ApiError> {
+    let from = query
+        .from
+        .as_deref()
+        .map(|from| time_bound(from, false))
+        .transpose()?;
+    let to = query
+        .to
+        .as_deref()
+        .map(|to| time_bound(to, true))
+        .transpose()?;
+    StatsQuery {
+        from: None,
+        to: None,
+        ..query
+    }
+    .no_filters()?;
+
+    let history = &cached_object(&HISTORY, common::HISTORY_INDEX_OBJ_NAME)
+        .await?
+        .value;
+    let points = history
+        .iter()
+        .filter(|point| {
+            from.is_none_or(|from| point.at >= from) && to.is_none_or(|to| point.at <= to)
+        })
+        .collect::<Vec<_>>();
+    Ok(with_cache_header(Json(points).into_response()))
+}
+
+/// `YYYY-MM-DD` is the start of the day, or its end for the end of a range
+fn time_bound(value: &str, end: bool) -> Result<DateTime<Utc>, ApiError> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.to_utc());
+    }
+    let day = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            format!("\"{value}\" isn't a YYYY-MM-DD date or an RFC 3339 time"),
+        )
+    })?;
+    let time = if end {
+        NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).unwrap()
     } else {
-        PER_REPO
-            .set(
-                common::get_per_repo_stats()
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("{e:?}");
-                        "Failed to get total stats".to_string()
-                    })?
-                    .into(),
-            )
-            .ok();
-        PER_REPO.get().unwrap().clone()
+        NaiveTime::MIN
     };
+    Ok(day.and_time(time).and_utc())
+}
 
-    // 6hrs cache
-    let cache_header = CacheControl::new().with_max_age(Duration::from_secs(6 * 60 * 60));
-    let mut res = Response::builder().body(Body::from(total)).unwrap();
-    res.headers_mut().typed_insert(cache_header);
+/// GET route which also answers bare `OPTIONS` requests. Preflights are answered by the CORS layer before they get here.
+fn get_route<H, T>(handler: H) -> MethodRouter<Arc<Config>>
+where
+    H: Handler<T, Arc<Config>>,
+    T: 'static,
+{
+    get(handler).options(|| async { StatusCode::NO_CONTENT })
+}
+
+/// Compares every byte whatever the first difference is, so how long a wrong token takes to reject doesn't give
+/// away how much of it was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The effective config, only for whoever has `CONFIG_SECRET`
+async fn show_config(
+    query: StatsQuery,
+    headers: HeaderMap,
+    State(config): State<Arc<Config>>,
+) -> Result<Response, ApiError> {
+    query.no_filters()?;
+    let Some(secret) = &config.config_secret else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "config_disabled",
+            "CONFIG_SECRET isn't set",
+        ));
+    };
+    if headers
+        .typed_get::<Authorization<Bearer>>()
+        .is_none_or(|auth| !constant_time_eq(auth.token().as_bytes(), secret.as_bytes()))
+    {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Missing or wrong bearer token",
+        ));
+    }
+
+    let mut res = Json(&*config).into_response();
+    res.headers_mut()
+        .typed_insert(CacheControl::new().with_no_store());
     Ok(res)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+const X_SCHEMA_VERSION: HeaderName = HeaderName::from_static("x-schema-version");
+
+fn cors_layer(config: &Config) -> CorsLayer {
+    let origins = config
+        .allowed_origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(origin) => Some(origin),
+            Err(_) => {
+                tracing::warn!("Ignoring invalid origin \"{origin}\"");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let allow_origin = if origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins)
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::OPTIONS])
+        // `If-None-Match` for revalidating, `Authorization` for a gateway in front which wants it
+        .allow_headers([
+            header::ACCEPT,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            header::AUTHORIZATION,
+        ])
+        // Otherwise cross origin scripts can't see them, and can't revalidate with the `ETag`
+        .expose_headers([X_SCHEMA_VERSION, header::ETAG])
+        .max_age(Duration::from_secs(config.cors_max_age_secs))
+}
+
+fn app(config: Arc<Config>) -> Router {
+    Router::new()
+        .route("/total", get_route(total))
+        .route("/total/count", get_route(total_count))
+        .route("/grafana", get_route(grafana))
+        .route("/per-repo", get_route(per_repo))
+        .route("/per-repo.ndjson", get_route(per_repo_ndjson))
+        .route("/repo/:name", get_route(repo))
+        .route("/by-year", get_route(by_year))
+        .route("/by-domain", get_route(by_domain))
+        .route("/tests", get_route(tests))
+        .route("/docs", get_route(docs))
+        .route("/meta", get_route(meta))
+        .route("/summary", get_route(summary))
+        .route("/matrix", get_route(matrix))
+        .route("/changes", get_route(changes))
+        .route("/empty-repos", get_route(empty_repos))
+        .route("/errors", get_route(errors))
+        .route("/archive", get_route(archive))
+        .route("/badge/total-lines", get_route(total_lines_badge))
+        .route("/badge/top-language", get_route(top_language_badge))
+        .route("/history", get_route(history))
+        .route("/history/:language", get_route(language_history))
+        .route("/config", get_route(show_config))
+        // Skips responses which already have a `Content-Encoding`, so nothing is compressed twice
+        .layer(CompressionLayer::new())
+        .layer(SetResponseHeaderLayer::overriding(
+            X_SCHEMA_VERSION,
+            HeaderValue::from(SCHEMA_VERSION),
+        ))
+        .layer(cors_layer(&config))
+        .with_state(config)
+}
+
+fn main() -> Result<(), Error> {
+    // Before the runtime has any threads, changing the environment isn't safe once it does
+    dotenvy::dotenv().ok();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(start())
+}
+
+async fn start() -> Result<(), Error> {
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::builder()
@@ -80,10 +1186,335 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
-    let app = Router::new()
-        .route("/total", get(total))
-        .route("/per-repo", get(per_repo))
-        .layer(CorsLayer::permissive());
+    let config = Arc::new(Config::from_env()?);
+
+    let app = app(config.clone());
+
+    // In prod, requests come through lambda
+    #[cfg(not(debug_assertions))]
+    lambda_http::run(app).await?;
+
+    // In dev, serve plain HTTP so it can be hit with curl
+    #[cfg(debug_assertions)]
+    {
+        let listener =
+            tokio::net::TcpListener::bind(format!("{}:{}", config.bind_addr, config.port)).await?;
+        tracing::info!("Listening on http://{}", listener.local_addr()?);
+        axum::serve(listener, app).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use common::store::StoreKind;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            storage_backend: StoreKind::Local,
+            bucket_name: None,
+            fallback_bucket_name: None,
+            environment: None,
+            allowed_origins: Vec::new(),
+            cors_max_age_secs: 60,
+            cache_max_age_secs: CACHE_MAX_AGE.as_secs(),
+            archive_cache_max_age_secs: ARCHIVE_CACHE_MAX_AGE.as_secs(),
+            cache_ttl_secs: CACHE_TTL.as_secs(),
+            bind_addr: "127.0.0.1".to_string(),
+            port: "3000".to_string(),
+            object_keys: BTreeMap::new(),
+            ignore_unknown_repos: false,
+            config_secret: None,
+        }
+    }
+
+    async fn get(config: Config, uri: &str) -> (StatusCode, serde_json::Value) {
+        StoreKind::Memory.install();
+        let res = app(Arc::new(config))
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    /// What every test needing the per-repo stats stores, since the cached object is shared too
+    const PER_REPO: &str = r#"[
+        {"name":"app","href":"https://github.com/me/app","description":null,"featured":true,
+         "languages":[{"name":"Rust","code":30,"files":2}]},
+        {"name":"experiment","href":"https://github.com/me/experiment","description":null,
+         "languages":[{"name":"Go","code":10,"files":1}]}
+    ]"#;
+
+    /// Into the in-memory store every test shares, so tests storing the same object have to agree on it
+    async fn store(key: &str, json: &str) {
+        StoreKind::Memory.install();
+        common::save_object(key, json).await.unwrap();
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[tokio::test]
+    async fn preflights_allow_the_configured_origins() {
+        let config = Arc::new(Config {
+            allowed_origins: vec!["https://allowed.example".to_string()],
+            ..config()
+        });
+        for path in ["/total", "/per-repo"] {
+            for (origin, allowed) in [
+                ("https://allowed.example", true),
+                ("https://other.example", false),
+            ] {
+                let res = app(config.clone())
+                    .oneshot(
+                        Request::options(path)
+                            .header(header::ORIGIN, origin)
+                            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                            .header(
+                                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                                "accept,if-none-match,authorization",
+                            )
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert!(res.status().is_success(), "{path}: {}", res.status());
+
+                let headers = res.headers();
+                assert_eq!(
+                    headers
+                        .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                        .and_then(|origin| origin.to_str().ok()),
+                    allowed.then_some(origin),
+                    "{path} from {origin}"
+                );
+                assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "60");
+                let methods = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+                    .to_str()
+                    .unwrap();
+                assert!(methods.contains("GET") && methods.contains("OPTIONS"));
+                let allowed_headers = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+                    .to_str()
+                    .unwrap();
+                for name in ["accept", "if-none-match", "authorization"] {
+                    assert!(allowed_headers.contains(name), "{allowed_headers}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_expose_the_schema_version_and_etag() {
+        store(common::PER_REPO_OBJ_NAME, PER_REPO).await;
+        let res = app(Arc::new(config()))
+            .oneshot(
+                Request::get("/per-repo")
+                    .header(header::ORIGIN, "https://any.example")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let exposed = res.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(
+            exposed.contains("x-schema-version") && exposed.contains("etag"),
+            "{exposed}"
+        );
+    }
+
+    #[tokio::test]
+    async fn language_history_skips_missing_and_invalid_snapshots() {
+        let day = |day| NaiveDate::from_ymd_opt(2020, 1, day).unwrap();
+        store(
+            &common::archive_key(day(1), common::TOTAL_STATS_OBJ_NAME),
+            r#"[{"name":"Rust","code":5,"files":1}]"#,
+        )
+        .await;
+        // Only the per-repo stats of this day were saved
+        store(
+            &common::archive_key(day(2), common::PER_REPO_OBJ_NAME),
+            PER_REPO,
+        )
+        .await;
+        store(
+            &common::archive_key(day(3), common::TOTAL_STATS_OBJ_NAME),
+            "not json",
+        )
+        .await;
+        store(
+            &common::archive_key(day(4), common::TOTAL_STATS_OBJ_NAME),
+            r#"[{"name":"Go","code":2,"files":1}]"#,
+        )
+        .await;
+
+        let (status, body) = get(config(), "/history/Rust").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {"date": "2020-01-01", "code": 5},
+                {"date": "2020-01-04", "code": 0},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn matrix_layout_combines_with_nothing_else() {
+        store(
+            common::MATRIX_OBJ_NAME,
+            r#"{"repos":["a"],"languages":["Rust"],"repo_totals":[3],"language_totals":[3],"cells":[{"repo":0,"language":0,"code":3}]}"#,
+        )
+        .await;
+
+        let (status, body) = get(config(), "/matrix").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["cells"][0]["code"], 3);
+        let (status, body) = get(config(), "/matrix?layout=sparse").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.get("code").is_none());
+        let (status, body) = get(config(), "/matrix?layout=dense").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["code"], serde_json::json!([[3]]));
+
+        for uri in [
+            "/matrix?layout=diagonal",
+            "/matrix?layout=dense&topic=rust",
+            "/matrix?layout=dense&date=2024-01-01",
+            "/matrix?rows=1",
+            "/total?layout=dense",
+            "/per-repo?layout=sparse",
+            "/history?layout=dense",
+        ] {
+            let (status, body) = get(config(), uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(body["code"], "invalid_query", "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn per_repo_filters_by_featured() {
+        store(common::PER_REPO_OBJ_NAME, PER_REPO).await;
+        let names = |body: serde_json::Value| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|repo| repo["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let (status, body) = get(config(), "/per-repo?featured=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["featured"], true);
+        assert_eq!(names(body), ["app"]);
+        let (_, body) = get(config(), "/per-repo?featured=false").await;
+        assert_eq!(names(body), ["experiment"]);
+        let (_, body) = get(config(), "/per-repo").await;
+        assert_eq!(names(body), ["app", "experiment"]);
+    }
+
+    /// What every test needing the total stores, since the cached object is shared too
+    const TOTAL: &str = r#"[{"name":"Rust","code":990,"comments":5,"blanks":5,"files":3},
+        {"name":"Other","languages":["Lua"],"code":10,"comments":1,"blanks":2,"files":1}]"#;
+
+    #[tokio::test]
+    async fn total_count_includes_other() {
+        store(common::TOTAL_STATS_OBJ_NAME, TOTAL).await;
+        let (status, body) = get(config(), "/total/count").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({"code": 1000, "comments": 6, "blanks": 7, "files": 4})
+        );
+    }
+
+    #[tokio::test]
+    async fn total_lines_badge_is_labeled_with_the_unit() {
+        store(common::TOTAL_STATS_OBJ_NAME, TOTAL).await;
+        store(
+            common::META_OBJ_NAME,
+            r#"{"generated_at":"2024-01-01T00:00:00Z","partial":false,"skipped_for_deadline":0,"unit":"bytes"}"#,
+        )
+        .await;
+
+        let res = app(Arc::new(config()))
+            .oneshot(
+                Request::get("/badge/total-lines")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let svg = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let svg = std::str::from_utf8(&svg).unwrap();
+        assert!(svg.contains("<title>bytes of code: 1.0k</title>"), "{svg}");
+    }
+
+    #[tokio::test]
+    async fn bad_paths_get_json_errors() {
+        for uri in ["/repo/%FF", "/history/%FF"] {
+            let (status, body) = get(config(), uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(body["code"], "invalid_path", "{uri}");
+        }
+    }
+
+    #[test]
+    fn failed_refreshes_wait_before_trying_again() {
+        let cell = CachedObject::<()>::new();
+        assert!(cell.start_refresh());
+        assert!(!cell.start_refresh());
+
+        *cell.refresh_started_at.lock().unwrap() =
+            Some(Instant::now() - REFRESH_RETRY_DELAY - Duration::from_secs(1));
+        assert!(cell.start_refresh());
+        assert!(!cell.start_refresh());
+    }
+
+    #[test]
+    fn cached_objects_are_served_as_json() {
+        let cached = parse_object::<Vec<RepoError>>("errors", Bytes::from_static(b"[]")).unwrap();
+        let res = cached.json_response(&HeaderMap::new());
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[test]
+    fn cached_objects_answer_with_their_stored_etag() {
+        let cached =
+            parse_object::<Vec<SimpleLanguage>>("total", Bytes::from_static(b"[]")).unwrap();
+        assert_eq!(cached.etag, etag_of(b"[]"));
+
+        let res = cached.json_response(&HeaderMap::new());
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[header::ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let res = cached.json_response(&headers);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag);
 
-    run(app).await
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("W/\"0\""));
+        assert_eq!(cached.json_response(&headers).status(), StatusCode::OK);
+    }
 }
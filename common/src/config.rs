@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, fmt, path::Path, str::FromStr, sync::OnceLock};
+
+/// Read from the working directory when `CONFIG_FILE` isn't set
+pub const DEFAULT_CONFIG_FILE: &str = "github-me.toml";
+
+/// The settings from the config file, by env var name
+static FILE_SETTINGS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// Value of a setting, treating empty as unset. Env vars take precedence over the config file.
+pub fn var(name: &str) -> Option<String> {
+    resolve(
+        name,
+        std::env::var(name).ok(),
+        FILE_SETTINGS.get().unwrap_or(&BTreeMap::new()),
+    )
+}
+
+/// A setting parsed as `T`, or else `default`. Invalid values are added to `problems`.
+pub fn parsed<T>(name: &str, default: T, problems: &mut Vec<String>) -> T
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match var(name) {
+        Some(value) => value.trim().parse().unwrap_or_else(|e| {
+            problems.push(format!("{name} has invalid value \"{value}\": {e}"));
+            default
+        }),
+        None => default,
+    }
+}
+
+/// A true or false setting, in any case, or else `default`. Invalid values are added to `problems`.
+pub fn flag(name: &str, default: bool, problems: &mut Vec<String>) -> bool {
+    match var(name)
+        .map(|value| value.trim().to_lowercase())
+        .as_deref()
+    {
+        None => default,
+        Some("0" | "false" | "no" | "off") => false,
+        Some("1" | "true" | "yes" | "on") => true,
+        Some(value) => {
+            problems.push(format!(
+                "{name} has invalid value \"{value}\", expected true or false"
+            ));
+            default
+        }
+    }
+}
+
+fn resolve(name: &str, env: Option<String>, file: &BTreeMap<String, String>) -> Option<String> {
+    env.filter(|value| !value.trim().is_empty()).or_else(|| {
+        file.get(name)
+            .filter(|value| !value.trim().is_empty())
+            .cloned()
+    })
+}
+
+/// Reads the settings in `CONFIG_FILE`, or `github-me.toml` when there's one, for [`var`] to fall back on. Shared
+/// by the job and the API, which each only read their own settings, so one file can configure both. Keys are the
+/// env var names in lowercase, e.g. `max_failure_percent = 10`. Lists become comma separated values, tables become
+/// `key=value` pairs and lists of tables (`repo_list_json`) are passed on as JSON. The environment itself is never
+/// changed, that isn't safe once the runtime's threads are running.
+///
+/// Has to run before any setting is read, `ENVIRONMENT` and the store are only read once.
+pub fn load_file(problems: &mut Vec<String>) {
+    let path = match var("CONFIG_FILE") {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => DEFAULT_CONFIG_FILE.to_string(),
+        None => return,
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            problems.push(format!("CONFIG_FILE \"{path}\" couldn't be read: {e}"));
+            return;
+        }
+    };
+
+    match parse(&contents, path.ends_with(".toml")) {
+        Ok(settings) => {
+            FILE_SETTINGS.set(settings).ok();
+        }
+        Err(e) => problems.push(format!("CONFIG_FILE \"{path}\" is invalid: {e}")),
+    }
+}
+
+/// The settings in a config file, by env var name
+fn parse(contents: &str, toml: bool) -> Result<BTreeMap<String, String>, String> {
+    let settings = if toml {
+        toml::from_str::<serde_json::Map<String, serde_json::Value>>(contents)
+            .map_err(|e| e.to_string())?
+    } else {
+        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(contents)
+            .map_err(|e| e.to_string())?
+    };
+
+    settings
+        .into_iter()
+        .map(|(key, value)| match env_value(&value) {
+            Some(value) => Ok((key.to_uppercase(), value)),
+            None => Err(format!("unsupported value for \"{key}\"")),
+        })
+        .collect()
+}
+
+fn env_value(value: &serde_json::Value) -> Option<String> {
+    use serde_json::Value;
+
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Array(items) if items.iter().any(Value::is_object) => Some(value.to_string()),
+        Value::Array(items) => items
+            .iter()
+            .map(env_value)
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        Value::Object(pairs) => pairs
+            .iter()
+            .map(|(key, value)| Some(format!("{key}={}", env_value(value)?)))
+            .collect::<Option<Vec<_>>>()
+            .map(|pairs| pairs.join(",")),
+        Value::Null => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> BTreeMap<String, String> {
+        parse(
+            r#"
+            max_failure_percent = 10
+            exclude_repos = ["a", "b"]
+            language_aliases = { Sh = "Bash" }
+            "#,
+            true,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn file_only() {
+        let file = file();
+        assert_eq!(
+            resolve("MAX_FAILURE_PERCENT", None, &file).as_deref(),
+            Some("10")
+        );
+        assert_eq!(
+            resolve("EXCLUDE_REPOS", None, &file).as_deref(),
+            Some("a,b")
+        );
+        assert_eq!(
+            resolve("LANGUAGE_ALIASES", None, &file).as_deref(),
+            Some("Sh=Bash")
+        );
+    }
+
+    #[test]
+    fn env_only() {
+        assert_eq!(
+            resolve("PRETTY_JSON", Some("true".to_string()), &file()).as_deref(),
+            Some("true")
+        );
+        assert_eq!(resolve("PRETTY_JSON", None, &file()), None);
+    }
+
+    #[test]
+    fn env_takes_precedence_over_file() {
+        assert_eq!(
+            resolve("MAX_FAILURE_PERCENT", Some("50".to_string()), &file()).as_deref(),
+            Some("50")
+        );
+        // An empty env var is unset, so the file still applies
+        assert_eq!(
+            resolve("MAX_FAILURE_PERCENT", Some(" ".to_string()), &file()).as_deref(),
+            Some("10")
+        );
+    }
+
+    #[test]
+    fn json_files_are_read_the_same() {
+        let json = parse(
+            r#"{"max_failure_percent": 10, "exclude_repos": ["a", "b"]}"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            json.get("MAX_FAILURE_PERCENT").map(String::as_str),
+            Some("10")
+        );
+        assert_eq!(json.get("EXCLUDE_REPOS").map(String::as_str), Some("a,b"));
+    }
+
+    #[test]
+    fn flags_ignore_case_and_reject_anything_else() {
+        let mut problems = Vec::new();
+        std::env::set_var("TEST_FLAG_UPPERCASE", " TRUE ");
+        assert!(flag("TEST_FLAG_UPPERCASE", false, &mut problems));
+        std::env::set_var("TEST_FLAG_OFF", "Off");
+        assert!(!flag("TEST_FLAG_OFF", true, &mut problems));
+        assert!(flag("TEST_FLAG_UNSET", true, &mut problems));
+        assert!(problems.is_empty());
+
+        std::env::set_var("TEST_FLAG_INVALID", "maybe");
+        assert!(!flag("TEST_FLAG_INVALID", false, &mut problems));
+        assert_eq!(problems.len(), 1);
+    }
+
+    #[test]
+    fn invalid_settings_fall_back_to_the_default() {
+        let mut problems = Vec::new();
+        std::env::set_var("TEST_PARSED_INVALID", "soon");
+        assert_eq!(parsed("TEST_PARSED_INVALID", 5_u64, &mut problems), 5);
+        assert_eq!(problems.len(), 1);
+        std::env::set_var("TEST_PARSED_VALID", "10");
+        assert_eq!(parsed("TEST_PARSED_VALID", 5_u64, &mut problems), 10);
+        assert_eq!(problems.len(), 1);
+    }
+
+    #[test]
+    fn unsupported_values_are_rejected() {
+        assert!(parse(r#"{"max_repos": null}"#, false).is_err());
+    }
+}
@@ -1,103 +1,218 @@
-use once_cell::sync::{Lazy, OnceCell};
-
-pub static BUCKET_NAME: Lazy<String> = Lazy::new(|| std::env::var("BUCKET_NAME").unwrap());
-const TOTAL_STATS_OBJ_NAME: &str = "total-stats.json";
-const PER_REPO_OBJ_NAME: &str = "per-repo-stats.json";
-static CLIENT: OnceCell<aws_sdk_s3::Client> = OnceCell::new();
-
-async fn get_init_client() -> &'static aws_sdk_s3::Client {
-    if CLIENT.get().is_none() {
-        let sdk_config = aws_config::from_env().load().await;
-        // Could fail if someone else set it between these statements (shouldn't happen, but being pedantic)
-        CLIENT.set(aws_sdk_s3::Client::new(&sdk_config)).ok();
-    }
+pub mod config;
+pub mod stats;
+pub mod store;
+
+use std::fmt;
+
+use once_cell::sync::Lazy;
+
+pub use store::{client, store};
+
+pub static BUCKET_NAME: Lazy<String> = Lazy::new(|| config::var("BUCKET_NAME").unwrap());
+/// Read from when `BUCKET_NAME` is unreachable or missing an object, e.g. a replica in another region
+pub static FALLBACK_BUCKET_NAME: Lazy<Option<String>> =
+    Lazy::new(|| config::var("FALLBACK_BUCKET_NAME"));
+/// Every object is kept under `{ENVIRONMENT}/` when it's set, so deployments sharing a bucket don't overwrite each
+/// other. The keys everywhere else leave it out, only the functions here add it.
+pub static ENVIRONMENT: Lazy<Option<String>> = Lazy::new(|| {
+    config::var("ENVIRONMENT")
+        .map(|environment| environment.trim().trim_matches('/').to_string())
+        .filter(|environment| !environment.is_empty())
+});
+pub const TOTAL_STATS_OBJ_NAME: &str = "total-stats.json";
+pub const PER_REPO_OBJ_NAME: &str = "per-repo-stats.json";
+pub const BY_YEAR_OBJ_NAME: &str = "by-year.json";
+pub const TEST_VS_SOURCE_OBJ_NAME: &str = "test-vs-source.json";
+pub const META_OBJ_NAME: &str = "meta.json";
+pub const SUMMARY_OBJ_NAME: &str = "summary.json";
+pub const DOCS_OBJ_NAME: &str = "docs.json";
+pub const DATED_TOTAL_OBJ_NAME: &str = "dated-total.json";
+pub const MATRIX_OBJ_NAME: &str = "matrix.json";
+pub const BY_DOMAIN_OBJ_NAME: &str = "by-domain.json";
+/// Markdown, only written by `PR_DIFF_MODE`
+pub const PR_DIFF_OBJ_NAME: &str = "pr-diff.md";
+pub const CHANGES_OBJ_NAME: &str = "changes.json";
+pub const EMPTY_REPOS_OBJ_NAME: &str = "empty-repos.json";
+pub const ERRORS_OBJ_NAME: &str = "errors.json";
+/// Written by hand rather than by the job, see `EXCLUDES_FROM_BUCKET`
+pub const EXCLUDES_OBJ_NAME: &str = "config/excludes.json";
+/// Code counted outside the job, for `EXTERNAL_STATS_FROM_BUCKET`
+pub const EXTERNAL_STATS_OBJ_NAME: &str = "config/external-stats.json";
+/// Dated copies of the stats live under `archive/{YYYY-MM-DD}/`
+pub const ARCHIVE_PREFIX: &str = "archive/";
+/// The total of every run with `HISTORY_SNAPSHOTS` lives under `history/{timestamp}.json`
+pub const HISTORY_PREFIX: &str = "history/";
+/// Every snapshot under `history/`, oldest first
+pub const HISTORY_INDEX_OBJ_NAME: &str = "history/index.json";
+/// Which repos are mostly the user's own, for `OWN_REPOS_ONLY`
+pub const CONTRIBUTORS_CACHE_OBJ_NAME: &str = "cache/contributors.json";
+/// Results of `CACHE_PER_REPO_RESULTS` live under `cache/repos/{repo}@{sha}.json`
+pub const REPO_CACHE_PREFIX: &str = "cache/repos/";
+/// When each repo was last pushed to and the commit it was counted at then, for `SKIP_UNPUSHED_REPOS`
+pub const REPO_PUSHED_OBJ_NAME: &str = "cache/pushed-at.json";
+/// The `BACKFILL_MODE` dates already archived
+pub const BACKFILL_CHECKPOINT_OBJ_NAME: &str = "cache/backfill.json";
+
+#[derive(Debug)]
+pub enum Error {
+    S3(aws_sdk_s3::Error),
+    /// From the `local` store, or a missing object in the `memory` one
+    Io(std::io::Error),
+}
 
-    CLIENT.get().unwrap()
-}
-
-pub async fn save_stats(total_stats: &str, per_repo_stats: &str) -> Result<(), aws_sdk_s3::Error> {
-    #[cfg(not(debug_assertions))]
-    {
-        let client = get_init_client().await;
-
-        client
-            .put_object()
-            .bucket(&*BUCKET_NAME)
-            .key(TOTAL_STATS_OBJ_NAME)
-            .body(total_stats.as_bytes().to_vec().into())
-            .send()
-            .await?;
-
-        client
-            .put_object()
-            .bucket(&*BUCKET_NAME)
-            .key(PER_REPO_OBJ_NAME)
-            .body(per_repo_stats.as_bytes().to_vec().into())
-            .send()
-            .await?;
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::S3(e) => write!(f, "S3 error: {e}"),
+            Self::Io(e) => write!(f, "IO error: {e}"),
+        }
     }
+}
 
-    #[cfg(debug_assertions)]
-    {
-        std::fs::write(TOTAL_STATS_OBJ_NAME, total_stats).unwrap();
-        std::fs::write(PER_REPO_OBJ_NAME, per_repo_stats).unwrap();
+impl std::error::Error for Error {}
+
+impl Error {
+    /// There's no object with the key
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Self::S3(e) => matches!(e, aws_sdk_s3::Error::NoSuchKey(_)),
+            Self::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+        }
     }
+}
 
-    Ok(())
+impl From<aws_sdk_s3::Error> for Error {
+    fn from(e: aws_sdk_s3::Error) -> Self {
+        Self::S3(e)
+    }
 }
 
-pub async fn get_total_stats() -> Result<Vec<u8>, aws_sdk_s3::Error> {
-	#[cfg(not(debug_assertions))]
-    {
-        let client = get_init_client().await;
-
-        let total = client
-            .get_object()
-            .bucket(&*BUCKET_NAME)
-            .key(TOTAL_STATS_OBJ_NAME)
-            .send()
-            .await?
-            .body
-            .collect()
-            .await
-            .unwrap()
-            .to_vec();
-
-		return Ok(total)
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
     }
+}
 
-    #[cfg(debug_assertions)]
-    {
-        let total = std::fs::read(TOTAL_STATS_OBJ_NAME).unwrap();
+/// 64-bit FNV-1a, for hashes which have to stay the same across runs and Rust releases, unlike `DefaultHasher`'s
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
 
-		return Ok(total)
+/// Where the object with `key` is actually stored
+fn storage_key(key: &str) -> String {
+    match &*ENVIRONMENT {
+        Some(environment) => format!("{environment}/{key}"),
+        None => key.to_string(),
     }
 }
 
-pub async fn get_per_repo_stats() -> Result<Vec<u8>, aws_sdk_s3::Error> {
-	#[cfg(not(debug_assertions))]
-    {
-        let client = get_init_client().await;
-
-        let per_repo = client
-            .get_object()
-            .bucket(&*BUCKET_NAME)
-            .key(PER_REPO_OBJ_NAME)
-            .send()
-            .await?
-            .body
-            .collect()
-            .await
-            .unwrap()
-            .to_vec();
-
-		return Ok(per_repo)
+pub async fn save_object(key: &str, body: &str) -> Result<(), Error> {
+    store()
+        .save(&storage_key(key), body.as_bytes().to_vec())
+        .await
+}
+
+pub async fn get_object(key: &str) -> Result<Vec<u8>, Error> {
+    store().load(&storage_key(key)).await
+}
+
+pub async fn delete_object(key: &str) -> Result<(), Error> {
+    store().delete(&storage_key(key)).await
+}
+
+/// Copies an object, without it going through here when the store can help it
+pub async fn copy_object(from: &str, to: &str) -> Result<(), Error> {
+    store().copy(&storage_key(from), &storage_key(to)).await
+}
+
+/// Saves an object written a piece at a time, so it never has to be in memory at once. With S3, it goes up as a
+/// multipart upload once it's bigger than a part, anything smaller is put in one go by [`ObjectWriter::finish`].
+/// Nothing is visible under the key until then.
+pub struct ObjectWriter {
+    writer: Box<dyn store::PieceWriter>,
+}
+
+impl ObjectWriter {
+    pub async fn new(key: &str) -> Result<Self, Error> {
+        Ok(Self {
+            writer: store().writer(&storage_key(key)).await?,
+        })
     }
 
-    #[cfg(debug_assertions)]
-    {
-        let per_repo = std::fs::read(PER_REPO_OBJ_NAME).unwrap();
+    pub async fn write(&mut self, piece: &[u8]) -> Result<(), Error> {
+        self.writer.write(piece).await
+    }
 
-		return Ok(per_repo)
+    pub async fn finish(self) -> Result<(), Error> {
+        self.writer.finish().await
     }
 }
+
+/// An object listed by [`list_objects`]
+#[derive(Debug)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub last_modified: chrono::DateTime<chrono::Utc>,
+}
+
+/// Every object directly under `prefix`, which has to end in `/`
+pub async fn list_objects(prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+    let stored_prefix = &storage_key(prefix);
+    let objects = store().list(stored_prefix).await?;
+
+    Ok(objects
+        .into_iter()
+        .filter_map(|object| {
+            Some(ObjectInfo {
+                key: format!("{prefix}{}", object.key.strip_prefix(stored_prefix)?),
+                last_modified: object.last_modified,
+            })
+        })
+        .collect())
+}
+
+pub async fn save_stats(total_stats: &str, per_repo_stats: &str) -> Result<(), Error> {
+    save_object(TOTAL_STATS_OBJ_NAME, total_stats).await?;
+    save_object(PER_REPO_OBJ_NAME, per_repo_stats).await?;
+
+    Ok(())
+}
+
+pub fn archive_key(date: chrono::NaiveDate, name: &str) -> String {
+    format!("{ARCHIVE_PREFIX}{}/{name}", date.format("%Y-%m-%d"))
+}
+
+/// Second precision, so runs never share a snapshot
+pub fn history_key(at: chrono::DateTime<chrono::Utc>) -> String {
+    format!("{HISTORY_PREFIX}{}.json", at.format("%Y-%m-%dT%H:%M:%SZ"))
+}
+
+/// Saves dated copies of the stats. Every later run on the same (UTC) day overwrites them, so a day's snapshot ends
+/// up holding its last run, and only snapshots of earlier days never change.
+pub async fn archive_stats(
+    date: chrono::NaiveDate,
+    total_stats: &str,
+    per_repo_stats: &str,
+) -> Result<(), Error> {
+    save_object(&archive_key(date, TOTAL_STATS_OBJ_NAME), total_stats).await?;
+    save_object(&archive_key(date, PER_REPO_OBJ_NAME), per_repo_stats).await?;
+
+    Ok(())
+}
+
+/// Dates (`YYYY-MM-DD`) which have an archived snapshot, oldest first
+pub async fn list_archive_dates() -> Result<Vec<String>, Error> {
+    let mut dates = store().list_dirs(&storage_key(ARCHIVE_PREFIX)).await?;
+    dates.sort_unstable();
+    Ok(dates)
+}
+
+pub async fn get_total_stats() -> Result<Vec<u8>, Error> {
+    store().load_total().await
+}
+
+pub async fn get_per_repo_stats() -> Result<Vec<u8>, Error> {
+    store().load_per_repo().await
+}
@@ -0,0 +1,512 @@
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokei::{CodeStats, Language, LanguageType};
+
+/// Bumped whenever a field of the stored stats changes meaning or is removed, so clients can tell what they're
+/// reading. New optional fields don't need a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SimpleLanguage {
+    pub name: LanguageType,
+    /// Friendlier name for showing to people, filled in right before saving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub code: usize,
+    /// Missing when saved with `COMBINE_NON_CODE`, like `comments`
+    #[serde(default)]
+    pub blanks: usize,
+    #[serde(default)]
+    pub comments: usize,
+    /// Missing from stats saved before it was added
+    #[serde(default)]
+    pub files: usize,
+    /// Lines of any kind per file, filled in right before saving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avg_lines_per_file: Option<f64>,
+    /// Comment lines per line of code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment_ratio: Option<f64>,
+}
+
+impl SimpleLanguage {
+    pub fn from_lang(ty: &LanguageType, lang: &Language) -> Self {
+        Self {
+            name: *ty,
+            display_name: None,
+            code: lang.code,
+            blanks: lang.blanks,
+            comments: lang.comments,
+            files: lang.reports.len(),
+            avg_lines_per_file: None,
+            comment_ratio: None,
+        }
+    }
+
+    /// Stats of a single file
+    pub fn from_stats(ty: &LanguageType, stats: &CodeStats) -> Self {
+        Self {
+            name: *ty,
+            display_name: None,
+            code: stats.code,
+            blanks: stats.blanks,
+            comments: stats.comments,
+            files: 1,
+            avg_lines_per_file: None,
+            comment_ratio: None,
+        }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.code + self.comments + self.blanks
+    }
+
+    /// Fills in `avg_lines_per_file` and `comment_ratio` from the counts
+    pub fn fill_derived(&mut self) {
+        self.avg_lines_per_file = (self.files > 0).then(|| self.lines() as f64 / self.files as f64);
+        self.comment_ratio = (self.code > 0).then(|| self.comments as f64 / self.code as f64);
+    }
+
+    /// Adds the counts of `other`, which should be the same language, into `self`.
+    /// The order doesn't matter, merging `a` into `b` gives the same counts as `b` into `a`.
+    pub fn merge(&mut self, other: &SimpleLanguage) {
+        debug_assert_eq!(self.name, other.name, "merging different languages");
+
+        // Destructured so a new field can't be added without deciding how it merges
+        let SimpleLanguage {
+            name: _,
+            display_name: _,
+            code,
+            blanks,
+            comments,
+            files,
+            avg_lines_per_file: _,
+            comment_ratio: _,
+        } = other;
+
+        self.code += code;
+        self.blanks += blanks;
+        self.comments += comments;
+        self.files += files;
+        // Derived from the counts, so they're stale now and get filled in again before saving
+        self.avg_lines_per_file = None;
+        self.comment_ratio = None;
+    }
+}
+
+impl AddAssign<&SimpleLanguage> for SimpleLanguage {
+    fn add_assign(&mut self, rhs: &SimpleLanguage) {
+        self.merge(rhs);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PerRepo {
+    pub name: String,
+    /// Friendlier name for showing to people with `PRETTIFY_NAMES`, `name` should be shown when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub href: String,
+    pub description: Option<String>,
+    pub languages: Vec<SimpleLanguage>,
+    /// Language with the most code, not counting `NON_PRIMARY_LANGUAGES` unless that's all there is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_language: Option<LanguageType>,
+    /// Guessed from the files at the root of the repo with `FRAMEWORK_SIGNATURES`, like `nextjs` or `rust`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_framework: Option<String>,
+    /// Languages past `MAX_LANGUAGES_PER_REPO`, folded together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub other: Option<OtherLanguages>,
+    /// How recently the repo was pushed to, missing when GitHub doesn't say
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freshness: Option<Freshness>,
+    /// Listed in `FEATURED_REPOS`, for highlighting
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub featured: bool,
+    /// Merged in from `external-stats.json` instead of counted by the job
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub external: bool,
+    /// Only filled in with `INCLUDE_TOPICS`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<String>,
+    /// Only for repos in `MONOREPO_SUBDIRS`, by most code
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subprojects: Vec<Subproject>,
+}
+
+/// The code in one top-level directory of a repo
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Subproject {
+    pub path: String,
+    pub languages: Vec<SimpleLanguage>,
+}
+
+/// Decided by the days since the last push, against `ACTIVE_DAYS` and `STALE_DAYS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Freshness {
+    Active,
+    Maintained,
+    Stale,
+}
+
+/// Several small languages counted as one entry
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OtherLanguages {
+    pub languages: Vec<LanguageType>,
+    pub code: usize,
+    #[serde(default)]
+    pub blanks: usize,
+    #[serde(default)]
+    pub comments: usize,
+    pub files: usize,
+}
+
+/// The saved total. Serialized as an array of the languages, followed by `other` as one more entry named
+/// `Other`, so anything adding up the entries still counts the folded code.
+#[derive(Debug, Default, Clone)]
+pub struct Total {
+    /// By most code
+    pub languages: Vec<SimpleLanguage>,
+    pub other: Option<OtherLanguages>,
+}
+
+impl Total {
+    pub fn code(&self) -> usize {
+        total_code(&self.languages) + self.other.as_ref().map_or(0, |other| other.code)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum OtherName {
+    Other,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NamedOther<T> {
+    name: OtherName,
+    #[serde(flatten)]
+    other: T,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TotalEntry<L, O> {
+    Language(L),
+    Other(NamedOther<O>),
+}
+
+impl Serialize for Total {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let other = self.other.as_ref().map(|other| {
+            TotalEntry::Other(NamedOther {
+                name: OtherName::Other,
+                other,
+            })
+        });
+        serializer.collect_seq(self.languages.iter().map(TotalEntry::Language).chain(other))
+    }
+}
+
+impl<'de> Deserialize<'de> for Total {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut total = Total::default();
+        for entry in Vec::<TotalEntry<SimpleLanguage, OtherLanguages>>::deserialize(deserializer)? {
+            match entry {
+                TotalEntry::Language(lang) => total.languages.push(lang),
+                TotalEntry::Other(NamedOther { other, .. }) => total.other = Some(other),
+            }
+        }
+        Ok(total)
+    }
+}
+
+/// Lines split between test and source files, as decided by path patterns
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TestVsSource {
+    pub test: Vec<SimpleLanguage>,
+    pub source: Vec<SimpleLanguage>,
+}
+
+/// One snapshot in `history/index.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    pub at: DateTime<Utc>,
+    /// Code in each language of the total that run saved
+    pub code: BTreeMap<LanguageType, usize>,
+    /// Code of the languages folded into `Other`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub other: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// Details about the run which produced the stats
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Meta {
+    pub generated_at: DateTime<Utc>,
+    /// The run hit its deadline, so the stats only cover some of the repos
+    pub partial: bool,
+    pub skipped_for_deadline: usize,
+    /// The per-repo stats only have the repos with the most code, see `MAX_PER_REPO_ENTRIES`
+    #[serde(default)]
+    pub per_repo_truncated: bool,
+    /// Repos left out of the per-repo stats, they're still counted everywhere else
+    #[serde(default)]
+    pub per_repo_omitted: usize,
+    /// [`SCHEMA_VERSION`] of the job which wrote the stats, 0 for stats from before it was recorded
+    #[serde(default)]
+    pub schema_version: u32,
+    /// Code in the `HEADLINE_LANGUAGES` only, for showing as the big number
+    #[serde(default)]
+    pub headline_total: usize,
+    /// What `code` counts, everywhere in the stats
+    #[serde(default)]
+    pub unit: CodeUnit,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeUnit {
+    /// Lines of code, as counted by tokei
+    #[default]
+    Lines,
+    /// Bytes of each language, as detected by GitHub with `USE_GITHUB_LANGUAGES_API`. Nothing else is counted.
+    Bytes,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Clone,
+    Analyze,
+}
+
+/// A single repo that couldn't be processed, the rest of the run continues without it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepoError {
+    pub repo: String,
+    pub phase: Phase,
+    pub message: String,
+}
+
+/// Totals of only the files last touched within a date range, both ends inclusive and open when missing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatedTotal {
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    pub languages: Vec<SimpleLanguage>,
+}
+
+/// Headline numbers about what was counted, where [`Meta`] is about the run itself
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub generated_at: DateTime<Utc>,
+    pub total_code: usize,
+    /// Cloned and counted without failing
+    pub repos_analyzed: usize,
+    /// Listed, but left out by the repo filters or the deadline
+    pub repos_skipped: usize,
+    pub repos_failed: usize,
+    /// The 3 languages with the most code, most first
+    pub top_languages: Vec<SimpleLanguage>,
+    /// Languages of the total under `MIN_TOTAL_PERCENT`, folded together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub other: Option<OtherLanguages>,
+    pub duration_secs: f64,
+}
+
+/// Code of every repo in every language from the per-repo stats, for heatmaps. Repos and languages are both ordered
+/// by most code, and the totals line up with them by index. Most repos only have a few of the languages, so only
+/// the cells with code are stored.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Matrix {
+    /// Rows
+    pub repos: Vec<String>,
+    /// Columns
+    pub languages: Vec<LanguageType>,
+    pub repo_totals: Vec<usize>,
+    pub language_totals: Vec<usize>,
+    /// Every cell that isn't zero
+    pub cells: Vec<MatrixCell>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatrixCell {
+    /// Index into [`Matrix::repos`]
+    pub repo: usize,
+    /// Index into [`Matrix::languages`]
+    pub language: usize,
+    pub code: usize,
+}
+
+/// Repos without a `REPO_DOMAINS` domain are in this one
+pub const UNTAGGED_DOMAIN: &str = "untagged";
+
+/// The per-repo stats of every repo in one `REPO_DOMAINS` domain, added up
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Domain {
+    /// By most code
+    pub repos: Vec<String>,
+    pub code: usize,
+    /// By most code
+    pub languages: Vec<SimpleLanguage>,
+}
+
+/// A repo which cloned fine but had no code counted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmptyRepo {
+    pub name: String,
+    pub reason: EmptyReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyReason {
+    /// Nothing but the `.git` directory
+    Empty,
+    /// Has text files, but all of them were ignored or in languages which aren't counted
+    AllExcluded,
+    /// Only has binary files
+    AllBinary,
+}
+
+/// Difference between the per-repo stats of a run and the run before it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Changes {
+    /// Repos which weren't in the previous run, which is all of them on the first run
+    pub added: Vec<RepoChange>,
+    /// Repos which were in the previous run but aren't anymore, with their languages going to zero
+    pub removed: Vec<RepoChange>,
+    /// Repos in both runs whose languages changed
+    pub changed: Vec<RepoChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepoChange {
+    pub name: String,
+    pub languages: Vec<LanguageChange>,
+}
+
+/// How much each count went up, or down when negative
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LanguageChange {
+    pub name: LanguageType,
+    pub code: i64,
+    #[serde(default)]
+    pub blanks: i64,
+    #[serde(default)]
+    pub comments: i64,
+    pub files: i64,
+}
+
+pub fn total_code(languages: &[SimpleLanguage]) -> usize {
+    let mut total = 0;
+
+    for lang in languages {
+        total += lang.code;
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(
+        name: LanguageType,
+        code: usize,
+        comments: usize,
+        blanks: usize,
+        files: usize,
+    ) -> SimpleLanguage {
+        SimpleLanguage {
+            name,
+            display_name: None,
+            code,
+            blanks,
+            comments,
+            files,
+            avg_lines_per_file: None,
+            comment_ratio: None,
+        }
+    }
+
+    #[test]
+    fn derived_metrics_of_a_crafted_dataset() {
+        let mut rust = language(LanguageType::Rust, 300, 60, 40, 4);
+        rust.fill_derived();
+        assert_eq!(rust.avg_lines_per_file, Some(100.0));
+        assert_eq!(rust.comment_ratio, Some(0.2));
+
+        // Like the manual adjustments, which have no files
+        let mut adjusted = language(LanguageType::Java, 10, 0, 0, 0);
+        adjusted.fill_derived();
+        assert_eq!(adjusted.avg_lines_per_file, None);
+        assert_eq!(adjusted.comment_ratio, Some(0.0));
+
+        let mut only_comments = language(LanguageType::Markdown, 0, 5, 1, 2);
+        only_comments.fill_derived();
+        assert_eq!(only_comments.avg_lines_per_file, Some(3.0));
+        assert_eq!(only_comments.comment_ratio, None);
+
+        let json = serde_json::to_value(&rust).unwrap();
+        assert_eq!(json["avg_lines_per_file"], 100.0);
+        assert_eq!(json["comment_ratio"], 0.2);
+        let json = serde_json::to_value(&only_comments).unwrap();
+        assert!(json.get("comment_ratio").is_none());
+    }
+
+    #[test]
+    fn languages_round_trip_with_and_without_non_code() {
+        let mut rust = language(LanguageType::Rust, 300, 60, 40, 4);
+        rust.display_name = Some("Rust".to_string());
+        rust.fill_derived();
+        let json = serde_json::to_string(&rust).unwrap();
+        let read = serde_json::from_str::<SimpleLanguage>(&json).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+
+        // Saved with `COMBINE_NON_CODE`, still readable with the code intact
+        let read = serde_json::from_str::<SimpleLanguage>(
+            r#"{"name":"Rust","code":300,"non_code":100,"files":4}"#,
+        )
+        .unwrap();
+        assert_eq!(read.name, LanguageType::Rust);
+        assert_eq!(
+            (read.code, read.comments, read.blanks, read.files),
+            (300, 0, 0, 4)
+        );
+    }
+
+    #[test]
+    fn totals_round_trip_with_other() {
+        let total = Total {
+            languages: vec![language(LanguageType::Rust, 990, 0, 0, 3)],
+            other: Some(OtherLanguages {
+                languages: vec![LanguageType::Lua],
+                code: 10,
+                files: 1,
+                ..Default::default()
+            }),
+        };
+        let json = serde_json::to_string(&total).unwrap();
+        let read = serde_json::from_str::<Total>(&json).unwrap();
+        assert_eq!(read.code(), 1000);
+        assert_eq!(read.other.unwrap().languages, [LanguageType::Lua]);
+        assert_eq!(
+            serde_json::to_string(&read.languages).unwrap(),
+            serde_json::to_string(&total.languages).unwrap()
+        );
+
+        // Totals saved before `Other` are still read
+        let read = serde_json::from_str::<Total>(r#"[{"name":"Rust","code":5}]"#).unwrap();
+        assert_eq!((read.code(), read.other.is_none()), (5, true));
+        assert!(serde_json::from_str::<Total>(r#"[{"name":"Nope","code":5}]"#).is_err());
+    }
+}
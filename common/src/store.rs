@@ -0,0 +1,760 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    storage_key, Error, ObjectInfo, BUCKET_NAME, FALLBACK_BUCKET_NAME, PER_REPO_OBJ_NAME,
+    TOTAL_STATS_OBJ_NAME,
+};
+
+/// Parts of a multipart upload are at least this big, S3 only allows the last one to be under 5MiB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+static STORE: OnceCell<Box<dyn StatsStore>> = OnceCell::new();
+
+/// Where the objects are kept. Every key here is the one it's actually stored at, with `ENVIRONMENT` already
+/// in front.
+#[async_trait]
+pub trait StatsStore: Send + Sync {
+    async fn save(&self, key: &str, body: Vec<u8>) -> Result<(), Error>;
+    async fn load(&self, key: &str) -> Result<Vec<u8>, Error>;
+    /// Deleting an object that isn't there is fine
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        let body = self.load(from).await?;
+        self.save(to, body).await
+    }
+
+    /// Every object directly under `prefix`, which ends in `/`
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error>;
+    /// The names of the "directories" directly under `prefix`, without the trailing `/`
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, Error>;
+
+    /// For saving an object a piece at a time, nothing is visible under `key` until it's finished
+    async fn writer<'a>(&'a self, key: &str) -> Result<Box<dyn PieceWriter + 'a>, Error>;
+
+    async fn load_total(&self) -> Result<Vec<u8>, Error> {
+        self.load(&storage_key(TOTAL_STATS_OBJ_NAME)).await
+    }
+
+    async fn load_per_repo(&self) -> Result<Vec<u8>, Error> {
+        self.load(&storage_key(PER_REPO_OBJ_NAME)).await
+    }
+}
+
+#[async_trait]
+pub trait PieceWriter: Send {
+    async fn write(&mut self, piece: &[u8]) -> Result<(), Error>;
+    async fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// `STORAGE_BACKEND`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// `BUCKET_NAME`, the default in release
+    S3,
+    /// Files under `STORAGE_DIR`, the default in dev
+    Local,
+    /// Gone when the process exits, for trying things out and tests
+    Memory,
+}
+
+impl Default for StoreKind {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Self::Local
+        } else {
+            Self::S3
+        }
+    }
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "s3" => Ok(Self::S3),
+            "local" => Ok(Self::Local),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!(
+                "expected \"s3\", \"local\" or \"memory\", got \"{other}\""
+            )),
+        }
+    }
+}
+
+impl StoreKind {
+    pub fn from_env() -> Result<Self, String> {
+        match crate::config::var("STORAGE_BACKEND") {
+            Some(kind) => kind
+                .parse()
+                .map_err(|e| format!("STORAGE_BACKEND is invalid: {e}")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Uses this kind of store from now on, unless one was already set (like by a test). Only called once the
+    /// config has been checked, so the settings it needs are there.
+    pub fn install(self) {
+        set_store(self.build()).ok();
+    }
+
+    fn build(self) -> Box<dyn StatsStore> {
+        match self {
+            Self::S3 => Box::new(S3Store {
+                bucket: BUCKET_NAME.clone(),
+                fallback: FALLBACK_BUCKET_NAME.clone(),
+            }),
+            Self::Local => Box::new(LocalStore {
+                dir: crate::config::var("STORAGE_DIR")
+                    .map_or_else(|| PathBuf::from("."), PathBuf::from),
+            }),
+            Self::Memory => Box::new(MemoryStore::default()),
+        }
+    }
+}
+
+/// The store everything goes through. The binaries install the one from `STORAGE_BACKEND` when their config is
+/// read, anything else gets the default kind.
+pub fn store() -> &'static dyn StatsStore {
+    STORE.get_or_init(|| StoreKind::default().build()).as_ref()
+}
+
+/// Uses `store` instead of the one from `STORAGE_BACKEND`, only works before anything has been stored or loaded.
+/// Gives the store back when it's too late.
+pub fn set_store(store: Box<dyn StatsStore>) -> Result<(), Box<dyn StatsStore>> {
+    STORE.set(store)
+}
+
+/// The S3 client every [`S3Store`] goes through, created on first use
+pub async fn client() -> &'static aws_sdk_s3::Client {
+    static CLIENT: tokio::sync::OnceCell<aws_sdk_s3::Client> = tokio::sync::OnceCell::const_new();
+
+    CLIENT
+        .get_or_init(|| async {
+            let sdk_config = aws_config::from_env().load().await;
+            aws_sdk_s3::Client::new(&sdk_config)
+        })
+        .await
+}
+
+pub struct S3Store {
+    bucket: String,
+    /// Read from when `bucket` fails in a way it could help with
+    fallback: Option<String>,
+}
+
+#[async_trait]
+impl StatsStore for S3Store {
+    async fn save(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        client()
+            .await
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(body.into())
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+
+        Ok(())
+    }
+
+    async fn load(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match get_from_bucket(&self.bucket, key).await {
+            Ok(object) => read_body(key, object).await,
+            Err(e) => match &self.fallback {
+                Some(fallback) if should_fall_back(&e) => {
+                    tracing::warn!(
+                        "Failed to get {key} from {}, trying {fallback}: {e}",
+                        self.bucket
+                    );
+                    let object = get_from_bucket(fallback, key)
+                        .await
+                        .map_err(aws_sdk_s3::Error::from)?;
+                    tracing::info!("Got {key} from the fallback bucket {fallback}");
+                    read_body(key, object).await
+                }
+                _ => Err(aws_sdk_s3::Error::from(e).into()),
+            },
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        client()
+            .await
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+
+        Ok(())
+    }
+
+    /// Within the bucket, without the object going through here
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        client()
+            .await
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{from}", self.bucket))
+            .key(to)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let mut objects = Vec::new();
+        let mut pages = client()
+            .await
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(aws_sdk_s3::Error::from)?;
+            objects.extend(page.contents().iter().filter_map(|object| {
+                Some(ObjectInfo {
+                    key: object.key()?.to_string(),
+                    last_modified: chrono::DateTime::from_timestamp(
+                        object.last_modified()?.secs(),
+                        0,
+                    )?,
+                })
+            }));
+        }
+
+        Ok(objects)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut dirs = Vec::new();
+        let mut pages = client()
+            .await
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(aws_sdk_s3::Error::from)?;
+            dirs.extend(
+                page.common_prefixes()
+                    .iter()
+                    .filter_map(|common| common.prefix())
+                    .map(|common| {
+                        // Only once, a dir named like the prefix would be stripped too otherwise
+                        let dir = common.strip_prefix(prefix).unwrap_or(common);
+                        dir.strip_suffix('/').unwrap_or(dir).to_string()
+                    }),
+            );
+        }
+
+        Ok(dirs)
+    }
+
+    async fn writer<'a>(&'a self, key: &str) -> Result<Box<dyn PieceWriter + 'a>, Error> {
+        Ok(Box::new(MultipartWriter {
+            store: self,
+            key: key.to_string(),
+            buffer: Vec::with_capacity(PART_SIZE),
+            upload_id: None,
+            parts: Vec::new(),
+        }))
+    }
+}
+
+async fn get_from_bucket(
+    bucket: &str,
+    key: &str,
+) -> Result<
+    aws_sdk_s3::operation::get_object::GetObjectOutput,
+    aws_sdk_s3::error::SdkError<aws_sdk_s3::operation::get_object::GetObjectError>,
+> {
+    client()
+        .await
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+}
+
+/// The body can still fail part way through, after the object was found
+async fn read_body(
+    key: &str,
+    object: aws_sdk_s3::operation::get_object::GetObjectOutput,
+) -> Result<Vec<u8>, Error> {
+    let body = object.body.collect().await.map_err(|e| {
+        std::io::Error::other(format!("the body of {key} failed part way through: {e}"))
+    })?;
+    Ok(body.to_vec())
+}
+
+/// The primary being unreachable, failing on its side, or not having the object (yet). Anything else, like being
+/// denied access, would most likely fail the same way on the fallback.
+fn should_fall_back(
+    e: &aws_sdk_s3::error::SdkError<aws_sdk_s3::operation::get_object::GetObjectError>,
+) -> bool {
+    use aws_sdk_s3::error::SdkError;
+
+    match e {
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service) => {
+            service.err().is_no_such_key() || service.raw().status().is_server_error()
+        }
+        _ => false,
+    }
+}
+
+/// Goes up as a multipart upload once it's bigger than a part, anything smaller is put in one go when finished
+struct MultipartWriter<'a> {
+    store: &'a S3Store,
+    key: String,
+    buffer: Vec<u8>,
+    upload_id: Option<String>,
+    parts: Vec<aws_sdk_s3::types::CompletedPart>,
+}
+
+#[async_trait]
+impl PieceWriter for MultipartWriter<'_> {
+    async fn write(&mut self, piece: &[u8]) -> Result<(), Error> {
+        self.buffer.extend_from_slice(piece);
+        if self.buffer.len() >= PART_SIZE {
+            self.upload_part().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        let Some(upload_id) = self.upload_id.clone() else {
+            let body = std::mem::take(&mut self.buffer);
+            return self.store.save(&self.key, body).await;
+        };
+        if !self.buffer.is_empty() {
+            self.upload_part().await?;
+        }
+
+        let completed = client()
+            .await
+            .complete_multipart_upload()
+            .bucket(&self.store.bucket)
+            .key(&self.key)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                aws_sdk_s3::types::CompletedMultipartUpload::builder()
+                    .set_parts(Some(std::mem::take(&mut self.parts)))
+                    .build(),
+            )
+            .send()
+            .await;
+        if let Err(e) = completed {
+            self.abort(&upload_id).await;
+            return Err(aws_sdk_s3::Error::from(e).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl MultipartWriter<'_> {
+    async fn upload_part(&mut self) -> Result<(), Error> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let upload_id = client()
+                    .await
+                    .create_multipart_upload()
+                    .bucket(&self.store.bucket)
+                    .key(&self.key)
+                    .send()
+                    .await
+                    .map_err(aws_sdk_s3::Error::from)?
+                    .upload_id
+                    .ok_or_else(|| {
+                        std::io::Error::other(format!("no upload id for {}", self.key))
+                    })?;
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+
+        // Part numbers start at 1
+        let part_number = self.parts.len() as i32 + 1;
+        let uploaded = client()
+            .await
+            .upload_part()
+            .bucket(&self.store.bucket)
+            .key(&self.key)
+            .upload_id(&upload_id)
+            .part_number(part_number)
+            .body(std::mem::take(&mut self.buffer).into())
+            .send()
+            .await;
+        let uploaded = match uploaded {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                self.abort(&upload_id).await;
+                return Err(aws_sdk_s3::Error::from(e).into());
+            }
+        };
+        self.parts.push(
+            aws_sdk_s3::types::CompletedPart::builder()
+                .set_e_tag(uploaded.e_tag)
+                .part_number(part_number)
+                .build(),
+        );
+
+        Ok(())
+    }
+
+    /// So the parts uploaded so far aren't kept around, and billed, forever
+    async fn abort(&self, upload_id: &str) {
+        let aborted = client()
+            .await
+            .abort_multipart_upload()
+            .bucket(&self.store.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+        if let Err(e) = aborted {
+            tracing::warn!("Failed to abort the upload of {}: {e}", self.key);
+        }
+    }
+}
+
+/// Each object is a file, at its key under `dir`. Goes through `tokio::fs`, so the blocking calls are off the
+/// runtime's threads.
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    /// Creates the directories the file goes in
+    async fn path_for_saving(&self, key: &str) -> std::io::Result<PathBuf> {
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(path)
+    }
+
+    /// The entries directly under `prefix`, none when it doesn't exist
+    async fn read_dir(&self, prefix: &str) -> std::io::Result<Vec<tokio::fs::DirEntry>> {
+        let mut entries = match tokio::fs::read_dir(self.dir.join(prefix)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut all = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            all.push(entry);
+        }
+        Ok(all)
+    }
+}
+
+#[async_trait]
+impl StatsStore for LocalStore {
+    /// Through [`Self::writer`], so a crash while saving never leaves half an object
+    async fn save(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        let mut writer = self.writer(key).await?;
+        writer.write(&body).await?;
+        writer.finish().await
+    }
+
+    async fn load(&self, key: &str) -> Result<Vec<u8>, Error> {
+        Ok(tokio::fs::read(self.dir.join(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.dir.join(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        tokio::fs::copy(self.dir.join(from), self.path_for_saving(to).await?).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        let mut objects = Vec::new();
+        for entry in self.read_dir(prefix).await? {
+            // Unfinished writes are hidden next to their object
+            if entry.file_type().await?.is_file()
+                && !entry.file_name().to_string_lossy().starts_with('.')
+            {
+                objects.push(ObjectInfo {
+                    key: format!("{prefix}{}", entry.file_name().to_string_lossy()),
+                    last_modified: entry.metadata().await?.modified()?.into(),
+                });
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut dirs = Vec::new();
+        for entry in self.read_dir(prefix).await? {
+            // Like S3's common prefixes, which are only ever of keys with more after them
+            if entry.file_type().await?.is_dir() {
+                dirs.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    async fn writer<'a>(&'a self, key: &str) -> Result<Box<dyn PieceWriter + 'a>, Error> {
+        static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+        let path = self.path_for_saving(key).await?;
+        // Next to the object, so it can be renamed over it. Hidden, so it isn't listed while it's written.
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id(),
+            WRITERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = tokio::fs::File::create(&temp_path).await?;
+        Ok(Box::new(FileWriter {
+            file: Some(tokio::io::BufWriter::new(file)),
+            temp_path,
+            path,
+        }))
+    }
+}
+
+/// Writes to a temporary file which is renamed over `path` once it's finished, and deleted when it's dropped
+/// before then
+struct FileWriter {
+    /// Only `None` once it's finished
+    file: Option<tokio::io::BufWriter<tokio::fs::File>>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl PieceWriter for FileWriter {
+    async fn write(&mut self, piece: &[u8]) -> Result<(), Error> {
+        if let Some(file) = &mut self.file {
+            file.write_all(piece).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+            file.into_inner().sync_all().await?;
+            tokio::fs::rename(&self.temp_path, &self.path).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        // Renamed already when it's finished. Can't be awaited here, and it's only ever a small unlink.
+        std::fs::remove_file(&self.temp_path).ok();
+    }
+}
+
+struct MemoryObject {
+    body: Vec<u8>,
+    last_modified: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: Mutex<BTreeMap<String, MemoryObject>>,
+}
+
+#[async_trait]
+impl StatsStore for MemoryStore {
+    async fn save(&self, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        self.objects.lock().unwrap().insert(
+            key.to_string(),
+            MemoryObject {
+                body,
+                last_modified: chrono::Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn load(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match self.objects.lock().unwrap().get(key) {
+            Some(object) => Ok(object.body.clone()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no object {key} in memory"),
+            )
+            .into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, Error> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| {
+                key.strip_prefix(prefix)
+                    .is_some_and(|name| !name.contains('/'))
+            })
+            .map(|(key, object)| ObjectInfo {
+                key: key.clone(),
+                last_modified: object.last_modified,
+            })
+            .collect())
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|key| Some(key.strip_prefix(prefix)?.split_once('/')?.0.to_string()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    async fn writer<'a>(&'a self, key: &str) -> Result<Box<dyn PieceWriter + 'a>, Error> {
+        Ok(Box::new(BufferWriter {
+            store: self,
+            key: key.to_string(),
+            buffer: Vec::new(),
+        }))
+    }
+}
+
+/// Keeps every piece until it's finished, then saves them as one object
+struct BufferWriter<'a> {
+    store: &'a MemoryStore,
+    key: String,
+    buffer: Vec<u8>,
+}
+
+#[async_trait]
+impl PieceWriter for BufferWriter<'_> {
+    async fn write(&mut self, piece: &[u8]) -> Result<(), Error> {
+        self.buffer.extend_from_slice(piece);
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<(), Error> {
+        self.store.save(&self.key, self.buffer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_store() -> (tempfile::TempDir, LocalStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore {
+            dir: dir.path().to_path_buf(),
+        };
+        (dir, store)
+    }
+
+    #[tokio::test]
+    async fn local_writer_only_replaces_the_object_once_finished() {
+        let (_dir, store) = local_store();
+        store.save("stats.json", b"old".to_vec()).await.unwrap();
+
+        let mut writer = store.writer("stats.json").await.unwrap();
+        writer.write(b"new").await.unwrap();
+        assert_eq!(store.load("stats.json").await.unwrap(), b"old");
+        assert_eq!(store.list("").await.unwrap().len(), 1);
+
+        writer.finish().await.unwrap();
+        assert_eq!(store.load("stats.json").await.unwrap(), b"new");
+        assert_eq!(store.list("").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_local_writer_leaves_nothing_behind() {
+        let (dir, store) = local_store();
+        store.save("stats.json", b"old".to_vec()).await.unwrap();
+
+        let mut writer = store.writer("stats.json").await.unwrap();
+        writer.write(b"partial").await.unwrap();
+        drop(writer);
+
+        assert_eq!(store.load("stats.json").await.unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn local_save_replaces_the_object_through_a_temp_file() {
+        let (dir, store) = local_store();
+        store.save("cache/a.json", b"old".to_vec()).await.unwrap();
+        store.save("cache/a.json", b"new".to_vec()).await.unwrap();
+
+        assert_eq!(store.load("cache/a.json").await.unwrap(), b"new");
+        // Nothing left of the temp files
+        let names = std::fs::read_dir(dir.path().join("cache"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.json"]);
+    }
+
+    #[tokio::test]
+    async fn local_list_dirs_leaves_out_files() {
+        let (_dir, store) = local_store();
+        store
+            .save("archive/2024-06-01/total-stats.json", b"[]".to_vec())
+            .await
+            .unwrap();
+        store
+            .save("archive/stray.json", b"{}".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(store.list_dirs("archive/").await.unwrap(), ["2024-06-01"]);
+    }
+}
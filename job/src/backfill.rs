@@ -0,0 +1,79 @@
+use std::collections::BTreeSet;
+
+use chrono::NaiveDate;
+use lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
+
+/// Which `BACKFILL_MODE` dates are done, so a backfill that was cut short carries on from where it stopped
+#[derive(Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    done: BTreeSet<NaiveDate>,
+}
+
+impl Checkpoint {
+    /// Days with an archived snapshot count as done too, a real run's stats are never replaced by a backfill's
+    pub async fn load() -> Self {
+        let mut checkpoint = match common::get_object(common::BACKFILL_CHECKPOINT_OBJ_NAME).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(_) => Self::default(),
+        };
+        match common::list_archive_dates().await {
+            Ok(dates) => checkpoint.done.extend(
+                dates
+                    .iter()
+                    .filter_map(|date| date.parse::<NaiveDate>().ok()),
+            ),
+            Err(e) => eprintln!("Failed to list the archived snapshots to skip: {e}"),
+        }
+        checkpoint
+    }
+
+    /// The dates still to do, oldest first
+    pub fn pending(&self, dates: &[NaiveDate]) -> Vec<NaiveDate> {
+        dates
+            .iter()
+            .filter(|date| !self.done.contains(date))
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    pub async fn mark_done(&mut self, date: NaiveDate) -> Result<(), Error> {
+        self.done.insert(date);
+        common::save_object(
+            common::BACKFILL_CHECKPOINT_OBJ_NAME,
+            &serde_json::to_string(self)?,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dates(dates: &[&str]) -> Vec<NaiveDate> {
+        dates.iter().map(|date| date.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn resumes_after_the_dates_already_done() {
+        let wanted = dates(&["2021-03-01", "2021-01-01", "2021-02-01", "2021-01-01"]);
+        let mut checkpoint = Checkpoint::default();
+        assert_eq!(
+            checkpoint.pending(&wanted),
+            dates(&["2021-01-01", "2021-02-01", "2021-03-01"])
+        );
+
+        // What `mark_done` saves, read back by the next run
+        checkpoint.done.insert(dates(&["2021-01-01"])[0]);
+        let saved = serde_json::to_string(&checkpoint).unwrap();
+        let resumed = serde_json::from_str::<Checkpoint>(&saved).unwrap();
+        assert_eq!(
+            resumed.pending(&wanted),
+            dates(&["2021-02-01", "2021-03-01"])
+        );
+    }
+}
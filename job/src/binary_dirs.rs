@@ -0,0 +1,81 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use ignore::WalkBuilder;
+use tokei::LanguageType;
+
+use crate::empty::is_text;
+
+/// Directories with fewer files aren't worth skipping
+const MIN_FILES: usize = 10;
+
+#[derive(Debug, Default)]
+struct DirFiles {
+    files: usize,
+    binary: usize,
+    /// Files tokei would count, a directory with any of them is never skipped
+    counted: usize,
+}
+
+/// Directories, relative to the repo root, where at least `percent` of the files are binary and none are in a
+/// language tokei counts. Skipping them can't change the counts, it only saves tokei walking them. Only the
+/// outermost of nested matching directories is returned.
+pub fn find(repo_path: &Path, percent: f64, tokei_config: &tokei::Config) -> Vec<PathBuf> {
+    let mut dirs = BTreeMap::<PathBuf, DirFiles>::new();
+
+    let walker = WalkBuilder::new(repo_path)
+        .hidden(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+    for entry in walker.filter_map(Result::ok) {
+        if !entry.file_type().is_some_and(|ty| ty.is_file()) {
+            continue;
+        }
+        let Ok(path) = entry.path().strip_prefix(repo_path) else {
+            continue;
+        };
+
+        let counted = LanguageType::from_path(entry.path(), tokei_config).is_some_and(|ty| {
+            tokei_config
+                .types
+                .as_ref()
+                .is_none_or(|types| types.contains(&ty))
+        });
+        // Only files tokei skips need reading, anything it counts is text
+        let binary = !counted && !is_text(entry.path());
+
+        for dir in path.ancestors().skip(1) {
+            if dir.as_os_str().is_empty() {
+                break;
+            }
+            let stats = dirs.entry(dir.to_path_buf()).or_default();
+            stats.files += 1;
+            stats.binary += usize::from(binary);
+            stats.counted += usize::from(counted);
+        }
+    }
+
+    let mut skipped = Vec::<PathBuf>::new();
+    // Sorted, so parents come before their children
+    for (dir, stats) in dirs {
+        if stats.counted > 0
+            || stats.files < MIN_FILES
+            || (stats.binary as f64 / stats.files as f64 * 100.0) < percent
+            || skipped.iter().any(|parent| dir.starts_with(parent))
+        {
+            continue;
+        }
+
+        println!(
+            "Skipping \"{}\", {} of its {} files are binary and none are counted",
+            dir.display(),
+            stats.binary,
+            stats.files
+        );
+        skipped.push(dir);
+    }
+
+    skipped
+}
@@ -0,0 +1,199 @@
+use std::{collections::BTreeSet, path::Path};
+
+use gix::{bstr::ByteSlice, ObjectId};
+use ignore::overrides::OverrideBuilder;
+use lambda_runtime::Error;
+use tokei::{LanguageType, Languages, Report};
+
+use crate::{config::Config, encoding};
+
+/// Counts the files on the other remote branches which aren't at HEAD with the same content, so each distinct
+/// version of a path is counted once. Reads the blobs straight from the object database, so nothing is checked out,
+/// normalizing them like [`encoding::recount`] does the worktree's files. The reports are named by where the file
+/// would be in the worktree, so they mustn't be recounted from there.
+///
+/// Files on other branches are only left out by the `ignored` patterns and tokei's language filter, not by any
+/// `.gitignore`. Returns how many files were added.
+pub fn count_other_branches(
+    repo_path: &Path,
+    ignored: &[&str],
+    config: &Config,
+    tokei_config: &tokei::Config,
+    languages: &mut Languages,
+) -> Result<usize, Error> {
+    let repo = gix::open(repo_path)?;
+
+    let mut overrides = OverrideBuilder::new(repo_path);
+    for pattern in ignored {
+        overrides.add(&format!("!{pattern}"))?;
+    }
+    let overrides = overrides.build()?;
+
+    let mut seen = tree_files(&repo, repo.head_commit()?.tree()?)?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    let mut touched = BTreeSet::new();
+    let mut added = 0;
+    for reference in repo.references()?.remote_branches()? {
+        let mut reference = reference?;
+        let tree = reference.peel_to_id_in_place()?.object()?.peel_to_tree()?;
+
+        for (path, id) in tree_files(&repo, tree)? {
+            if !seen.insert((path.clone(), id)) {
+                continue;
+            }
+
+            let full_path = repo_path.join(path.to_path_lossy());
+            if overrides.matched(&full_path, false).is_ignore() {
+                continue;
+            }
+            let Some(ty) = LanguageType::from_path(&full_path, tokei_config) else {
+                continue;
+            };
+            if tokei_config
+                .types
+                .as_ref()
+                .is_some_and(|types| !types.contains(&ty))
+            {
+                continue;
+            }
+
+            let blob = repo.find_object(id)?;
+            let mut report = Report::new(full_path);
+            let normalized = encoding::normalize(&blob.data, config);
+            let text = normalized
+                .as_ref()
+                .map_or(&blob.data[..], |text| text.as_bytes());
+            report.stats = ty.parse_from_slice(text, tokei_config);
+            languages.entry(ty).or_default().add_report(report);
+            touched.insert(ty);
+            added += 1;
+        }
+    }
+
+    // Adding reports doesn't update the language's totals
+    for ty in touched {
+        languages.entry(ty).or_default().total();
+    }
+
+    Ok(added)
+}
+
+/// Stands in for the HEAD commit as what the counts of a repo are cached by with `ALL_BRANCHES`, which count
+/// every remote branch too. A hash of HEAD and the tip of each remote branch, so moving any of them changes it.
+pub fn tips(repo_path: &Path) -> Result<String, Error> {
+    let repo = gix::open(repo_path)?;
+    let mut tips = vec![format!("HEAD {}", repo.head_id()?)];
+    for reference in repo.references()?.remote_branches()? {
+        let mut reference = reference?;
+        let name = reference.name().as_bstr().to_string();
+        tips.push(format!("{name} {}", reference.peel_to_id_in_place()?));
+    }
+    tips.sort_unstable();
+
+    Ok(format!(
+        "{:016x}",
+        common::fnv1a(tips.join("\n").as_bytes())
+    ))
+}
+
+/// Every regular file in the tree, by path relative to the repo root and blob id
+fn tree_files(
+    repo: &gix::Repository,
+    tree: gix::Tree<'_>,
+) -> Result<Vec<(gix::bstr::BString, ObjectId)>, Error> {
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    tree.traverse().breadthfirst(&mut recorder)?;
+
+    Ok(recorder
+        .records
+        .into_iter()
+        .filter(|entry| entry.mode.is_blob())
+        .filter(|entry| repo.has_object(entry.oid))
+        .map(|entry| (entry.filepath, entry.oid))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::git::tests::fixture;
+
+    #[test]
+    fn other_branches_are_normalized_from_their_own_content() {
+        // The branch changes `main.rs` to old Mac line endings, then HEAD goes back to before it
+        let repo = fixture(&[
+            ("2024-01-01T12:00:00Z", &[("main.rs", "fn main() {}\n")]),
+            (
+                "2024-01-02T12:00:00Z",
+                &[("main.rs", "fn a() {}\r// comment\r\rfn b() {}\r")],
+            ),
+        ]);
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(repo.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["update-ref", "refs/remotes/origin/feature", "HEAD"]);
+        git(&["reset", "-q", "--hard", "HEAD~1"]);
+
+        let config = Config {
+            normalize_line_endings: true,
+            ..crate::config::tests::config()
+        };
+        let tokei_config = tokei::Config::default();
+        let mut languages = Languages::new();
+        languages.get_statistics(&[repo.path()], &[".git"], &tokei_config);
+        encoding::recount(&mut languages, &config, &tokei_config);
+        let added =
+            count_other_branches(repo.path(), &[], &config, &tokei_config, &mut languages).unwrap();
+        assert_eq!(added, 1);
+
+        let mut counts = languages[&LanguageType::Rust]
+            .reports
+            .iter()
+            .map(|report| {
+                (
+                    report.stats.code,
+                    report.stats.comments,
+                    report.stats.blanks,
+                )
+            })
+            .collect::<Vec<_>>();
+        counts.sort();
+        // HEAD's version, and the branch's instead of HEAD's again
+        assert_eq!(counts, [(1, 0, 0), (2, 1, 1)]);
+        assert_eq!(languages[&LanguageType::Rust].code, 3);
+    }
+
+    #[test]
+    fn tips_change_with_any_remote_branch() {
+        let repo = fixture(&[
+            ("2024-01-01T12:00:00Z", &[("main.rs", "fn main() {}\n")]),
+            ("2024-01-02T12:00:00Z", &[("lib.rs", "fn lib() {}\n")]),
+        ]);
+        let update_ref = |name: &str, commit: &str| {
+            let status = Command::new("git")
+                .args(["update-ref", name, commit])
+                .current_dir(repo.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+
+        let head_only = tips(repo.path()).unwrap();
+        assert_eq!(tips(repo.path()).unwrap(), head_only);
+        update_ref("refs/remotes/origin/feature", "HEAD~1");
+        let with_feature = tips(repo.path()).unwrap();
+        assert_ne!(with_feature, head_only);
+        // The feature branch moving while HEAD stays put
+        update_ref("refs/remotes/origin/feature", "HEAD");
+        assert_ne!(tips(repo.path()).unwrap(), with_feature);
+    }
+}
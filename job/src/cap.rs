@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use tokei::{LanguageType, Languages};
+
+/// Scales down every file with more than `cap` lines to exactly `cap`, keeping its split between code, comments
+/// and blanks. Returns how many files were capped.
+pub fn cap_file_lines(languages: &mut Languages, cap: usize) -> usize {
+    let mut capped = 0;
+
+    for (_, lang) in languages.iter_mut() {
+        let mut touched = false;
+        for report in &mut lang.reports {
+            let stats = &mut report.stats;
+            let lines = stats.lines();
+            if lines <= cap {
+                continue;
+            }
+
+            stats.code = stats.code * cap / lines;
+            stats.comments = stats.comments * cap / lines;
+            // Whatever rounding lost goes here, so the file adds up to the cap
+            stats.blanks = cap - stats.code - stats.comments;
+            touched = true;
+            capped += 1;
+        }
+
+        // The language's totals are only summed from its reports when asked
+        if touched {
+            lang.total();
+        }
+    }
+
+    capped
+}
+
+/// Drops the file with the most lines from each language with more than one file, since a single huge file is
+/// often generated or minified. Returns the dropped files, with the language and lines of each.
+pub fn drop_largest_files(languages: &mut Languages) -> Vec<(LanguageType, PathBuf, usize)> {
+    let mut dropped = Vec::new();
+
+    for (ty, lang) in languages.iter_mut() {
+        if lang.reports.len() < 2 {
+            continue;
+        }
+        let Some((largest, _)) = lang
+            .reports
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, report)| report.stats.lines())
+        else {
+            continue;
+        };
+
+        let report = lang.reports.remove(largest);
+        dropped.push((*ty, report.name, report.stats.lines()));
+        lang.total();
+    }
+
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokei::{Language, Report};
+
+    use super::*;
+
+    fn file(name: &str, code: usize, comments: usize, blanks: usize) -> Report {
+        let mut report = Report::new(PathBuf::from(name));
+        report.stats.code = code;
+        report.stats.comments = comments;
+        report.stats.blanks = blanks;
+        report
+    }
+
+    #[test]
+    fn only_huge_files_are_capped() {
+        let mut rust = Language::new();
+        rust.add_report(file("generated.rs", 400_000, 50_000, 50_000));
+        rust.add_report(file("main.rs", 80, 10, 10));
+        rust.add_report(file("lib.rs", 700, 200, 100));
+        rust.total();
+        let mut languages = Languages::new();
+        languages.insert(LanguageType::Rust, rust);
+
+        assert_eq!(cap_file_lines(&mut languages, 1000), 1);
+
+        let rust = &languages[&LanguageType::Rust];
+        let stats = |name: &str| {
+            let report = rust
+                .reports
+                .iter()
+                .find(|report| report.name == Path::new(name))
+                .unwrap();
+            (
+                report.stats.code,
+                report.stats.comments,
+                report.stats.blanks,
+            )
+        };
+        assert_eq!(stats("generated.rs"), (800, 100, 100));
+        assert_eq!(stats("main.rs"), (80, 10, 10));
+        // Exactly at the cap
+        assert_eq!(stats("lib.rs"), (700, 200, 100));
+        // And the language's totals follow
+        assert_eq!(rust.code, 800 + 80 + 700);
+        assert_eq!(rust.lines(), 1000 + 100 + 1000);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use common::stats::{Changes, LanguageChange, PerRepo, RepoChange, SimpleLanguage};
+use tokei::LanguageType;
+
+/// Per-repo stats saved by the previous run
+#[derive(Debug, Default)]
+pub struct Previous {
+    pub repos: Vec<PerRepo>,
+    /// Saved with `COMBINE_NON_CODE`, so each language's `comments` are what was its `non_code` and its `blanks`
+    /// are 0. The current counts are combined the same way to diff against them.
+    pub non_code_combined: bool,
+}
+
+/// Per-repo stats saved by the previous run, none on the first run. `None` when they're there but can't be read,
+/// since diffing against nothing would list every repo as added.
+pub async fn previous_per_repo() -> Option<Previous> {
+    let bytes = match common::get_per_repo_stats().await {
+        Ok(bytes) => bytes,
+        Err(e) if e.is_not_found() => {
+            println!("No previous per-repo stats, every repo is added");
+            return Some(Previous::default());
+        }
+        Err(e) => {
+            eprintln!("Failed to get the previous per-repo stats, not saving changes: {e}");
+            return None;
+        }
+    };
+
+    match parse(&bytes) {
+        Ok(previous) => Some(previous),
+        Err(e) => {
+            eprintln!("Previous per-repo stats are invalid, not saving changes: {e}");
+            None
+        }
+    }
+}
+
+fn parse(bytes: &[u8]) -> Result<Previous, serde_json::Error> {
+    let mut value = serde_json::from_slice::<serde_json::Value>(bytes)?;
+    let non_code_combined = split_non_code(&mut value);
+    Ok(Previous {
+        repos: serde_json::from_value(value)?,
+        non_code_combined,
+    })
+}
+
+/// Undoes `COMBINE_NON_CODE` as far as it can be, putting all of `non_code` in `comments`. Whether there was any.
+fn split_non_code(value: &mut serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut combined = false;
+            if let Some(non_code) = object.remove("non_code") {
+                object.insert("comments".to_string(), non_code);
+                object.insert("blanks".to_string(), 0.into());
+                combined = true;
+            }
+
+            object
+                .values_mut()
+                .fold(combined, |combined, value| split_non_code(value) | combined)
+        }
+        serde_json::Value::Array(values) => values
+            .iter_mut()
+            .fold(false, |combined, value| split_non_code(value) | combined),
+        _ => false,
+    }
+}
+
+/// Taking the current repos one at a time, so they don't have to all be in memory with `STREAMING_SAVE`
+pub fn diff(
+    previous: &Previous,
+    current: impl IntoIterator<Item = impl Borrow<PerRepo>>,
+) -> Changes {
+    let combine = previous.non_code_combined;
+    let previous = previous
+        .repos
+        .iter()
+        .map(|repo| (repo.name.as_str(), repo))
+        .collect::<BTreeMap<_, _>>();
+    let mut still_there = BTreeSet::new();
+
+    let mut changes = Changes::default();
+    for repo in current {
+        let repo = repo.borrow();
+        match previous.get_key_value(repo.name.as_str()) {
+            Some((&name, before)) => {
+                still_there.insert(name);
+                let languages = diff_languages(&before.languages, &repo.languages, combine);
+                if !languages.is_empty() {
+                    changes.changed.push(RepoChange {
+                        name: repo.name.clone(),
+                        languages,
+                    });
+                }
+            }
+            None => changes.added.push(RepoChange {
+                name: repo.name.clone(),
+                languages: diff_languages(&[], &repo.languages, combine),
+            }),
+        }
+    }
+
+    for (name, before) in previous {
+        if !still_there.contains(name) {
+            changes.removed.push(RepoChange {
+                name: name.to_string(),
+                languages: diff_languages(&before.languages, &[], combine),
+            });
+        }
+    }
+
+    changes
+}
+
+/// Only the languages whose counts changed, in order of their canonical names. With `combine`, the blanks are
+/// counted as comments, which `COMBINE_NON_CODE` sums back up when the changes are saved.
+fn diff_languages(
+    before: &[SimpleLanguage],
+    after: &[SimpleLanguage],
+    combine: bool,
+) -> Vec<LanguageChange> {
+    let mut languages = BTreeMap::<LanguageType, LanguageChange>::new();
+    for (sign, langs) in [(-1, before), (1, after)] {
+        for lang in langs {
+            let change = languages.entry(lang.name).or_insert(LanguageChange {
+                name: lang.name,
+                code: 0,
+                blanks: 0,
+                comments: 0,
+                files: 0,
+            });
+            change.code += sign * lang.code as i64;
+            if combine {
+                change.comments += sign * (lang.comments + lang.blanks) as i64;
+            } else {
+                change.blanks += sign * lang.blanks as i64;
+                change.comments += sign * lang.comments as i64;
+            }
+            change.files += sign * lang.files as i64;
+        }
+    }
+
+    languages
+        .into_values()
+        .filter(|change| {
+            change.code != 0 || change.blanks != 0 || change.comments != 0 || change.files != 0
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(name: &str, languages: &[(LanguageType, usize)]) -> PerRepo {
+        PerRepo {
+            name: name.to_string(),
+            languages: languages
+                .iter()
+                .map(|&(name, code)| SimpleLanguage {
+                    name,
+                    display_name: None,
+                    code,
+                    blanks: 0,
+                    comments: 0,
+                    files: 1,
+                    avg_lines_per_file: None,
+                    comment_ratio: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn names(changes: &[RepoChange]) -> Vec<&str> {
+        changes.iter().map(|change| change.name.as_str()).collect()
+    }
+
+    #[test]
+    fn diffs_added_removed_and_changed_repos() {
+        let previous = vec![
+            repo("same", &[(LanguageType::Rust, 10)]),
+            repo("grew", &[(LanguageType::Rust, 10), (LanguageType::Go, 5)]),
+            repo("gone", &[(LanguageType::Java, 3)]),
+        ];
+        let current = [
+            repo("new", &[(LanguageType::Python, 7)]),
+            repo("same", &[(LanguageType::Rust, 10)]),
+            repo("grew", &[(LanguageType::Rust, 15)]),
+        ];
+
+        let previous = Previous {
+            repos: previous,
+            ..Default::default()
+        };
+        let changes = diff(&previous, &current);
+        assert_eq!(names(&changes.added), ["new"]);
+        assert_eq!(names(&changes.removed), ["gone"]);
+        assert_eq!(names(&changes.changed), ["grew"]);
+
+        let grew = &changes.changed[0].languages;
+        assert_eq!(grew.len(), 2);
+        assert_eq!(
+            (grew[0].name, grew[0].code, grew[0].files),
+            (LanguageType::Go, -5, -1)
+        );
+        assert_eq!(
+            (grew[1].name, grew[1].code, grew[1].files),
+            (LanguageType::Rust, 5, 0)
+        );
+        assert_eq!(changes.removed[0].languages[0].code, -3);
+        assert_eq!(changes.added[0].languages[0].code, 7);
+    }
+
+    #[test]
+    fn everything_is_added_on_the_first_run() {
+        let changes = diff(
+            &Previous::default(),
+            &[repo("a", &[(LanguageType::Rust, 1)])],
+        );
+        assert_eq!(names(&changes.added), ["a"]);
+        assert!(changes.removed.is_empty() && changes.changed.is_empty());
+    }
+
+    /// Saved like the previous run saves them, combined or not
+    fn saved(repos: &[PerRepo], combine: bool) -> Previous {
+        let mut value = serde_json::to_value(repos).unwrap();
+        if combine {
+            crate::combine_non_code(&mut value);
+        }
+        parse(value.to_string().as_bytes()).unwrap()
+    }
+
+    fn with_non_code(mut repo: PerRepo, comments: usize, blanks: usize) -> PerRepo {
+        repo.languages[0].comments = comments;
+        repo.languages[0].blanks = blanks;
+        repo
+    }
+
+    #[test]
+    fn unchanged_repos_stay_unchanged_against_combined_stats() {
+        let previous = saved(
+            &[
+                with_non_code(repo("same", &[(LanguageType::Rust, 10)]), 1, 4),
+                with_non_code(repo("grew", &[(LanguageType::Rust, 10)]), 1, 4),
+            ],
+            true,
+        );
+        assert!(previous.non_code_combined);
+
+        let current = [
+            with_non_code(repo("same", &[(LanguageType::Rust, 10)]), 2, 3),
+            with_non_code(repo("grew", &[(LanguageType::Rust, 10)]), 4, 3),
+        ];
+        let changes = diff(&previous, &current);
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert_eq!(names(&changes.changed), ["grew"]);
+        let grew = &changes.changed[0].languages[0];
+        assert_eq!((grew.code, grew.comments, grew.blanks), (0, 2, 0));
+    }
+
+    #[test]
+    fn uncombined_stats_are_diffed_as_they_are() {
+        let previous = saved(
+            &[with_non_code(repo("a", &[(LanguageType::Rust, 10)]), 1, 4)],
+            false,
+        );
+        assert!(!previous.non_code_combined);
+
+        let changes = diff(
+            &previous,
+            &[with_non_code(repo("a", &[(LanguageType::Rust, 10)]), 2, 3)],
+        );
+        let a = &changes.changed[0].languages[0];
+        assert_eq!((a.comments, a.blanks), (1, -1));
+    }
+}
@@ -0,0 +1,46 @@
+use std::{fs, num::NonZeroUsize, thread};
+
+use crate::config::Config;
+
+/// How many repos are cloned and counted at once. `CONCURRENCY` when set, otherwise as many as fit in the
+/// available memory at `CLONE_MEMORY_MB` each, but never more than there are CPUs. Lambda gives a function a CPU
+/// per ~1.8GB, so it's usually memory which runs out first with big repos.
+pub fn repo_threads(config: &Config) -> usize {
+    if let Some(concurrency) = config.concurrency {
+        println!("Processing {concurrency} repos at once, from CONCURRENCY");
+        return concurrency;
+    }
+
+    let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let (memory_mb, source) = match config.available_memory_mb {
+        Some(memory_mb) => (memory_mb, "AVAILABLE_MEMORY_MB"),
+        None => match meminfo_available_mb() {
+            Some(memory_mb) => (memory_mb, "/proc/meminfo"),
+            None => {
+                println!("Couldn't tell the available memory, processing {cpus} repos at once, one per CPU");
+                return cpus;
+            }
+        },
+    };
+
+    let threads = ((memory_mb / config.clone_memory_mb) as usize).clamp(1, cpus);
+    println!(
+        "Processing {threads} repos at once, {memory_mb}MB available (from {source}) at {}MB per clone, {cpus} CPUs",
+        config.clone_memory_mb
+    );
+    threads
+}
+
+/// `MemAvailable` rather than `MemFree`, which doesn't count the page cache the kernel would give back
+fn meminfo_available_mb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let kb = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb / 1024)
+}
@@ -0,0 +1,1085 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Component, PathBuf},
+    str::FromStr,
+};
+
+use chrono::NaiveDate;
+use common::config::{flag, parsed};
+use encoding_rs::Encoding;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hmac::{Hmac, Mac};
+use octocrab::models;
+use sha2::Sha256;
+use tokei::LanguageType;
+
+use crate::source::ListedRepo;
+
+/// Every setting the job reads from the environment, parsed once at startup
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub personal_access_token: String,
+    /// Repos which are still counted in the totals, but left out of the per-repo stats
+    pub exclude_repos: Vec<String>,
+    /// Abort without saving when more than this percentage of repos fail
+    pub max_failure_percent: f64,
+    pub lfs_handling: LfsHandling,
+    /// Clone full histories and attribute each file's lines to the year it was last touched
+    pub by_year: bool,
+    /// Also keep a copy of every run's stats under a date stamped key
+    pub archive_snapshots: bool,
+    /// Carry on with the repos listed so far when fetching a later page of repos fails
+    pub continue_on_pagination_error: bool,
+    /// Leave out files matched by each repo's `.gitignore`, `.ignore` and `.tokeignore` files
+    pub respect_gitignore: bool,
+    /// Files matching any of these, relative to the repo root, are counted as tests
+    pub test_patterns: GlobSet,
+    /// Stop starting new repos after this long and save what has been gathered so far
+    pub max_runtime_secs: Option<u64>,
+    /// Display names used in place of the defaults
+    pub language_aliases: BTreeMap<LanguageType, String>,
+    /// Outside of lambda, keep running with this many seconds between runs instead of exiting
+    pub run_interval_secs: Option<u64>,
+    /// Skip the hardcoded contract work offsets so the totals are purely measured
+    pub disable_manual_adjustments: bool,
+    /// Repos GitHub reports as smaller than this aren't cloned at all
+    pub min_repo_size_kb: Option<u64>,
+    /// Analyze exactly these repos instead of listing them from GitHub
+    pub repo_list: Option<Vec<ListedRepo>>,
+    /// Languages past this many in a repo are folded into its `other` entry
+    pub max_languages_per_repo: Option<usize>,
+    /// Indent the stored JSON so it's readable, at the cost of size
+    pub pretty_json: bool,
+    /// Add each repo's topics to the per-repo stats, which can take an extra request per repo
+    pub include_topics: bool,
+    /// Config and docs, which shouldn't decide a repo's primary language
+    pub non_primary_languages: Vec<LanguageType>,
+    /// Where to POST the totals after they're saved
+    pub result_webhook_url: Option<url::Url>,
+    /// Sent as `X-Webhook-Secret` so the receiver can tell the request is ours
+    pub result_webhook_secret: Option<String>,
+    /// Left out of the totals, but still counted per repo
+    pub exclude_from_total: Vec<LanguageType>,
+    /// Left out of each repo's languages, but still counted in the totals
+    pub exclude_from_per_repo: Vec<LanguageType>,
+    /// Files with these extensions are counted as the given language instead of whatever tokei thinks
+    pub extension_overrides: BTreeMap<String, LanguageType>,
+    /// Repos pushed to within this many days are active
+    pub active_days: i64,
+    /// Repos not pushed to for more than this many days are stale
+    pub stale_days: i64,
+    /// Only count the files changed by this many of the latest commits, which deepens the clone to match
+    pub recent_commits_only: Option<usize>,
+    /// Repos marked as featured in the per-repo stats
+    pub featured_repos: Vec<String>,
+    /// Refer to private repos by a stable hash of their name in logs and stored objects
+    pub anonymize_private: bool,
+    /// Secret which keys the hashes of `anonymize_private`
+    pub anonymize_salt: String,
+    /// Also count files from every remote branch which differ from the ones at HEAD
+    pub all_branches: bool,
+    /// Only process this many of the largest repos
+    pub max_repos: Option<usize>,
+    /// Languages counted in the headline total, everything but the `non_primary_languages` when unset
+    pub headline_languages: Option<Vec<LanguageType>>,
+    /// Store comments and blanks as a single `non_code` count
+    pub combine_non_code: bool,
+    /// Repos which also get their stats split by top-level directory
+    pub monorepo_subdirs: Vec<String>,
+    /// Only these repos are processed at all, every repo when empty
+    pub include_repos: Vec<String>,
+    /// Also read `exclude_repos` and `include_repos` from the bucket at startup
+    pub excludes_from_bucket: bool,
+    pub excludes_precedence: ExcludesPrecedence,
+    /// No single file counts for more lines than this
+    pub per_file_line_cap: Option<usize>,
+    /// Give up on a repo when counting its lines takes longer than this
+    pub analyze_timeout_secs: Option<u64>,
+    /// Extra tries for each page of the repo listing when GitHub fails in a way that might go away
+    pub github_max_retries: u32,
+    /// Describe repos without a description by the start of their README
+    pub readme_description_fallback: bool,
+    /// Move the `docs_languages` out of the totals and into their own breakdown
+    pub separate_docs: bool,
+    pub docs_languages: Vec<LanguageType>,
+    /// Don't walk directories where at least this percentage of the files are binary, and none are counted
+    pub skip_binary_dirs: Option<f64>,
+    /// Also total only the files last touched between these dates, both inclusive
+    pub snapshot_since: Option<NaiveDate>,
+    pub snapshot_until: Option<NaiveDate>,
+    /// Give each repo in the per-repo stats a title cased `display_name`
+    pub prettify_names: bool,
+    /// TSX code is multiplied by this when it's folded into TypeScript
+    pub tsx_code_weight: f64,
+    /// Repos processed at once, worked out from the memory and CPUs when unset
+    pub concurrency: Option<usize>,
+    /// Used instead of reading `/proc/meminfo`
+    pub available_memory_mb: Option<u64>,
+    /// Rough peak memory of cloning and counting one repo
+    pub clone_memory_mb: u64,
+    /// Keep the counts of each repo by commit in the bucket and reuse them for the same commit
+    pub cache_per_repo_results: bool,
+    /// Cached counts written longer ago than this are counted again, and deleted
+    pub cache_ttl_days: u64,
+    /// Past this many cached counts, the oldest are deleted
+    pub cache_max_entries: usize,
+    /// Only count repos where the authenticated user has the most commits
+    pub own_repos_only: bool,
+    /// Analyze only this directory, as is, instead of cloning repos from GitHub
+    pub local_dir: Option<PathBuf>,
+    /// Also write the per-repo stats as a repo by language matrix
+    pub matrix: bool,
+    /// Retry a failed shallow clone as a full one
+    pub shallow_fallback_full: bool,
+    /// Turn every line ending into `\n` before counting
+    pub normalize_line_endings: bool,
+    /// Tried in order on files which aren't UTF-8
+    pub fallback_encodings: Vec<&'static Encoding>,
+    /// Leave out the file with the most lines of each language in a repo, as likely generated
+    pub drop_largest_file_per_language: bool,
+    /// Only count these directories of each repo, relative to its root
+    pub only_paths: Vec<PathBuf>,
+    /// Log how many repos are done and how long the rest should take as each one finishes
+    pub log_progress: bool,
+    /// Languages with less than this share of the total code, in percent, are left out of the total
+    pub min_total_percent: f64,
+    pub min_total_percent_mode: MinTotalPercentMode,
+    /// Take each repo's bytes per language from GitHub instead of cloning and counting lines
+    pub use_github_languages_api: bool,
+    /// Check each checkout and clone it again once when it looks corrupt
+    pub reclone_corrupt_checkouts: bool,
+    /// Domain of each repo in `by-domain.json`, by name
+    pub repo_domains: BTreeMap<String, String>,
+    /// Only this many of the repos with the most code are saved in the per-repo stats
+    pub max_per_repo_entries: Option<usize>,
+    /// Run over the total and per-repo stats in order, before the rest of post-processing
+    pub post_processors: Vec<BuiltInProcessor>,
+    /// Only count the change between two refs of `LOCAL_DIR` instead of a normal run
+    pub pr_diff_mode: bool,
+    /// Used when the lambda event has no `base`
+    pub pr_diff_base: Option<String>,
+    /// Used when the lambda event has no `head`
+    pub pr_diff_head: String,
+    /// Keep the per-repo stats on disk until they're saved, and save them a piece at a time
+    pub streaming_save: bool,
+    /// Signature file and the framework it means, checked in order for each repo's `primary_framework`
+    pub framework_signatures: Vec<(String, String)>,
+    /// Archive the total as of each of these days instead of a normal run, empty when not backfilling
+    pub backfill_dates: Vec<NaiveDate>,
+    /// Between each repo while backfilling
+    pub backfill_pause_secs: u64,
+    /// The `backfill_dates` day being counted, which clones are checked out as of. Never read from the environment.
+    pub backfill_as_of: Option<NaiveDate>,
+    /// `external-stats.json` to merge into the stats from this file
+    pub external_stats_file: Option<PathBuf>,
+    /// `external-stats.json` to merge into the stats from the bucket
+    pub external_stats_from_bucket: bool,
+    /// Reuse the cached counts of repos which haven't been pushed to since, without cloning them
+    pub skip_unpushed_repos: bool,
+    /// Keep every run's total under `history/`, with an index of them
+    pub history_snapshots: bool,
+    pub download_method: DownloadMethod,
+    /// Repos downloaded differently from `download_method`
+    pub download_method_overrides: BTreeMap<String, DownloadMethod>,
+    /// The languages tokei counts
+    pub languages: Vec<LanguageType>,
+    /// Patterns like in a `.gitignore` for files left out of every repo, on top of the built-in ones
+    pub exclude_paths: Vec<String>,
+    /// Lines added to the total for each language by the `manual_adjustments` post-processor
+    pub manual_adjustments: Vec<(LanguageType, usize)>,
+}
+
+impl Config {
+    /// Whether `dated-total.json` is written, which needs full histories
+    pub fn dated_snapshot(&self) -> bool {
+        self.snapshot_since.is_some() || self.snapshot_until.is_some()
+    }
+
+    pub fn download_method(&self, repo: &models::Repository) -> DownloadMethod {
+        self.download_method_overrides
+            .get(&repo.name)
+            .copied()
+            .unwrap_or(self.download_method)
+    }
+
+    /// How to refer to a repo anywhere other than the per-repo stats, which never have private repos.
+    /// The hash is an HMAC keyed by `ANONYMIZE_SALT`, so without the salt it can't be matched against the hashes of
+    /// likely names, even knowing the names behind other labels, and it
+    /// stays the same between runs and builds as long as the salt does, so a private repo can still be followed
+    /// across them.
+    pub fn repo_label(&self, repo: &models::Repository) -> String {
+        if !self.anonymize_private || !repo.private.is_some_and(|p| p) {
+            return repo.name.clone();
+        }
+
+        anonymized_label(&self.anonymize_salt, &repo.name)
+    }
+}
+
+/// What to do with files tracked by git-lfs, which are only pointer files in our clones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfsHandling {
+    /// Leave them out of the analysis
+    Skip,
+    /// Count the pointer files like any other file, only logging how many there are
+    Note,
+}
+
+impl FromStr for LfsHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "note" => Ok(Self::Note),
+            _ => Err("expected \"skip\" or \"note\"".to_string()),
+        }
+    }
+}
+
+/// What happens to the languages under `MIN_TOTAL_PERCENT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinTotalPercentMode {
+    /// Fold them together into the summary's `other`, so their code is still in its `total_code`
+    Fold,
+    /// Leave them out entirely
+    Drop,
+}
+
+impl FromStr for MinTotalPercentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fold" => Ok(Self::Fold),
+            "drop" => Ok(Self::Drop),
+            _ => Err("expected \"fold\" or \"drop\"".to_string()),
+        }
+    }
+}
+
+/// The `PostProcessor`s which can be picked by `POST_PROCESSORS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltInProcessor {
+    /// Fold TSX into TypeScript
+    CombineTsTsx,
+    /// Add the hardcoded offsets, unless `DISABLE_MANUAL_ADJUSTMENTS`
+    ManualAdjustments,
+}
+
+impl FromStr for BuiltInProcessor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "combine_ts_tsx" => Ok(Self::CombineTsTsx),
+            "manual_adjustments" => Ok(Self::ManualAdjustments),
+            _ => Err("expected \"combine_ts_tsx\" or \"manual_adjustments\"".to_string()),
+        }
+    }
+}
+
+/// How the repo lists in the bucket combine with the ones from the environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExcludesPrecedence {
+    /// Use both
+    Merge,
+    /// Only use the bucket's list when the env var's is empty
+    Env,
+}
+
+impl FromStr for ExcludesPrecedence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "merge" => Ok(Self::Merge),
+            "env" => Ok(Self::Env),
+            _ => Err("expected \"merge\" or \"env\"".to_string()),
+        }
+    }
+}
+
+/// How the repos are fetched before they're counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadMethod {
+    /// Clone with git, which has the history some settings need
+    Clone,
+    /// Download GitHub's tarball of the default branch, which is much faster but only has the files
+    Tarball,
+}
+
+impl FromStr for DownloadMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "clone" => Ok(Self::Clone),
+            "tarball" => Ok(Self::Tarball),
+            _ => Err("expected \"clone\" or \"tarball\"".to_string()),
+        }
+    }
+}
+
+/// All the problems found in the environment, so they can be fixed in one go
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        common::config::load_file(&mut problems);
+
+        let local_dir = var("LOCAL_DIR").map(PathBuf::from);
+        // Absolute since tokei's ignores are matched against the full path
+        let local_dir = local_dir.and_then(|dir| match dir.canonicalize() {
+            Ok(dir) if dir.is_dir() => Some(dir),
+            Ok(_) => {
+                problems.push(format!("LOCAL_DIR {} isn't a directory", dir.display()));
+                None
+            }
+            Err(e) => {
+                problems.push(format!("LOCAL_DIR {} can't be read: {e}", dir.display()));
+                None
+            }
+        });
+
+        // Nothing talks to GitHub when analyzing a local directory
+        let personal_access_token = if local_dir.is_some() {
+            var("PERSONAL_ACCESS_TOKEN").unwrap_or_default()
+        } else {
+            required("PERSONAL_ACCESS_TOKEN", &mut problems)
+        };
+        let exclude_repos = list("EXCLUDE_REPOS");
+        let max_failure_percent = parsed("MAX_FAILURE_PERCENT", 100.0, &mut problems);
+        if !(0.0..=100.0).contains(&max_failure_percent) {
+            problems.push(format!(
+                "MAX_FAILURE_PERCENT must be between 0 and 100, got {max_failure_percent}"
+            ));
+        }
+
+        let lfs_handling = parsed("LFS_HANDLING", LfsHandling::Skip, &mut problems);
+
+        let by_year = flag("BY_YEAR", false, &mut problems);
+        let archive_snapshots = flag("ARCHIVE_SNAPSHOTS", false, &mut problems);
+        let continue_on_pagination_error =
+            flag("CONTINUE_ON_PAGINATION_ERROR", false, &mut problems);
+
+        let respect_gitignore = flag("RESPECT_GITIGNORE", true, &mut problems);
+
+        let test_patterns = globs(
+            "TEST_PATH_PATTERNS",
+            &[
+                "**/test/**",
+                "**/tests/**",
+                "**/__tests__/**",
+                "*.test.*",
+                "*_test.*",
+                "*.spec.*",
+            ],
+            &mut problems,
+        );
+
+        let max_runtime_secs = optional("MAX_RUNTIME_SECS", &mut problems);
+
+        let mut language_aliases = BTreeMap::new();
+        for (language, alias) in pairs("LANGUAGE_ALIASES", &mut problems) {
+            match parse_language(&language) {
+                Some(language) => {
+                    language_aliases.insert(language, alias);
+                }
+                None => problems.push(format!(
+                    "LANGUAGE_ALIASES has unknown language \"{language}\""
+                )),
+            }
+        }
+
+        let run_interval_secs = optional("RUN_INTERVAL_SECS", &mut problems);
+        if run_interval_secs == Some(0) {
+            problems.push("RUN_INTERVAL_SECS must be greater than 0".to_string());
+        }
+
+        let disable_manual_adjustments = flag("DISABLE_MANUAL_ADJUSTMENTS", false, &mut problems);
+
+        let min_repo_size_kb = optional("MIN_REPO_SIZE_KB", &mut problems);
+
+        let repo_list = repo_list(&mut problems);
+        if local_dir.is_some() && repo_list.is_some() {
+            problems
+                .push("LOCAL_DIR can't be used with REPO_LIST_FILE or REPO_LIST_JSON".to_string());
+        }
+
+        let max_languages_per_repo = optional("MAX_LANGUAGES_PER_REPO", &mut problems);
+
+        let pretty_json = flag("PRETTY_JSON", false, &mut problems);
+
+        let include_topics = flag("INCLUDE_TOPICS", false, &mut problems);
+
+        let non_primary_languages = languages(
+            "NON_PRIMARY_LANGUAGES",
+            &[
+                LanguageType::Json,
+                LanguageType::Yaml,
+                LanguageType::Toml,
+                LanguageType::Markdown,
+            ],
+            &mut problems,
+        );
+
+        let result_webhook_url = optional("RESULT_WEBHOOK_URL", &mut problems);
+        let result_webhook_secret = var("RESULT_WEBHOOK_SECRET");
+
+        let exclude_from_total = languages("EXCLUDE_FROM_TOTAL", &[], &mut problems);
+        let exclude_from_per_repo = languages("EXCLUDE_FROM_PER_REPO", &[], &mut problems);
+
+        let mut extension_overrides = BTreeMap::new();
+        for (extension, language) in pairs("EXTENSION_OVERRIDES", &mut problems) {
+            let extension = extension.trim_start_matches('.');
+            if extension.is_empty() || extension.contains(['/', '*']) {
+                problems.push(format!(
+                    "EXTENSION_OVERRIDES has invalid extension \"{extension}\""
+                ));
+                continue;
+            }
+            match parse_language(&language) {
+                Some(language) => {
+                    extension_overrides.insert(extension.to_string(), language);
+                }
+                None => problems.push(format!(
+                    "EXTENSION_OVERRIDES has unknown language \"{language}\""
+                )),
+            }
+        }
+
+        let active_days = parsed("ACTIVE_DAYS", 30, &mut problems);
+        let stale_days = parsed("STALE_DAYS", 365, &mut problems);
+        if active_days < 0 || stale_days < active_days {
+            problems.push(format!(
+                "ACTIVE_DAYS ({active_days}) must be at least 0 and at most STALE_DAYS ({stale_days})"
+            ));
+        }
+
+        let recent_commits_only = optional("RECENT_COMMITS_ONLY", &mut problems);
+        if recent_commits_only == Some(0) {
+            problems.push("RECENT_COMMITS_ONLY must be greater than 0".to_string());
+        }
+
+        let featured_repos = list("FEATURED_REPOS");
+
+        let anonymize_private = flag("ANONYMIZE_PRIVATE", false, &mut problems);
+        let anonymize_salt = if anonymize_private {
+            required("ANONYMIZE_SALT", &mut problems)
+        } else {
+            String::new()
+        };
+
+        let all_branches = flag("ALL_BRANCHES", false, &mut problems);
+
+        let max_repos = optional("MAX_REPOS", &mut problems);
+
+        let headline_languages =
+            var("HEADLINE_LANGUAGES").map(|_| languages("HEADLINE_LANGUAGES", &[], &mut problems));
+
+        let combine_non_code = flag("COMBINE_NON_CODE", false, &mut problems);
+
+        let monorepo_subdirs = list("MONOREPO_SUBDIRS");
+
+        let include_repos = list("INCLUDE_REPOS");
+
+        let excludes_from_bucket = flag("EXCLUDES_FROM_BUCKET", false, &mut problems);
+
+        let excludes_precedence = parsed(
+            "EXCLUDES_PRECEDENCE",
+            ExcludesPrecedence::Merge,
+            &mut problems,
+        );
+
+        let per_file_line_cap = optional("PER_FILE_LINE_CAP", &mut problems);
+
+        let analyze_timeout_secs = optional("ANALYZE_TIMEOUT_SECS", &mut problems);
+
+        let github_max_retries = parsed("GITHUB_MAX_RETRIES", 3, &mut problems);
+
+        let readme_description_fallback = flag("README_DESCRIPTION_FALLBACK", false, &mut problems);
+
+        let separate_docs = flag("SEPARATE_DOCS", false, &mut problems);
+
+        let docs_languages = languages("DOCS_LANGUAGES", &[LanguageType::Markdown], &mut problems);
+
+        let skip_binary_dirs = optional("SKIP_BINARY_DIRS", &mut problems);
+        if skip_binary_dirs.is_some_and(|percent: f64| !(0.0..=100.0).contains(&percent)) {
+            problems.push("SKIP_BINARY_DIRS must be between 0 and 100".to_string());
+        }
+
+        let snapshot_since: Option<NaiveDate> = optional("SNAPSHOT_SINCE", &mut problems);
+        let snapshot_until: Option<NaiveDate> = optional("SNAPSHOT_UNTIL", &mut problems);
+        if let (Some(since), Some(until)) = (snapshot_since, snapshot_until) {
+            if since > until {
+                problems.push(format!(
+                    "SNAPSHOT_SINCE ({since}) must not be after SNAPSHOT_UNTIL ({until})"
+                ));
+            }
+        }
+
+        let prettify_names = flag("PRETTIFY_NAMES", false, &mut problems);
+
+        let tsx_code_weight: f64 = parsed("TSX_CODE_WEIGHT", 1.0, &mut problems);
+        if !(tsx_code_weight >= 0.0 && tsx_code_weight.is_finite()) {
+            problems.push(format!(
+                "TSX_CODE_WEIGHT must be a non-negative number, got {tsx_code_weight}"
+            ));
+        }
+
+        let concurrency = optional("CONCURRENCY", &mut problems);
+        if concurrency == Some(0) {
+            problems.push("CONCURRENCY must be at least 1".to_string());
+        }
+
+        let available_memory_mb = optional("AVAILABLE_MEMORY_MB", &mut problems);
+
+        let clone_memory_mb = parsed("CLONE_MEMORY_MB", 256, &mut problems);
+        if clone_memory_mb == 0 {
+            problems.push("CLONE_MEMORY_MB must be at least 1".to_string());
+        }
+
+        let cache_per_repo_results = flag("CACHE_PER_REPO_RESULTS", false, &mut problems);
+
+        let cache_ttl_days = parsed("CACHE_TTL_DAYS", 30, &mut problems);
+
+        let cache_max_entries = parsed("CACHE_MAX_ENTRIES", 1000, &mut problems);
+
+        let own_repos_only = flag("OWN_REPOS_ONLY", false, &mut problems);
+
+        let matrix = flag("MATRIX", false, &mut problems);
+
+        let shallow_fallback_full = flag("SHALLOW_FALLBACK_FULL", true, &mut problems);
+
+        let normalize_line_endings = flag("NORMALIZE_LINE_ENDINGS", false, &mut problems);
+
+        let fallback_encodings = list("FALLBACK_ENCODINGS")
+            .into_iter()
+            .filter_map(|label| {
+                let encoding = Encoding::for_label(label.as_bytes());
+                if encoding.is_none() {
+                    problems.push(format!(
+                        "FALLBACK_ENCODINGS has unknown encoding \"{label}\""
+                    ));
+                }
+                encoding
+            })
+            .collect();
+
+        let drop_largest_file_per_language =
+            flag("DROP_LARGEST_FILE_PER_LANGUAGE", false, &mut problems);
+
+        let only_paths = list("ONLY_PATHS")
+            .into_iter()
+            .map(|path| PathBuf::from(path.trim_matches('/')))
+            .filter(|path| {
+                let inside = path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)));
+                if !inside {
+                    problems.push(format!(
+                        "ONLY_PATHS has \"{}\", which isn't inside the repo",
+                        path.display()
+                    ));
+                }
+                inside
+            })
+            .collect();
+
+        let log_progress = flag("LOG_PROGRESS", true, &mut problems);
+
+        let min_total_percent = parsed("MIN_TOTAL_PERCENT", 0.0, &mut problems);
+        if !(0.0..=100.0).contains(&min_total_percent) {
+            problems.push(format!(
+                "MIN_TOTAL_PERCENT must be between 0 and 100, got {min_total_percent}"
+            ));
+        }
+        let min_total_percent_mode = parsed(
+            "MIN_TOTAL_PERCENT_MODE",
+            MinTotalPercentMode::Fold,
+            &mut problems,
+        );
+
+        let use_github_languages_api = flag("USE_GITHUB_LANGUAGES_API", false, &mut problems);
+        if use_github_languages_api && local_dir.is_some() {
+            problems.push(
+                "USE_GITHUB_LANGUAGES_API can't be used with LOCAL_DIR, which isn't on GitHub"
+                    .to_string(),
+            );
+        }
+
+        let reclone_corrupt_checkouts = flag("RECLONE_CORRUPT_CHECKOUTS", true, &mut problems);
+
+        let repo_domains = pairs("REPO_DOMAINS", &mut problems).into_iter().collect();
+
+        let max_per_repo_entries = optional("MAX_PER_REPO_ENTRIES", &mut problems);
+
+        let post_processors = if var("POST_PROCESSORS").is_some() {
+            list("POST_PROCESSORS")
+                .into_iter()
+                .filter_map(|name| match name.parse() {
+                    Ok(processor) => Some(processor),
+                    Err(e) => {
+                        problems.push(format!(
+                            "POST_PROCESSORS has unknown processor \"{name}\": {e}"
+                        ));
+                        None
+                    }
+                })
+                .collect()
+        } else {
+            vec![
+                BuiltInProcessor::CombineTsTsx,
+                BuiltInProcessor::ManualAdjustments,
+            ]
+        };
+
+        let pr_diff_mode = flag("PR_DIFF_MODE", false, &mut problems);
+        if pr_diff_mode && local_dir.is_none() {
+            problems
+                .push("PR_DIFF_MODE needs LOCAL_DIR, the checkout of the repo to diff".to_string());
+        }
+        let pr_diff_base = var("PR_DIFF_BASE");
+        let pr_diff_head = var("PR_DIFF_HEAD").unwrap_or_else(|| "HEAD".to_string());
+
+        let streaming_save = flag("STREAMING_SAVE", false, &mut problems);
+
+        let framework_signatures = if var("FRAMEWORK_SIGNATURES").is_some() {
+            pairs("FRAMEWORK_SIGNATURES", &mut problems)
+        } else {
+            crate::frameworks::BUILT_IN_SIGNATURES
+                .iter()
+                .map(|&(path, framework)| (path.to_string(), framework.to_string()))
+                .collect()
+        };
+
+        let backfill_dates = list("BACKFILL_MODE")
+            .into_iter()
+            .filter_map(|date| match date.parse::<NaiveDate>() {
+                Ok(date) => Some(date),
+                Err(e) => {
+                    problems.push(format!(
+                        "BACKFILL_MODE has invalid date \"{date}\", expected YYYY-MM-DD: {e}"
+                    ));
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if !backfill_dates.is_empty() {
+            if snapshot_since.is_some() || snapshot_until.is_some() {
+                problems.push(
+                    "BACKFILL_MODE can't be used with SNAPSHOT_SINCE/SNAPSHOT_UNTIL, each date is counted as of that day"
+                        .to_string(),
+                );
+            }
+            if use_github_languages_api {
+                problems.push(
+                    "BACKFILL_MODE can't be used with USE_GITHUB_LANGUAGES_API, which has no history"
+                        .to_string(),
+                );
+            }
+            if pr_diff_mode {
+                problems.push("BACKFILL_MODE can't be used with PR_DIFF_MODE".to_string());
+            }
+            if local_dir.is_some() {
+                problems.push(
+                    "BACKFILL_MODE can't be used with LOCAL_DIR, only clones are checked out as of each date"
+                        .to_string(),
+                );
+            }
+        }
+        let backfill_pause_secs = parsed("BACKFILL_PAUSE_SECS", 5, &mut problems);
+
+        let external_stats_file = var("EXTERNAL_STATS_FILE").map(PathBuf::from);
+        let external_stats_from_bucket = flag("EXTERNAL_STATS_FROM_BUCKET", false, &mut problems);
+        if external_stats_file.is_some() && external_stats_from_bucket {
+            problems.push(
+                "Only one of EXTERNAL_STATS_FILE and EXTERNAL_STATS_FROM_BUCKET can be set"
+                    .to_string(),
+            );
+        }
+
+        let skip_unpushed_repos = flag("SKIP_UNPUSHED_REPOS", false, &mut problems);
+        if skip_unpushed_repos && !cache_per_repo_results {
+            problems.push(
+                "SKIP_UNPUSHED_REPOS needs CACHE_PER_REPO_RESULTS, the counts it reuses are kept there"
+                    .to_string(),
+            );
+        }
+
+        let history_snapshots = flag("HISTORY_SNAPSHOTS", false, &mut problems);
+
+        let download_method = parsed("DOWNLOAD_METHOD", DownloadMethod::Clone, &mut problems);
+        let download_method_overrides = pairs("DOWNLOAD_METHOD_OVERRIDES", &mut problems)
+            .into_iter()
+            .filter_map(|(repo, method)| match method.parse() {
+                Ok(method) => Some((repo, method)),
+                Err(e) => {
+                    problems.push(format!(
+                        "DOWNLOAD_METHOD_OVERRIDES has invalid method \"{method}\" for \"{repo}\": {e}"
+                    ));
+                    None
+                }
+            })
+            .collect::<BTreeMap<_, _>>();
+        if download_method == DownloadMethod::Tarball
+            || download_method_overrides
+                .values()
+                .any(|&method| method == DownloadMethod::Tarball)
+        {
+            let needs_history = [
+                ("BY_YEAR", by_year),
+                (
+                    "SNAPSHOT_SINCE/SNAPSHOT_UNTIL",
+                    snapshot_since.is_some() || snapshot_until.is_some(),
+                ),
+                ("BACKFILL_MODE", !backfill_dates.is_empty()),
+                ("ALL_BRANCHES", all_branches),
+                ("RECENT_COMMITS_ONLY", recent_commits_only.is_some()),
+            ];
+            for (setting, _) in needs_history.iter().filter(|(_, set)| *set) {
+                problems.push(format!(
+                    "{setting} can't be used with tarball downloads, which have no git history"
+                ));
+            }
+        }
+
+        let languages = languages("LANGUAGES", crate::DEFAULT_LANGUAGES, &mut problems);
+        if languages.is_empty() {
+            problems.push("LANGUAGES can't be empty, nothing would be counted".to_string());
+        }
+
+        let exclude_paths = list("EXCLUDE_PATHS");
+
+        let manual_adjustments = if var("MANUAL_ADJUSTMENTS").is_some() {
+            pairs("MANUAL_ADJUSTMENTS", &mut problems)
+                .into_iter()
+                .filter_map(|(language, lines)| {
+                    let Some(ty) = parse_language(&language) else {
+                        problems.push(format!(
+                            "MANUAL_ADJUSTMENTS has unknown language \"{language}\""
+                        ));
+                        return None;
+                    };
+                    match lines.parse() {
+                        Ok(lines) => Some((ty, lines)),
+                        Err(e) => {
+                            problems.push(format!(
+                                "MANUAL_ADJUSTMENTS has invalid lines \"{lines}\" for {language}: {e}"
+                            ));
+                            None
+                        }
+                    }
+                })
+                .collect()
+        } else {
+            crate::post::BUILT_IN_ADJUSTMENTS.to_vec()
+        };
+
+        // Only read by `common`, which needs a bucket when it's talking to S3
+        let storage_backend = match common::store::StoreKind::from_env() {
+            Ok(kind) => kind,
+            Err(e) => {
+                problems.push(e);
+                Default::default()
+            }
+        };
+        if storage_backend == common::store::StoreKind::S3 {
+            required("BUCKET_NAME", &mut problems);
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError(problems));
+        }
+        storage_backend.install();
+
+        Ok(Self {
+            personal_access_token,
+            exclude_repos,
+            max_failure_percent,
+            lfs_handling,
+            by_year,
+            archive_snapshots,
+            continue_on_pagination_error,
+            respect_gitignore,
+            test_patterns,
+            max_runtime_secs,
+            language_aliases,
+            run_interval_secs,
+            disable_manual_adjustments,
+            min_repo_size_kb,
+            repo_list,
+            max_languages_per_repo,
+            pretty_json,
+            include_topics,
+            non_primary_languages,
+            result_webhook_url,
+            result_webhook_secret,
+            exclude_from_total,
+            exclude_from_per_repo,
+            extension_overrides,
+            active_days,
+            stale_days,
+            recent_commits_only,
+            featured_repos,
+            anonymize_private,
+            anonymize_salt,
+            all_branches,
+            max_repos,
+            headline_languages,
+            combine_non_code,
+            monorepo_subdirs,
+            include_repos,
+            excludes_from_bucket,
+            excludes_precedence,
+            per_file_line_cap,
+            analyze_timeout_secs,
+            github_max_retries,
+            readme_description_fallback,
+            separate_docs,
+            docs_languages,
+            skip_binary_dirs,
+            snapshot_since,
+            snapshot_until,
+            prettify_names,
+            tsx_code_weight,
+            concurrency,
+            available_memory_mb,
+            clone_memory_mb,
+            cache_per_repo_results,
+            cache_ttl_days,
+            cache_max_entries,
+            own_repos_only,
+            local_dir,
+            matrix,
+            shallow_fallback_full,
+            normalize_line_endings,
+            fallback_encodings,
+            drop_largest_file_per_language,
+            only_paths,
+            log_progress,
+            min_total_percent,
+            min_total_percent_mode,
+            use_github_languages_api,
+            reclone_corrupt_checkouts,
+            repo_domains,
+            max_per_repo_entries,
+            post_processors,
+            pr_diff_mode,
+            pr_diff_base,
+            pr_diff_head,
+            streaming_save,
+            framework_signatures,
+            backfill_dates,
+            backfill_pause_secs,
+            backfill_as_of: None,
+            external_stats_file,
+            external_stats_from_bucket,
+            skip_unpushed_repos,
+            history_snapshots,
+            download_method,
+            download_method_overrides,
+            languages,
+            exclude_paths,
+            manual_adjustments,
+        })
+    }
+}
+
+/// From `REPO_LIST_JSON` directly, or the file at `REPO_LIST_FILE`
+fn repo_list(problems: &mut Vec<String>) -> Option<Vec<ListedRepo>> {
+    let (name, json) = match (var("REPO_LIST_JSON"), var("REPO_LIST_FILE")) {
+        (Some(_), Some(_)) => {
+            problems.push("Only one of REPO_LIST_JSON and REPO_LIST_FILE can be set".to_string());
+            return None;
+        }
+        (Some(json), None) => ("REPO_LIST_JSON", json),
+        (None, Some(path)) => match std::fs::read_to_string(&path) {
+            Ok(json) => ("REPO_LIST_FILE", json),
+            Err(e) => {
+                problems.push(format!("REPO_LIST_FILE \"{path}\" couldn't be read: {e}"));
+                return None;
+            }
+        },
+        (None, None) => return None,
+    };
+
+    match serde_json::from_str::<Vec<ListedRepo>>(&json) {
+        Ok(list) => {
+            problems.extend(
+                list.iter()
+                    .filter_map(|repo| repo.validate().err())
+                    .map(|problem| format!("{name} {problem}")),
+            );
+            Some(list)
+        }
+        Err(e) => {
+            problems.push(format!("{name} is invalid: {e}"));
+            None
+        }
+    }
+}
+
+fn globs(name: &str, default: &[&str], problems: &mut Vec<String>) -> GlobSet {
+    let patterns = var(name)
+        .map(|_| list(name))
+        .unwrap_or_else(|| default.iter().map(|p| p.to_string()).collect());
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => problems.push(format!("{name} has invalid pattern \"{pattern}\": {e}")),
+        }
+    }
+
+    builder.build().unwrap_or_else(|e| {
+        problems.push(format!("{name} is invalid: {e}"));
+        GlobSet::empty()
+    })
+}
+
+fn optional<T>(name: &str, problems: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = var(name)?;
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            problems.push(format!("{name} has invalid value \"{value}\": {e}"));
+            None
+        }
+    }
+}
+
+fn languages(
+    name: &str,
+    default: &[LanguageType],
+    problems: &mut Vec<String>,
+) -> Vec<LanguageType> {
+    if var(name).is_none() {
+        return default.to_vec();
+    }
+
+    list(name)
+        .into_iter()
+        .filter_map(|language| {
+            let parsed = parse_language(&language);
+            if parsed.is_none() {
+                problems.push(format!("{name} has unknown language \"{language}\""));
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Accepts both the serialized name (`Sh`) and tokei's display name (`Shell`)
+fn parse_language(name: &str) -> Option<LanguageType> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .ok()
+        .or_else(|| name.parse().ok())
+}
+
+/// Comma separated `key=value` pairs
+fn pairs(name: &str, problems: &mut Vec<String>) -> Vec<(String, String)> {
+    list(name)
+        .into_iter()
+        .filter_map(|pair| match pair.split_once('=') {
+            Some((key, value)) => Some((key.trim().to_string(), value.trim().to_string())),
+            None => {
+                problems.push(format!(
+                    "{name} has invalid entry \"{pair}\", expected key=value"
+                ));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Value of an env var, or else the config file's, treating empty as unset
+fn var(name: &str) -> Option<String> {
+    common::config::var(name)
+}
+
+/// The first 8 bytes of the HMAC-SHA256 of the name, keyed by the salt
+fn anonymized_label(salt: &str, name: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(name.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    let hex = hash[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    format!("private-repo-{hex}")
+}
+
+fn required(name: &str, problems: &mut Vec<String>) -> String {
+    var(name).unwrap_or_else(|| {
+        problems.push(format!("{name} must be set"));
+        String::new()
+    })
+}
+
+/// Comma separated list, ignoring empty entries
+fn list(name: &str) -> Vec<String> {
+    var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The defaults, as if only the token was set
+    pub fn config() -> Config {
+        std::env::set_var("PERSONAL_ACCESS_TOKEN", "test");
+        Config::from_env().unwrap()
+    }
+
+    #[test]
+    fn anonymized_labels_are_keyed_by_the_salt() {
+        // The start of the HMAC-SHA256 test vector of RFC 4231 test case 2, so a change to the hash which would
+        // rename every private repo doesn't go unnoticed
+        assert_eq!(
+            anonymized_label("Jefe", "what do ya want for nothing?"),
+            "private-repo-5bdcc146bf60754e"
+        );
+        assert_ne!(
+            anonymized_label("salt", "secret-project"),
+            anonymized_label("other salt", "secret-project")
+        );
+        assert_ne!(
+            anonymized_label("salt", "secret-project"),
+            anonymized_label("salt", "secret-projects")
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use octocrab::{models, Octocrab};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, source::with_retries};
+
+#[derive(Deserialize)]
+struct Contributor {
+    login: String,
+    contributions: u32,
+}
+
+/// Checks from earlier runs, only redone for repos pushed to since
+#[derive(Default, Serialize, Deserialize)]
+struct ContributorsCache {
+    /// Whose repos they are, a different token starts over
+    login: String,
+    repos: BTreeMap<String, CachedCheck>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCheck {
+    pushed_at: Option<DateTime<Utc>>,
+    primary: bool,
+}
+
+/// `OWN_REPOS_ONLY`, leaves out the repos where the authenticated user doesn't have the most commits. Repos
+/// GitHub can't say for, like empty repos or ones from a static list, are kept.
+pub async fn retain_own_repos(
+    github: &Octocrab,
+    repos: &mut Vec<models::Repository>,
+    config: &Config,
+) -> Result<(), lambda_runtime::Error> {
+    let login = with_retries(config, "get the authenticated user", || {
+        github.get::<models::Author, _, ()>("/user", None)
+    })
+    .await?
+    .login;
+
+    let mut cache = match common::get_object(common::CONTRIBUTORS_CACHE_OBJ_NAME).await {
+        Ok(bytes) => serde_json::from_slice::<ContributorsCache>(&bytes)
+            .ok()
+            .filter(|cache| cache.login == login)
+            .unwrap_or_default(),
+        Err(_) => ContributorsCache::default(),
+    };
+    cache.login.clone_from(&login);
+
+    let mut checked = 0;
+    let mut own = Vec::with_capacity(repos.len());
+    for repo in repos.drain(..) {
+        let Some(owner) = repo.owner.as_ref().map(|owner| owner.login.clone()) else {
+            own.push(repo);
+            continue;
+        };
+        let full_name = format!("{owner}/{}", repo.name);
+
+        let primary = match cache.repos.get(&full_name) {
+            Some(check) if check.pushed_at == repo.pushed_at => check.primary,
+            _ => {
+                // Sorted by commits, so the top contributor is always on the first page
+                let contributors = with_retries(config, "list contributors", || {
+                    github.get::<Vec<Contributor>, _, _>(
+                        format!("/repos/{full_name}/contributors"),
+                        Some(&[("per_page", "100")]),
+                    )
+                })
+                .await;
+                match contributors {
+                    Ok(contributors) => {
+                        checked += 1;
+                        let primary = is_primary(&contributors, &login);
+                        cache.repos.insert(
+                            full_name,
+                            CachedCheck {
+                                pushed_at: repo.pushed_at,
+                                primary,
+                            },
+                        );
+                        primary
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to list the contributors of \"{}\", keeping it: {e}",
+                            config.repo_label(&repo)
+                        );
+                        true
+                    }
+                }
+            }
+        };
+
+        if primary {
+            own.push(repo);
+        } else {
+            println!(
+                "Skipping \"{}\", not primarily yours",
+                config.repo_label(&repo)
+            );
+        }
+    }
+    *repos = own;
+    println!(
+        "{} repos are primarily yours, {checked} were checked with the contributors API",
+        repos.len()
+    );
+
+    if let Err(e) = common::save_object(
+        common::CONTRIBUTORS_CACHE_OBJ_NAME,
+        &serde_json::to_string(&cache)?,
+    )
+    .await
+    {
+        eprintln!("Failed to save the contributors cache: {e}");
+    }
+
+    Ok(())
+}
+
+/// The only contributor, or tied for the most commits
+fn is_primary(contributors: &[Contributor], login: &str) -> bool {
+    let most = contributors
+        .iter()
+        .map(|contributor| contributor.contributions)
+        .max()
+        .unwrap_or_default();
+    contributors.iter().any(|contributor| {
+        contributor.login.eq_ignore_ascii_case(login) && contributor.contributions == most
+    })
+}
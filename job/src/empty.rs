@@ -0,0 +1,35 @@
+use std::{fs::File, io::Read, path::Path};
+
+use common::stats::EmptyReason;
+use ignore::WalkBuilder;
+
+/// Why a repo which cloned fine had no code counted, decided by what's in its worktree
+pub fn classify(repo_path: &Path) -> EmptyReason {
+    let mut files = WalkBuilder::new(repo_path)
+        .standard_filters(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|ty| ty.is_file()))
+        .peekable();
+
+    if files.peek().is_none() {
+        return EmptyReason::Empty;
+    }
+
+    if files.any(|entry| is_text(entry.path())) {
+        EmptyReason::AllExcluded
+    } else {
+        EmptyReason::AllBinary
+    }
+}
+
+/// Same check git uses, a NUL byte near the start means binary
+pub fn is_text(path: &Path) -> bool {
+    let mut start = [0; 8000];
+    let Ok(read) = File::open(path).and_then(|mut file| file.read(&mut start)) else {
+        return false;
+    };
+
+    !start[..read].contains(&0)
+}
@@ -0,0 +1,130 @@
+use std::{borrow::Cow, fs};
+
+use encoding_rs::Encoding;
+use tokei::Languages;
+
+use crate::config::Config;
+
+/// Counts files again where tokei can't read them right on its own. tokei only decodes files with a BOM, and only
+/// splits lines on `\n`, so an old Mac file with lone `\r` line endings counts as a single line. With
+/// `NORMALIZE_LINE_ENDINGS` every line ending becomes `\n`, and files which aren't UTF-8 are decoded with the first
+/// of the `FALLBACK_ENCODINGS` they're valid in. Returns how many files were counted again.
+pub fn recount(languages: &mut Languages, config: &Config, tokei_config: &tokei::Config) -> usize {
+    if !config.normalize_line_endings && config.fallback_encodings.is_empty() {
+        return 0;
+    }
+
+    let mut recounted = 0;
+    for (ty, lang) in languages.iter_mut() {
+        let mut touched = false;
+        for report in &mut lang.reports {
+            let Ok(bytes) = fs::read(&report.name) else {
+                continue;
+            };
+            let Some(text) = normalize(&bytes, config) else {
+                continue;
+            };
+
+            report.stats = ty.parse_from_slice(text.as_bytes(), tokei_config);
+            touched = true;
+            recounted += 1;
+        }
+
+        // The language's totals are only summed from its reports when asked
+        if touched {
+            lang.total();
+        }
+    }
+
+    recounted
+}
+
+/// The file as tokei should have seen it, or `None` when it already did
+pub fn normalize(bytes: &[u8], config: &Config) -> Option<String> {
+    let (text, decoded) = match Encoding::for_bom(bytes) {
+        // Already decoded by tokei
+        Some((encoding, bom_len)) => (
+            encoding.decode_without_bom_handling(&bytes[bom_len..]).0,
+            false,
+        ),
+        None => match std::str::from_utf8(bytes) {
+            Ok(text) => (Cow::Borrowed(text), false),
+            Err(_) => (
+                config.fallback_encodings.iter().find_map(|encoding| {
+                    encoding.decode_without_bom_handling_and_without_replacement(bytes)
+                })?,
+                true,
+            ),
+        },
+    };
+
+    if config.normalize_line_endings && text.contains('\r') {
+        Some(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else if decoded {
+        Some(text.into_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokei::LanguageType;
+
+    use super::*;
+
+    /// Counts of `lf.rs`, `crlf.rs` and `cr.rs`, which only differ in their line endings
+    fn counts(config: &Config) -> Vec<(String, (usize, usize, usize))> {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = ["fn a() {}", "// comment", "", "fn b() {}", ""];
+        for (name, ending) in [("lf.rs", "\n"), ("crlf.rs", "\r\n"), ("cr.rs", "\r")] {
+            fs::write(dir.path().join(name), lines.join(ending)).unwrap();
+        }
+
+        let tokei_config = tokei::Config::default();
+        let mut languages = Languages::new();
+        languages.get_statistics(&[dir.path()], &[], &tokei_config);
+        recount(&mut languages, config, &tokei_config);
+
+        let mut counts = languages[&LanguageType::Rust]
+            .reports
+            .iter()
+            .map(|report| {
+                let name = report.name.file_name().unwrap().to_string_lossy();
+                let stats = &report.stats;
+                (
+                    name.into_owned(),
+                    (stats.code, stats.comments, stats.blanks),
+                )
+            })
+            .collect::<Vec<_>>();
+        counts.sort();
+        counts
+    }
+
+    #[test]
+    fn line_endings_are_normalized() {
+        let lf = (2, 1, 1);
+        let config = Config {
+            normalize_line_endings: true,
+            ..crate::config::tests::config()
+        };
+        assert_eq!(
+            counts(&config),
+            [
+                ("cr.rs".to_string(), lf),
+                ("crlf.rs".to_string(), lf),
+                ("lf.rs".to_string(), lf)
+            ]
+        );
+
+        // tokei already handles CRLF, but not a lone CR
+        let config = Config {
+            normalize_line_endings: false,
+            ..config
+        };
+        let counts = counts(&config);
+        assert_ne!(counts[0].1, lf);
+        assert_eq!(counts[1].1, lf);
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Deserialize;
+
+use crate::config::{Config, ExcludesPrecedence};
+
+/// `config/excludes.json`, the same lists as `EXCLUDE_REPOS` and `INCLUDE_REPOS` but editable without a redeploy
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BucketExcludes {
+    exclude: Vec<String>,
+    include: Vec<String>,
+}
+
+/// Adds the lists from the bucket to the ones from the environment, which are left as they are when the object
+/// is missing. Any other error getting it is returned, like an invalid object is
+pub async fn load_from_bucket(config: &mut Config) -> Result<(), String> {
+    let bytes = match common::get_object(common::EXCLUDES_OBJ_NAME).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.is_not_found() => {
+            println!(
+                "No {} in the bucket, only using the env vars",
+                common::EXCLUDES_OBJ_NAME
+            );
+            return Ok(());
+        }
+        // Running without the lists would count repos which were meant to be excluded
+        Err(e) => return Err(format!("Failed to get {}: {e}", common::EXCLUDES_OBJ_NAME)),
+    };
+    let excludes = serde_json::from_slice::<BucketExcludes>(&bytes)
+        .map_err(|e| format!("{} is invalid: {e}", common::EXCLUDES_OBJ_NAME))?;
+    println!(
+        "Loaded {} excluded and {} included repos from the bucket",
+        excludes.exclude.len(),
+        excludes.include.len()
+    );
+
+    let precedence = config.excludes_precedence;
+    for (current, from_bucket) in [
+        (&mut config.exclude_repos, excludes.exclude),
+        (&mut config.include_repos, excludes.include),
+    ] {
+        if precedence == ExcludesPrecedence::Env && !current.is_empty() {
+            continue;
+        }
+
+        for name in from_bucket {
+            if !current.contains(&name) {
+                current.push(name);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use tokei::Languages;
+
+use crate::config::Config;
+
+/// Patterns to hand tokei as ignored, so files with an overridden extension are only counted by
+/// [`count_overridden`]
+pub fn ignore_patterns(config: &Config) -> Vec<String> {
+    config
+        .extension_overrides
+        .keys()
+        .map(|ext| format!("*.{ext}"))
+        .collect()
+}
+
+/// tokei has no way to map extensions itself, so walk the repo the same way it does and parse the
+/// files with an overridden extension as the configured language
+pub fn count_overridden(
+    repo_path: &Path,
+    paths: &[PathBuf],
+    ignored: &[&str],
+    config: &Config,
+    tokei_config: &tokei::Config,
+    languages: &mut Languages,
+) {
+    if config.extension_overrides.is_empty() {
+        return;
+    }
+
+    let mut overrides = OverrideBuilder::new(repo_path);
+    for pattern in ignored {
+        if let Err(e) = overrides.add(&format!("!{pattern}")) {
+            println!("Ignoring invalid pattern \"{pattern}\": {e}");
+        }
+    }
+    let Ok(overrides) = overrides.build() else {
+        return;
+    };
+
+    let Some((first, rest)) = paths.split_first() else {
+        return;
+    };
+    let mut walker = WalkBuilder::new(first);
+    for path in rest {
+        walker.add(path);
+    }
+    if config.respect_gitignore {
+        walker.add_custom_ignore_filename(".tokeignore");
+    }
+    walker
+        .overrides(overrides)
+        .git_ignore(config.respect_gitignore)
+        .git_exclude(config.respect_gitignore)
+        .git_global(config.respect_gitignore)
+        .ignore(config.respect_gitignore);
+
+    let mut touched = BTreeSet::new();
+    for entry in walker.build().filter_map(Result::ok) {
+        if !entry.file_type().is_some_and(|ty| ty.is_file()) {
+            continue;
+        }
+        let Some(&ty) = entry
+            .path()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| config.extension_overrides.get(ext))
+        else {
+            continue;
+        };
+
+        match ty.parse(entry.into_path(), tokei_config) {
+            Ok(report) => {
+                languages.entry(ty).or_default().add_report(report);
+                touched.insert(ty);
+            }
+            Err((e, path)) => println!("Failed to count {}: {e}", path.display()),
+        }
+    }
+
+    // Adding reports doesn't update the language's totals
+    for ty in touched {
+        languages.entry(ty).or_default().total();
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeSet;
+
+use common::stats::{PerRepo, SimpleLanguage};
+use octocrab::models;
+use serde::Deserialize;
+
+use crate::{config::Config, post::add_language};
+
+/// `external-stats.json`, code counted somewhere else, in the same shape as the stats the job saves
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExternalStats {
+    /// Only added to the total, for code which has no repo to list
+    pub total: Vec<SimpleLanguage>,
+    /// Added to the per-repo stats, and to the total like any counted repo
+    pub per_repo: Vec<PerRepo>,
+}
+
+impl ExternalStats {
+    /// Adds the languages into the computed `total`, along with those of each repo, which then goes to
+    /// `add_repo` like a counted one
+    pub fn merge(self, total: &mut Vec<SimpleLanguage>, mut add_repo: impl FnMut(PerRepo)) {
+        for lang in self.total {
+            add_language(total, lang);
+        }
+        for repo in self.per_repo {
+            for lang in &repo.languages {
+                add_language(total, lang.clone());
+            }
+            add_repo(repo);
+        }
+    }
+}
+
+/// From `EXTERNAL_STATS_FILE` or the bucket with `EXTERNAL_STATS_FROM_BUCKET`, `None` when neither is set.
+/// Stats which don't fit the schema, or repos named like one of the counted `repos`, are an error rather than
+/// being left out, so the saved stats are never quietly missing them.
+pub async fn load(
+    config: &Config,
+    repos: &[models::Repository],
+) -> Result<Option<ExternalStats>, String> {
+    let (source, bytes) = if let Some(path) = &config.external_stats_file {
+        let bytes = std::fs::read(path).map_err(|e| {
+            format!(
+                "EXTERNAL_STATS_FILE \"{}\" couldn't be read: {e}",
+                path.display()
+            )
+        })?;
+        (path.display().to_string(), bytes)
+    } else if config.external_stats_from_bucket {
+        let bytes = common::get_object(common::EXTERNAL_STATS_OBJ_NAME)
+            .await
+            .map_err(|e| format!("Failed to get {}: {e}", common::EXTERNAL_STATS_OBJ_NAME))?;
+        (common::EXTERNAL_STATS_OBJ_NAME.to_string(), bytes)
+    } else {
+        return Ok(None);
+    };
+
+    let mut external = serde_json::from_slice::<ExternalStats>(&bytes)
+        .map_err(|e| format!("{source} is invalid: {e}"))?;
+    validate(&external, repos).map_err(|e| format!("{source} is invalid: {e}"))?;
+    for repo in &mut external.per_repo {
+        repo.external = true;
+    }
+    println!(
+        "Merging {} languages and {} repos from {source}",
+        external.total.len(),
+        external.per_repo.len()
+    );
+
+    Ok(Some(external))
+}
+
+fn validate(external: &ExternalStats, repos: &[models::Repository]) -> Result<(), String> {
+    unique_languages(&external.total).map_err(|e| format!("total has {e}"))?;
+
+    let mut names = BTreeSet::new();
+    for repo in &external.per_repo {
+        if repo.name.trim().is_empty() {
+            return Err("a repo has no name".to_string());
+        }
+        if !names.insert(&repo.name) {
+            return Err(format!("\"{}\" is in per_repo twice", repo.name));
+        }
+        if repos.iter().any(|counted| counted.name == repo.name) {
+            return Err(format!(
+                "\"{}\" is also a counted repo, give it another name",
+                repo.name
+            ));
+        }
+        unique_languages(&repo.languages).map_err(|e| format!("\"{}\" has {e}", repo.name))?;
+    }
+
+    Ok(())
+}
+
+fn unique_languages(languages: &[SimpleLanguage]) -> Result<(), String> {
+    let mut seen = BTreeSet::new();
+    match languages.iter().find(|lang| !seen.insert(lang.name)) {
+        Some(lang) => Err(format!("{} twice", lang.name)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokei::LanguageType;
+
+    use super::*;
+
+    fn file(json: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(json.as_bytes()).unwrap();
+        file
+    }
+
+    async fn load_file(file: &tempfile::NamedTempFile) -> Result<Option<ExternalStats>, String> {
+        let config = Config {
+            external_stats_file: Some(file.path().to_path_buf()),
+            ..crate::config::tests::config()
+        };
+        load(&config, &[]).await
+    }
+
+    #[tokio::test]
+    async fn external_stats_are_merged_into_the_computed_ones() {
+        let external = file(
+            r#"{
+                "total": [{"name": "Java", "code": 4000, "files": 0}],
+                "per_repo": [{
+                    "name": "gitlab-project",
+                    "href": "https://gitlab.example/me/project",
+                    "description": null,
+                    "languages": [{"name": "Rust", "code": 50, "files": 2}, {"name": "Go", "code": 20, "files": 1}]
+                }]
+            }"#,
+        );
+        let external = load_file(&external).await.unwrap().unwrap();
+
+        let mut total = vec![SimpleLanguage {
+            code: 100,
+            ..SimpleLanguage::from_stats(&LanguageType::Rust, &Default::default())
+        }];
+        let mut per_repo = Vec::new();
+        external.merge(&mut total, |repo| per_repo.push(repo));
+
+        let total = total
+            .iter()
+            .map(|lang| (lang.name, lang.code, lang.files))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            total,
+            [
+                (LanguageType::Rust, 150, 3),
+                (LanguageType::Java, 4000, 0),
+                (LanguageType::Go, 20, 1)
+            ]
+        );
+        assert_eq!(per_repo.len(), 1);
+        assert_eq!(per_repo[0].name, "gitlab-project");
+        assert!(per_repo[0].external);
+    }
+
+    #[tokio::test]
+    async fn external_stats_have_to_fit_the_schema() {
+        for invalid in [
+            r#"{"totals": []}"#,
+            r#"{"total": [{"name": "NotALanguage", "code": 1}]}"#,
+            r#"{"total": [{"name": "Rust", "code": 1}, {"name": "Rust", "code": 2}]}"#,
+            r#"{"per_repo": [
+                {"name": "a", "href": "", "description": null, "languages": []},
+                {"name": "a", "href": "", "description": null, "languages": []}
+            ]}"#,
+        ] {
+            assert!(load_file(&file(invalid)).await.is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::Path;
+
+/// Used without `FRAMEWORK_SIGNATURES`. Checked in order, so the files which say the most about a repo come
+/// before the ones most repos have.
+pub const BUILT_IN_SIGNATURES: &[(&str, &str)] = &[
+    ("next.config.js", "nextjs"),
+    ("next.config.mjs", "nextjs"),
+    ("next.config.ts", "nextjs"),
+    ("nuxt.config.js", "nuxt"),
+    ("nuxt.config.ts", "nuxt"),
+    ("svelte.config.js", "sveltekit"),
+    ("astro.config.mjs", "astro"),
+    ("angular.json", "angular"),
+    ("vite.config.js", "vite"),
+    ("vite.config.ts", "vite"),
+    ("manage.py", "django"),
+    ("config/routes.rb", "rails"),
+    ("pubspec.yaml", "flutter"),
+    ("Cargo.toml", "rust"),
+    ("go.mod", "go"),
+    ("pom.xml", "maven"),
+    ("build.gradle", "gradle"),
+    ("build.gradle.kts", "gradle"),
+    ("pyproject.toml", "python"),
+    ("requirements.txt", "python"),
+    ("package.json", "node"),
+    ("Dockerfile", "containerized"),
+];
+
+/// The framework of the first signature, a path from the root of the repo, which the worktree has
+pub fn primary(repo_path: &Path, signatures: &[(String, String)]) -> Option<String> {
+    signatures
+        .iter()
+        .find(|(path, _)| repo_path.join(path).exists())
+        .map(|(_, framework)| framework.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn built_in() -> Vec<(String, String)> {
+        crate::config::tests::config().framework_signatures
+    }
+
+    fn worktree(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn signatures_match_in_order() {
+        let next = worktree(&["package.json", "next.config.js", "Dockerfile"]);
+        assert_eq!(primary(next.path(), &built_in()).as_deref(), Some("nextjs"));
+        let rust = worktree(&["Cargo.toml", "src/main.rs"]);
+        assert_eq!(primary(rust.path(), &built_in()).as_deref(), Some("rust"));
+        let rails = worktree(&["Gemfile", "config/routes.rb"]);
+        assert_eq!(primary(rails.path(), &built_in()).as_deref(), Some("rails"));
+    }
+
+    #[test]
+    fn lookalikes_dont_match() {
+        let repo = worktree(&["Cargo.toml.bak", "examples/Cargo.toml", "routes.rb"]);
+        assert_eq!(primary(repo.path(), &built_in()), None);
+    }
+
+    #[test]
+    fn configured_signatures_replace_the_built_in_ones() {
+        let repo = worktree(&["Cargo.toml", "tauri.conf.json"]);
+        let signatures = [("tauri.conf.json", "tauri")]
+            .map(|(path, framework)| (path.to_string(), framework.to_string()));
+        assert_eq!(primary(repo.path(), &signatures).as_deref(), Some("tauri"));
+        assert_eq!(primary(repo.path(), &signatures[..0]), None);
+    }
+}
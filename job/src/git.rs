@@ -0,0 +1,428 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+};
+
+use chrono::NaiveDate;
+use gix::{
+    bstr::{BString, ByteSlice},
+    object::tree::diff::Action,
+    progress,
+    traverse::commit::simple::Sorting,
+};
+use lambda_runtime::Error;
+use octocrab::models;
+use url::Url;
+
+use crate::config::Config;
+
+/// Commit checked out in the clone, in hex
+pub fn head_sha(repo_path: &Path) -> Result<String, Error> {
+    Ok(gix::open(repo_path)?.head_id()?.to_string())
+}
+
+pub fn clone_repo(
+    repo: &models::Repository,
+    repo_path: &str,
+    config: &Config,
+) -> Result<(), Error> {
+    let url = repo.clone_url.as_ref().ok_or("repo has no clone url")?;
+    let url = make_authenticated_url(url, config)?;
+    let gix_url = gix::Url::from_bytes(url.as_str().into())?;
+
+    // History is needed to know when files were last touched and, since a shallow fetch only brings the default
+    // branch, to see the other branches. The last commits also need their parents to be diffed.
+    let depth = match (
+        config.by_year
+            || config.dated_snapshot()
+            || config.all_branches
+            || config.backfill_as_of.is_some(),
+        config.recent_commits_only,
+    ) {
+        (true, _) => None,
+        (false, Some(commits)) => Some(commits + 1),
+        (false, None) => Some(1),
+    };
+    let mut cloned = checkout(&gix_url, repo_path, depth, repo, config)?;
+    if config.reclone_corrupt_checkouts {
+        if let Err(problem) = validate_checkout(&cloned, repo, Path::new(repo_path)) {
+            println!(
+                "Checkout of \"{}\" looks corrupt, cloning it again: {problem}",
+                config.repo_label(repo)
+            );
+            fs::remove_dir_all(repo_path).ok();
+            cloned = checkout(&gix_url, repo_path, depth, repo, config)?;
+            validate_checkout(&cloned, repo, Path::new(repo_path)).map_err(|problem| {
+                format!("checkout is still corrupt after cloning again: {problem}")
+            })?;
+        }
+    }
+    check_head_branch(&cloned, repo, &config.repo_label(repo));
+
+    Ok(())
+}
+
+/// Fetches and checks out the worktree, falling back to a full fetch with `SHALLOW_FALLBACK_FULL`
+fn checkout(
+    url: &gix::Url,
+    repo_path: &str,
+    depth: Option<usize>,
+    repo: &models::Repository,
+    config: &Config,
+) -> Result<gix::Repository, Error> {
+    let mut checkout = match fetch(url, repo_path, depth) {
+        Ok(checkout) => checkout,
+        // Brand new repos and unusual default branches can trip up shallow fetches
+        Err(e) if depth.is_some() && config.shallow_fallback_full => {
+            println!(
+                "Shallow clone of \"{}\" failed, retrying with a full clone: {e}",
+                config.repo_label(repo)
+            );
+            fs::remove_dir_all(repo_path).ok();
+            fetch(url, repo_path, None)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (cloned, _) = checkout.main_worktree(progress::Discard, &AtomicBool::new(false))?;
+    Ok(cloned)
+}
+
+/// Catches checkouts left half done by an interrupted fetch or a disk hiccup: HEAD has to resolve to a commit,
+/// unless GitHub says the repo is empty, and the worktree can't be empty when that commit has files
+fn validate_checkout(
+    cloned: &gix::Repository,
+    repo: &models::Repository,
+    repo_path: &Path,
+) -> Result<(), String> {
+    let head = cloned
+        .head()
+        .map_err(|e| format!("HEAD can't be read: {e}"))?;
+    if head.is_unborn() {
+        return match repo.size {
+            Some(size) if size > 0 => Err(format!(
+                "HEAD has no commit, but GitHub says the repo has {size}KB"
+            )),
+            _ => Ok(()),
+        };
+    }
+
+    let tree = cloned
+        .head_commit()
+        .map_err(|e| format!("HEAD doesn't resolve to a commit: {e}"))?
+        .tree()
+        .map_err(|e| format!("the tree of HEAD can't be read: {e}"))?;
+    if tree.iter().next().is_none() {
+        return Ok(());
+    }
+
+    let checked_out = fs::read_dir(repo_path)
+        .map_err(|e| format!("the worktree can't be read: {e}"))?
+        .filter_map(Result::ok)
+        .any(|entry| entry.file_name() != ".git");
+    if checked_out {
+        Ok(())
+    } else {
+        Err("HEAD has files, but the worktree is empty".to_string())
+    }
+}
+
+/// Fetches into a new repo at `repo_path`, only the last `depth` commits when given
+fn fetch(
+    url: &gix::Url,
+    repo_path: &str,
+    depth: Option<usize>,
+) -> Result<gix::clone::PrepareCheckout, Error> {
+    let mut prepare = gix::prepare_clone(url.clone(), repo_path)?;
+    if let Some(depth) = depth {
+        prepare = prepare.with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(
+            u32::try_from(depth).unwrap_or(u32::MAX).try_into().unwrap(),
+        ));
+    }
+    let (checkout, _) = prepare.fetch_then_checkout(progress::Discard, &AtomicBool::new(false))?;
+
+    Ok(checkout)
+}
+
+/// The only place the token is put into a url, so it's easy to check where it can end up
+pub fn make_authenticated_url(base: &Url, config: &Config) -> Result<Url, Error> {
+    let mut url = base.clone();
+    // Repos from a static list can be local paths, which have nowhere to put credentials
+    if url.scheme().starts_with("http") {
+        url.set_username("tsar-boomba")
+            .map_err(|_| "couldn't set clone url username")?;
+        url.set_password(Some(&config.personal_access_token))
+            .map_err(|_| "couldn't set clone url password")?;
+    }
+
+    Ok(url)
+}
+
+/// Warns when the checked out branch isn't the default one, since that's what people expect to be counted
+fn check_head_branch(cloned: &gix::Repository, repo: &models::Repository, label: &str) {
+    let Some(expected) = &repo.default_branch else {
+        return;
+    };
+
+    match cloned.head_name() {
+        Ok(Some(head)) if head.shorten() == expected.as_str() => {}
+        Ok(Some(head)) => eprintln!(
+            "\"{}\" checked out \"{}\" instead of the default branch \"{expected}\"",
+            label,
+            head.shorten()
+        ),
+        Ok(None) => eprintln!(
+            "\"{}\" has a detached HEAD instead of the default branch \"{expected}\"",
+            label
+        ),
+        Err(e) => eprintln!("Couldn't read HEAD of \"{}\": {e}", label),
+    }
+}
+
+/// Replaces the worktree with the tree of the newest commit on HEAD's first-parent history made by the end of `date`
+/// (UTC), so the repo is counted as it was that day. Left empty when the repo has no commit that old. Only regular
+/// files are written, like a tarball is extracted.
+pub fn checkout_as_of(repo_path: &Path, date: NaiveDate) -> Result<(), Error> {
+    let repo = gix::open(repo_path)?;
+    let head = repo.head_commit()?;
+    let cutoff = date
+        .and_hms_opt(23, 59, 59)
+        .expect("a valid time")
+        .and_utc()
+        .timestamp();
+
+    let mut as_of = None;
+    let walk = repo
+        .rev_walk([head.id])
+        .sorting(Sorting::ByCommitTimeNewestFirst)
+        .first_parent_only()
+        .all()?;
+    for info in walk {
+        let commit = info?.object()?;
+        if commit.time()?.seconds <= cutoff {
+            as_of = Some(commit);
+            break;
+        }
+    }
+
+    for entry in fs::read_dir(repo_path)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    let Some(commit) = as_of else {
+        return Ok(());
+    };
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    commit.tree()?.traverse().breadthfirst(&mut recorder)?;
+    for entry in recorder.records {
+        if !entry.mode.is_blob() {
+            continue;
+        }
+        let path = repo_path.join(entry.filepath.to_path_lossy());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, &repo.find_object(entry.oid)?.data)?;
+    }
+
+    Ok(())
+}
+
+/// Commit time (seconds since the epoch) of the most recent commit touching each file at HEAD,
+/// keyed by the path relative to the repo root.
+///
+/// Walks the history newest first, diffing every commit against its first parent, so the cost grows with
+/// the length of the history. Files whose last change is beyond a shallow boundary are missing from the result.
+pub fn last_touched(repo_path: &Path) -> Result<BTreeMap<PathBuf, i64>, Error> {
+    let repo = gix::open(repo_path)?;
+    let head = repo.head_commit()?;
+
+    let mut recorder = gix::traverse::tree::Recorder::default();
+    head.tree()?.traverse().breadthfirst(&mut recorder)?;
+    let mut remaining = recorder
+        .records
+        .into_iter()
+        .filter(|entry| entry.mode.is_no_tree())
+        .map(|entry| entry.filepath)
+        .collect::<BTreeSet<BString>>();
+
+    let mut touched = BTreeMap::new();
+    let walk = repo
+        .rev_walk([head.id])
+        .sorting(Sorting::ByCommitTimeNewestFirst)
+        .all()?;
+
+    for info in walk {
+        let info = info?;
+        let commit = info.object()?;
+        let time = commit.time()?.seconds;
+        let tree = commit.tree()?;
+        let parent_tree = match info.parent_ids().next() {
+            Some(parent) => parent.object()?.into_commit().tree()?,
+            None => repo.empty_tree(),
+        };
+
+        parent_tree
+            .changes()?
+            .track_path()
+            .track_rewrites(None)
+            .for_each_to_obtain_tree(&tree, |change| {
+                if remaining.remove(change.location) {
+                    touched.insert(change.location.to_path_lossy().into_owned(), time);
+                }
+                Ok::<_, Infallible>(Action::Continue)
+            })?;
+
+        if remaining.is_empty() {
+            break;
+        }
+    }
+
+    Ok(touched)
+}
+
+/// Paths, relative to the repo root, of the files changed by the last `commits` commits which still exist at HEAD.
+/// `None` when the history is too shallow to diff all of those commits against their parents.
+pub fn changed_in_last_commits(
+    repo_path: &Path,
+    commits: usize,
+) -> Result<Option<BTreeSet<PathBuf>>, Error> {
+    let repo = gix::open(repo_path)?;
+    let head = repo.head_commit()?;
+    let shallow = repo.shallow_commits()?;
+
+    let mut changed = BTreeSet::new();
+    let walk = repo
+        .rev_walk([head.id])
+        .sorting(Sorting::ByCommitTimeNewestFirst)
+        .all()?;
+
+    for info in walk.take(commits) {
+        let info = info?;
+        let parent_tree = match info.parent_ids().next() {
+            Some(parent) => parent.object()?.into_commit().tree()?,
+            // The parents were cut off by the shallow clone, so the commit's changes can't be known
+            None if shallow
+                .as_ref()
+                .is_some_and(|shallow| shallow.contains(&info.id)) =>
+            {
+                return Ok(None);
+            }
+            None => repo.empty_tree(),
+        };
+
+        parent_tree
+            .changes()?
+            .track_path()
+            .track_rewrites(None)
+            .for_each_to_obtain_tree(&info.object()?.tree()?, |change| {
+                changed.insert(change.location.to_path_lossy().into_owned());
+                Ok::<_, Infallible>(Action::Continue)
+            })?;
+    }
+
+    changed.retain(|path| repo_path.join(path).is_file());
+    Ok(Some(changed))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    /// A repo made with the git CLI, committing each of `commits` (commit date, files to write) in order
+    pub fn fixture(commits: &[(&str, &[(&str, &str)])]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str], date: &str| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(dir.path())
+                .env("GIT_AUTHOR_DATE", date)
+                .env("GIT_COMMITTER_DATE", date)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+
+        git(&["init", "-q", "-b", "main"], "");
+        for (date, files) in commits {
+            for (path, contents) in *files {
+                let path = dir.path().join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            git(&["add", "-A"], date);
+            git(&["commit", "-q", "-m", date], date);
+        }
+        dir
+    }
+
+    fn worktree(path: &Path) -> Vec<(String, String)> {
+        let mut files = ignore::WalkBuilder::new(path)
+            .hidden(false)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .build()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_some_and(|ty| ty.is_file()))
+            .map(|entry| {
+                (
+                    entry
+                        .path()
+                        .strip_prefix(path)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    fs::read_to_string(entry.path()).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn checks_out_the_last_commit_of_the_day() {
+        let repo = fixture(&[
+            ("2020-01-01T12:00:00Z", &[("a.rs", "old")]),
+            (
+                "2020-06-01T23:00:00Z",
+                &[("a.rs", "new"), ("src/b.rs", "b")],
+            ),
+            ("2021-01-01T00:00:00Z", &[("c.rs", "c")]),
+        ]);
+
+        checkout_as_of(repo.path(), date("2020-05-31")).unwrap();
+        assert_eq!(worktree(repo.path()), [("a.rs".into(), "old".into())]);
+
+        // The commit late on the day still counts
+        checkout_as_of(repo.path(), date("2020-06-01")).unwrap();
+        assert_eq!(
+            worktree(repo.path()),
+            [
+                ("a.rs".into(), "new".into()),
+                ("src/b.rs".into(), "b".into())
+            ]
+        );
+
+        checkout_as_of(repo.path(), date("2019-12-31")).unwrap();
+        assert!(worktree(repo.path()).is_empty());
+        assert!(repo.path().join(".git").is_dir());
+    }
+}
@@ -0,0 +1,48 @@
+use chrono::Utc;
+use common::stats::{HistoryPoint, Total};
+
+/// `HISTORY_SNAPSHOTS`, keeps this run's total under `history/` and adds it to the index `GET /history` serves.
+/// The snapshot is saved either way, a broken index only means this run is left out of it.
+pub async fn record(total: &Total, total_json: &str) -> Result<(), common::Error> {
+    let at = Utc::now();
+    let key = common::history_key(at);
+    common::save_object(&key, total_json).await?;
+
+    let mut index = match common::get_object(common::HISTORY_INDEX_OBJ_NAME).await {
+        Ok(bytes) => match serde_json::from_slice::<Vec<HistoryPoint>>(&bytes) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!(
+                    "{} is invalid, not adding {key} to it: {e}",
+                    common::HISTORY_INDEX_OBJ_NAME
+                );
+                return Ok(());
+            }
+        },
+        Err(e) if e.is_not_found() => Vec::new(),
+        Err(e) => {
+            eprintln!(
+                "Failed to get {}, not adding {key} to it: {e}",
+                common::HISTORY_INDEX_OBJ_NAME
+            );
+            return Ok(());
+        }
+    };
+    index.push(HistoryPoint {
+        at,
+        code: total
+            .languages
+            .iter()
+            .map(|lang| (lang.name, lang.code))
+            .collect(),
+        other: total.other.as_ref().map_or(0, |other| other.code),
+    });
+    common::save_object(
+        common::HISTORY_INDEX_OBJ_NAME,
+        &serde_json::to_string(&index).unwrap(),
+    )
+    .await?;
+    println!("Saved {key}, the history has {} snapshots", index.len());
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use common::stats::SimpleLanguage;
+use futures_util::{stream, StreamExt};
+use octocrab::{models, Octocrab};
+use tokei::LanguageType;
+
+use crate::{config::Config, repo_cache::Analysis, source::with_retries};
+
+/// Repos asked about at once
+const CONCURRENT_REQUESTS: usize = 8;
+
+/// GitHub's names which tokei calls something else
+const LINGUIST_NAMES: &[(&str, LanguageType)] = &[
+    ("SCSS", LanguageType::Sass),
+    ("Jupyter Notebook", LanguageType::Jupyter),
+];
+
+/// `USE_GITHUB_LANGUAGES_API`, what GitHub's own language detection found in each repo instead of counting a
+/// clone. The counts are bytes, so each language's `code` is its bytes and the rest of its counts are 0. Only
+/// the languages in `types` are kept, like tokei would.
+pub async fn analyze_all(
+    github: &Octocrab,
+    repos: Vec<models::Repository>,
+    types: &[LanguageType],
+    config: &Config,
+) -> Vec<(models::Repository, Result<Analysis, String>)> {
+    stream::iter(repos)
+        .map(|repo| async move {
+            let analysis = analyze(github, &repo, types, config).await;
+            (repo, analysis)
+        })
+        .buffer_unordered(CONCURRENT_REQUESTS)
+        .collect()
+        .await
+}
+
+async fn analyze(
+    github: &Octocrab,
+    repo: &models::Repository,
+    types: &[LanguageType],
+    config: &Config,
+) -> Result<Analysis, String> {
+    let Some(owner) = repo.owner.as_ref().map(|owner| &owner.login) else {
+        return Err("repo has no owner to ask GitHub about".to_string());
+    };
+    let bytes = with_retries(config, "get the languages of a repo", || {
+        github.get::<BTreeMap<String, usize>, _, ()>(
+            format!("/repos/{owner}/{}/languages", repo.name),
+            None,
+        )
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut languages = Vec::<SimpleLanguage>::new();
+    for (name, bytes) in bytes {
+        let Some(ty) = language_type(&name) else {
+            println!(
+                "Leaving {name} out of \"{}\", tokei doesn't know it",
+                config.repo_label(repo)
+            );
+            continue;
+        };
+        if !types.contains(&ty) {
+            continue;
+        }
+
+        // Several of GitHub's languages can be the same one to tokei
+        match languages.iter_mut().find(|lang| lang.name == ty) {
+            Some(lang) => lang.code += bytes,
+            None => languages.push(SimpleLanguage {
+                code: bytes,
+                files: 0,
+                ..SimpleLanguage::from_stats(&ty, &Default::default())
+            }),
+        }
+    }
+
+    Ok(Analysis {
+        languages,
+        ..Default::default()
+    })
+}
+
+fn language_type(name: &str) -> Option<LanguageType> {
+    LINGUIST_NAMES
+        .iter()
+        .find(|(linguist, _)| *linguist == name)
+        .map(|&(_, ty)| ty)
+        .or_else(|| name.parse().ok())
+}
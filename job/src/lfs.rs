@@ -0,0 +1,44 @@
+use std::{fs, path::Path};
+
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+
+/// Patterns in the repo's root `.gitattributes` which are tracked by git-lfs.
+/// Shallow clones only contain the pointer files for these, not the real content.
+pub fn tracked_patterns(repo_path: &Path) -> Vec<String> {
+    let Ok(attributes) = fs::read_to_string(repo_path.join(".gitattributes")) else {
+        return Vec::new();
+    };
+
+    attributes
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let pattern = parts.next()?;
+            parts
+                .any(|attr| attr == "filter=lfs")
+                .then(|| pattern.to_string())
+        })
+        .collect()
+}
+
+/// Number of files in the worktree matching any of the LFS patterns
+pub fn count_tracked_files(repo_path: &Path, patterns: &[String]) -> usize {
+    let mut overrides = OverrideBuilder::new(repo_path);
+    for pattern in patterns {
+        if let Err(e) = overrides.add(pattern) {
+            println!("Ignoring invalid LFS pattern \"{pattern}\": {e}");
+        }
+    }
+    let Ok(overrides) = overrides.build() else {
+        return 0;
+    };
+
+    WalkBuilder::new(repo_path)
+        .overrides(overrides)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|ty| ty.is_file()))
+        .count()
+}
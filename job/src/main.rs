@@ -1,342 +1,1694 @@
+mod backfill;
+mod binary_dirs;
+mod branches;
+mod cap;
+mod changes;
+mod concurrency;
+mod config;
+mod contributors;
+mod empty;
+mod encoding;
+mod excludes;
+mod extensions;
+mod external;
+mod frameworks;
+mod git;
+mod history;
+mod languages_api;
+mod lfs;
+mod monorepo;
+mod post;
+mod pr_diff;
+mod processors;
+mod progress;
+mod readme;
+mod repo_cache;
+mod source;
+mod streaming;
+mod tarball;
+mod webhook;
+
 use std::{
+    cmp::Reverse,
     collections::BTreeMap,
     fs,
-    ops::AddAssign,
-    sync::{atomic::AtomicBool, Arc, Mutex},
-    time::Instant,
+    path::{Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use gix::progress;
-use lambda_runtime::{tracing, Error, LambdaEvent};
-use octocrab::models;
+use chrono::{DateTime, Datelike, Utc};
+use common::stats::{
+    total_code, CodeUnit, DatedTotal, EmptyRepo, Meta, PerRepo, Phase, RepoError, SimpleLanguage,
+    Summary, TestVsSource, Total, SCHEMA_VERSION,
+};
+use lambda_runtime::{tracing, Error};
+use octocrab::{models, Octocrab};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde::Serialize;
-use tokei::{Language, LanguageType};
-
-const SEPARATOR: &str = "=================================";
+use tokei::LanguageType;
 
-#[derive(Debug, Serialize, Clone, Copy)]
-struct SimpleLanguage {
-    name: LanguageType,
-    code: usize,
-    blanks: usize,
-    comments: usize,
-}
+use crate::{
+    config::{BuiltInProcessor, Config, DownloadMethod, LfsHandling},
+    post::{
+        add_language, apply_min_total_percent, by_domain, finalize, fold_other_languages,
+        freshness, headline_total, matrix, prettify_name, primary_language,
+    },
+    progress::Progress,
+    repo_cache::{Analysis, RepoCache},
+    streaming::{Saved, Spill},
+    tarball::Downloader,
+};
 
-impl SimpleLanguage {
-    fn from_lang(ty: &LanguageType, lang: &Language) -> Self {
-        Self {
-            name: ty.clone(),
-            code: lang.code,
-            blanks: lang.blanks,
-            comments: lang.comments,
-        }
-    }
-}
+const SEPARATOR: &str = "=================================";
+/// Left out of every repo, along with the `EXCLUDE_PATHS`
+const DEFAULT_IGNORED: &[&str] = &["build", "package-lock.json", "pnpm-lock.yaml"];
+/// Counted without `LANGUAGES`
+pub const DEFAULT_LANGUAGES: &[LanguageType] = &[
+    LanguageType::Rust,
+    LanguageType::C,
+    LanguageType::Cpp,
+    LanguageType::JavaScript,
+    LanguageType::TypeScript,
+    LanguageType::Css,
+    LanguageType::Html,
+    LanguageType::Python,
+    LanguageType::Java,
+    LanguageType::Sh,
+    LanguageType::Tsx,
+    LanguageType::Jsx,
+    LanguageType::Toml,
+    LanguageType::Markdown,
+    LanguageType::Svelte,
+    LanguageType::Vue,
+    LanguageType::Sass,
+    LanguageType::CMake,
+    LanguageType::CppHeader,
+    LanguageType::Zig,
+    LanguageType::Go,
+    LanguageType::Dockerfile,
+    LanguageType::Yaml,
+    LanguageType::Json,
+];
 
-impl AddAssign<&SimpleLanguage> for SimpleLanguage {
-    fn add_assign(&mut self, rhs: &SimpleLanguage) {
-        self.code += rhs.code;
-        self.comments += rhs.comments;
-        self.blanks += rhs.blanks;
-    }
+#[derive(Debug)]
+struct Failure {
+    error: RepoError,
+    /// Can go in `errors.json`, the same rule as for `empty-repos.json`
+    shareable: bool,
 }
 
-#[derive(Debug, Serialize)]
-struct PerRepo {
-    name: String,
-    href: String,
-    description: Option<String>,
-    languages: Vec<SimpleLanguage>,
+fn main() -> Result<(), Error> {
+    // Before the runtime has any threads, changing the environment isn't safe once it does
+    dotenvy::dotenv().ok();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(start())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn start() -> Result<(), Error> {
     // required to enable CloudWatch error logging by the runtime
     tracing::init_default_subscriber();
-    dotenvy::dotenv().ok();
+    let mut config = Config::from_env()?;
+    if config.excludes_from_bucket {
+        excludes::load_from_bucket(&mut config).await?;
+    }
 
-    octocrab::initialise(
-        octocrab::Octocrab::builder()
-            .personal_token(std::env::var("PERSONAL_ACCESS_TOKEN").unwrap())
-            .build()
-            .unwrap(),
+    // Shared explicitly instead of through octocrab's global, `Octocrab` is `Send + Sync` so it can be
+    // cloned into the rayon threads by anything processing repos that needs the API
+    let github = Arc::new(
+        Octocrab::builder()
+            .personal_token(config.personal_access_token.clone())
+            .build()?,
     );
 
+    // Outside of lambda, keep the stats fresh from a single long-lived process
+    if let Some(secs) = config.run_interval_secs {
+        if std::env::var_os("AWS_LAMBDA_RUNTIME_API").is_none() {
+            run_on_interval(&config, &github, Duration::from_secs(secs)).await;
+        }
+    }
+
     // In prod, setup all the stuff for handling lambda
     #[cfg(not(debug_assertions))]
     {
-        let func = lambda_runtime::service_fn(my_handler);
+        let func = lambda_runtime::service_fn(|event| my_handler(event, &config, &github));
         lambda_runtime::run(func).await?;
     }
 
     // In dev, just run the stuff normally
     #[cfg(debug_assertions)]
-    run().await?;
+    handle(&config, &github, None).await?;
 
     Ok(())
 }
 
-async fn run() -> Result<(), Error> {
+async fn run(config: &Config, github: &Arc<Octocrab>) -> Result<(), Error> {
     let start_time = Instant::now();
-    let octocrab = octocrab::instance();
-    let exclude_repos_string = std::env::var("EXCLUDE_REPOS").unwrap_or_default();
-    let exclude_repos = exclude_repos_string.split(",").collect::<Vec<_>>();
-
-    let mut page = octocrab
-        .current()
-        .list_repos_for_authenticated_user()
-        .affiliation("owner")
-        .direction("desc")
-        .sort("updated")
-        .send()
-        .await?;
+    let (repos, listed) = repos_to_process(config, github).await?;
+    // Before any cloning, so bad external stats fail the run straight away
+    let external = external::load(config, &repos).await?;
 
-    let mut repos =
-        Vec::with_capacity(page.items.len() * page.number_of_pages().unwrap_or(1) as usize);
+    fs::remove_dir_all("/tmp/repo").ok();
+    fs::create_dir("/tmp/repo").unwrap();
 
-    loop {
-        for repo in &page {
-            if !repo.fork.is_some_and(|f| f) {
-                repos.push(repo.clone());
+    // Shared with the threads `ANALYZE_TIMEOUT_SECS` runs tokei on
+    let tokei_config = Arc::new(tokei_config(config));
+    let collected = Collected {
+        spill: config.streaming_save.then(Spill::create).transpose()?,
+        ..Default::default()
+    };
+    let deadline = config
+        .max_runtime_secs
+        .map(|secs| start_time + Duration::from_secs(secs));
+
+    let repos_len = repos.len();
+    if config.use_github_languages_api {
+        let types = tokei_config.types.as_deref().unwrap_or_default();
+        for (repo, analysis) in languages_api::analyze_all(github, repos, types, config).await {
+            match analysis {
+                Ok(analysis) => collect(repo, analysis, config, &collected),
+                Err(e) => {
+                    let label = config.repo_label(&repo);
+                    eprintln!("Failed to get the languages of \"{label}\": {e}");
+                    collected.failures.lock().unwrap().push(Failure {
+                        error: RepoError {
+                            repo: label,
+                            phase: Phase::Analyze,
+                            message: e,
+                        },
+                        shareable: shareable(&repo, config),
+                    });
+                }
             }
         }
+    } else {
+        let cache = RepoCache::load(config, &repos).await;
+
+        let progress = config.log_progress.then(|| Progress::new(repos_len));
+        let downloader = Downloader::new(github);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(concurrency::repo_threads(config))
+            .build()?;
+
+        // Rayon is actually amazing. Really shows the strengths of Rust
+        // `block_in_place` so the runtime can keep going for the tarball downloads while this thread waits
+        tokio::task::block_in_place(|| {
+            pool.install(|| {
+                repos.into_par_iter().for_each(|repo| {
+                    if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                        println!(
+                            "Past MAX_RUNTIME_SECS, skipping \"{}\"",
+                            config.repo_label(&repo)
+                        );
+                        collected
+                            .skipped_for_deadline
+                            .fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        process_repo(repo, config, &tokei_config, &cache, &collected, &downloader);
+                    }
+
+                    if let Some(progress) = &progress {
+                        progress.complete_one();
+                    }
+                })
+            })
+        });
+        cache.save(config).await;
+    }
 
-        page = match octocrab.get_page::<models::Repository>(&page.next).await? {
-            Some(next_page) => next_page,
-            None => break,
+    println!(
+        "{SEPARATOR}\n\nFinished all in {:.2} seconds!!!",
+        (Instant::now() - start_time).as_secs_f64()
+    );
+
+    if let Some(external) = external {
+        external.merge(&mut collected.total.lock().unwrap(), |repo| {
+            collected.add_per_repo(repo)
+        });
+    }
+
+    let Collected {
+        total,
+        per_repo: per_repo_stats,
+        failures,
+        by_year,
+        dated_total,
+        test_vs_source,
+        skipped_for_deadline,
+        empty_repos,
+        spill,
+    } = collected;
+    let skipped_for_deadline = skipped_for_deadline.into_inner();
+    if skipped_for_deadline > 0 {
+        println!("Ran out of time, {skipped_for_deadline} repos were skipped and a partial snapshot will be saved");
+    }
+
+    // Repos skipped for the deadline were never attempted
+    let repo_count = repos_len - skipped_for_deadline;
+    let failures = failures.into_inner().unwrap();
+    if !failures.is_empty() {
+        for Failure { error, .. } in &failures {
+            eprintln!(
+                "\"{}\" failed during {:?}: {}",
+                error.repo, error.phase, error.message
+            );
+        }
+
+        let max_failure_percent = config.max_failure_percent;
+        let failure_percent = failure_percent(failures.len(), repo_count);
+        println!(
+            "{} of {repo_count} repos failed ({failure_percent:.1}%)",
+            failures.len()
+        );
+
+        if failure_percent > max_failure_percent {
+            return Err(format!(
+                "Too many repos failed ({failure_percent:.1}% > {max_failure_percent}%), not saving stats"
+            )
+            .into());
         }
     }
 
-    fs::remove_dir_all("/tmp/repo").ok();
-    fs::create_dir("/tmp/repo").unwrap();
+    println!("Starting post-processing!");
+    let post_start = Instant::now();
+    let mut total = total.into_inner().unwrap();
+    let mut per_repo_stats = per_repo_stats.into_inner().unwrap();
 
-    let config = tokei::Config {
-        types: Some(vec![
-            LanguageType::Rust,
-            LanguageType::C,
-            LanguageType::Cpp,
-            LanguageType::JavaScript,
-            LanguageType::TypeScript,
-            LanguageType::Css,
-            LanguageType::Html,
-            LanguageType::Python,
-            LanguageType::Java,
-            LanguageType::Sh,
-            LanguageType::Tsx,
-            LanguageType::Jsx,
-            LanguageType::Toml,
-            LanguageType::Markdown,
-            LanguageType::Svelte,
-            LanguageType::Vue,
-            LanguageType::Sass,
-            LanguageType::CMake,
-            LanguageType::CppHeader,
-            LanguageType::Zig,
-            LanguageType::Go,
-            LanguageType::Dockerfile,
-            LanguageType::Yaml,
-            LanguageType::Json,
-        ]),
-        ..Default::default()
+    let processors = processors::built_in(config);
+    processors::run(&processors, &mut total, &mut per_repo_stats);
+    // Outside of what the processors are given, so they're kept in line with the total here
+    let combine_ts_tsx = |languages: &mut Vec<SimpleLanguage>| {
+        if config
+            .post_processors
+            .contains(&BuiltInProcessor::CombineTsTsx)
+        {
+            post::combine_ts_tsx(languages, config);
+        }
     };
-    let total = Arc::new(Mutex::new(Vec::<SimpleLanguage>::with_capacity(
-        config.types.as_ref().unwrap().len(),
-    )));
-    let per_repo_stats = Arc::new(Mutex::new(Vec::<PerRepo>::with_capacity(repos.len())));
 
-    // Process largest repos first
-    repos.sort_unstable_by(|a, b| {
-        b.size
-            .clone()
-            .unwrap_or_default()
-            .cmp(&a.size.clone().unwrap_or_default())
+    let mut docs = Vec::new();
+    if config.separate_docs {
+        (docs, total) = total
+            .into_iter()
+            .partition(|lang| config.docs_languages.contains(&lang.name));
+        // tokei counts most of the lines in docs as comments
+        docs.sort_unstable_by_key(|lang| (Reverse(lang.lines()), lang.name));
+    }
+    exclude_from_total(&mut total, config);
+
+    for repo in &mut per_repo_stats {
+        post_process_repo(repo, config);
+    }
+    sort_per_repo(&mut per_repo_stats);
+
+    total.sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+
+    let mut by_year = by_year.into_inner().unwrap();
+    for languages in by_year.values_mut() {
+        combine_ts_tsx(languages);
+        languages.sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+    }
+
+    let mut dated_total = dated_total.into_inner().unwrap();
+    combine_ts_tsx(&mut dated_total);
+    exclude_from_total(&mut dated_total, config);
+    dated_total.sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+
+    let mut test_vs_source = test_vs_source.into_inner().unwrap();
+    for languages in [&mut test_vs_source.test, &mut test_vs_source.source] {
+        combine_ts_tsx(languages);
+        languages.sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+    }
+
+    finalize(&mut total, config);
+    let other = apply_min_total_percent(
+        &mut total,
+        config.min_total_percent,
+        config.min_total_percent_mode,
+    );
+    let total = Total {
+        languages: total,
+        other,
+    };
+    finalize(&mut docs, config);
+    finalize(&mut dated_total, config);
+    for languages in by_year.values_mut() {
+        finalize(languages, config);
+    }
+    finalize(&mut test_vs_source.test, config);
+    finalize(&mut test_vs_source.source, config);
+
+    println!(
+        "Post-processing complete in {:.2} seconds",
+        (Instant::now() - post_start).as_secs_f64()
+    );
+
+    let total_json = to_json(&total, config);
+    // Read before it's overwritten by this run
+    let previous_per_repo = changes::previous_per_repo().await;
+    let max_per_repo_entries = config.max_per_repo_entries.unwrap_or(usize::MAX);
+    let process = |repo: &mut PerRepo| {
+        processors::run(&processors, &mut Vec::new(), slice::from_mut(repo));
+        post_process_repo(repo, config);
+        ranked_code(repo)
+    };
+    // Kept on disk for the rest of the stats when it was streamed out as it was post-processed
+    let streamed = match spill {
+        // Laid out like `to_json` would lay out the whole array
+        Some(spill) if config.combine_non_code => Some(
+            spill
+                .save(max_per_repo_entries, config.pretty_json, process, |repo| {
+                    let mut value = serde_json::to_value(repo).unwrap();
+                    combine_non_code(&mut value);
+                    value
+                })
+                .await?,
+        ),
+        Some(spill) => Some(
+            spill
+                .save(max_per_repo_entries, config.pretty_json, process, |repo| {
+                    repo
+                })
+                .await?,
+        ),
+        None => None,
+    };
+    let per_repo_json = streamed.is_none().then(|| {
+        to_json(
+            &&per_repo_stats[..max_per_repo_entries.min(per_repo_stats.len())],
+            config,
+        )
     });
+    // Already by most code, the rest are still in everything else
+    let repos_kept = streamed.as_ref().map_or(per_repo_stats.len(), Saved::len);
+    let per_repo_omitted = repos_kept - max_per_repo_entries.min(repos_kept);
+    if per_repo_omitted > 0 {
+        println!("Leaving the {per_repo_omitted} repos past MAX_PER_REPO_ENTRIES out of the per-repo stats");
+    }
+    match &per_repo_json {
+        Some(per_repo_json) => common::save_stats(&total_json, per_repo_json).await?,
+        None => common::save_object(common::TOTAL_STATS_OBJ_NAME, &total_json).await?,
+    }
 
-    // Rayon is actually amazing. Really shows the strengths of Rust
-    repos.into_par_iter().for_each({
-        let total = total.clone();
-        let per_repo_stats = per_repo_stats.clone();
-        move |repo| {
-            let clone_start = Instant::now();
-            let repo_path = format!("/tmp/repo/{}", repo.name);
-            println!(
-                "Cloning: \"{}\"; Size: {}",
-                repo.name,
-                repo.size
-                    .map(|n| human_bytes::human_bytes(n * 1000))
-                    .unwrap_or_default()
-            );
-            let mut url = repo.clone_url.unwrap();
-            url.set_username("tsar-boomba").unwrap();
-            url.set_password(Some(&std::env::var("PERSONAL_ACCESS_TOKEN").unwrap()))
-                .unwrap();
+    if let Some(previous_per_repo) = &previous_per_repo {
+        common::save_object(
+            common::CHANGES_OBJ_NAME,
+            &to_json(
+                &match &streamed {
+                    Some(saved) => saved.with_repos(|repos| {
+                        changes::diff(previous_per_repo, repos.take(max_per_repo_entries))
+                    })?,
+                    None => changes::diff(
+                        previous_per_repo,
+                        per_repo_stats.iter().take(max_per_repo_entries),
+                    ),
+                },
+                config,
+            ),
+        )
+        .await?;
+    }
+
+    webhook::send_totals(config, &total_json).await;
 
-            let gix_url = gix::Url::from_bytes(url.as_str().try_into().unwrap()).unwrap();
+    if config.archive_snapshots {
+        let today = Utc::now().date_naive();
+        match &per_repo_json {
+            Some(per_repo_json) => common::archive_stats(today, &total_json, per_repo_json).await?,
+            None => {
+                common::save_object(
+                    &common::archive_key(today, common::TOTAL_STATS_OBJ_NAME),
+                    &total_json,
+                )
+                .await?;
+                common::copy_object(
+                    common::PER_REPO_OBJ_NAME,
+                    &common::archive_key(today, common::PER_REPO_OBJ_NAME),
+                )
+                .await?;
+            }
+        }
+        println!("Archived stats for {today}");
+    }
 
-            let (mut checkout, _) = gix::prepare_clone(gix_url, &repo_path)
-                .unwrap()
-                .with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(
-                    1.try_into().unwrap(),
-                ))
-                .fetch_then_checkout(progress::Discard, &AtomicBool::new(false))
-                .unwrap();
+    if config.history_snapshots {
+        history::record(&total, &total_json).await?;
+    }
+
+    let generated_at = Utc::now();
+    let meta = Meta {
+        generated_at,
+        partial: skipped_for_deadline > 0,
+        skipped_for_deadline,
+        per_repo_truncated: per_repo_omitted > 0,
+        per_repo_omitted,
+        schema_version: SCHEMA_VERSION,
+        headline_total: headline_total(&total.languages, config),
+        unit: if config.use_github_languages_api {
+            CodeUnit::Bytes
+        } else {
+            CodeUnit::Lines
+        },
+    };
+    common::save_object(common::META_OBJ_NAME, &to_json(&meta, config)).await?;
+
+    let summary = Summary {
+        generated_at,
+        total_code: total.code(),
+        repos_analyzed: repo_count - failures.len(),
+        repos_skipped: listed - repo_count,
+        repos_failed: failures.len(),
+        top_languages: total.languages.iter().take(3).cloned().collect(),
+        other: total.other.clone(),
+        duration_secs: (Instant::now() - start_time).as_secs_f64(),
+    };
+    common::save_object(common::SUMMARY_OBJ_NAME, &to_json(&summary, config)).await?;
+
+    common::save_object(
+        common::TEST_VS_SOURCE_OBJ_NAME,
+        &to_json(&test_vs_source, config),
+    )
+    .await?;
 
-            checkout
-                .main_worktree(progress::Discard, &AtomicBool::new(false))
-                .unwrap();
+    let errors = failures
+        .into_iter()
+        .filter(|failure| failure.shareable)
+        .map(|Failure { mut error, .. }| {
+            // Errors from cloning could include the authenticated url
+            error.message = error.message.replace(&config.personal_access_token, "***");
+            error
+        })
+        .collect::<Vec<_>>();
+    common::save_object(common::ERRORS_OBJ_NAME, &to_json(&errors, config)).await?;
 
+    let mut empty_repos = empty_repos.into_inner().unwrap();
+    empty_repos.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    common::save_object(common::EMPTY_REPOS_OBJ_NAME, &to_json(&empty_repos, config)).await?;
+
+    if config.separate_docs {
+        common::save_object(common::DOCS_OBJ_NAME, &to_json(&docs, config)).await?;
+    }
+
+    if config.matrix {
+        common::save_object(
+            common::MATRIX_OBJ_NAME,
+            &to_json(
+                &match &streamed {
+                    Some(saved) => saved.with_repos(|repos| matrix(repos))?,
+                    None => matrix(&per_repo_stats),
+                },
+                config,
+            ),
+        )
+        .await?;
+    }
+
+    common::save_object(
+        common::BY_DOMAIN_OBJ_NAME,
+        &to_json(
+            &match &streamed {
+                Some(saved) => saved.with_repos(|repos| by_domain(repos, config))?,
+                None => by_domain(&per_repo_stats, config),
+            },
+            config,
+        ),
+    )
+    .await?;
+
+    if config.by_year {
+        common::save_object(common::BY_YEAR_OBJ_NAME, &to_json(&by_year, config)).await?;
+    }
+
+    if config.dated_snapshot() {
+        let dated_total = DatedTotal {
+            since: config.snapshot_since,
+            until: config.snapshot_until,
+            languages: dated_total,
+        };
+        common::save_object(common::DATED_TOTAL_OBJ_NAME, &to_json(&dated_total, config)).await?;
+    }
+
+    println!(
+        "All processing complete in {:.2} seconds",
+        (Instant::now() - start_time).as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// The languages counted, and how tokei finds the files
+fn tokei_config(config: &Config) -> tokei::Config {
+    let mut types = config.languages.clone();
+    if config.separate_docs {
+        for &ty in &config.docs_languages {
+            if !types.contains(&ty) {
+                types.push(ty);
+            }
+        }
+    }
+    tokei::Config {
+        types: Some(types),
+        // tokei reads `.gitignore` (only inside a git repo, which our clones are) and `.ignore`/`.tokeignore` on its own
+        no_ignore_vcs: Some(!config.respect_gitignore),
+        no_ignore_dot: Some(!config.respect_gitignore),
+        ..Default::default()
+    }
+}
+
+/// Patterns, like in a `.gitignore`, for the files left out of every repo
+fn ignored_paths(config: &Config) -> Vec<&str> {
+    DEFAULT_IGNORED
+        .iter()
+        .copied()
+        .chain(config.exclude_paths.iter().map(String::as_str))
+        .collect()
+}
+
+/// Percent of `repo_count` repos which failed, never NaN or over 100 when there were no repos to count or more
+/// failures than repos
+fn failure_percent(failed: usize, repo_count: usize) -> f64 {
+    failed as f64 / repo_count.max(failed).max(1) as f64 * 100.0
+}
+
+/// The repo with the most code first, by name when that's the same so the output doesn't depend on which repo
+/// finished first. Sorted once they're post-processed, like `STREAMING_SAVE` sorts them.
+fn sort_per_repo(per_repo: &mut [PerRepo]) {
+    per_repo.sort_by_cached_key(|repo| (Reverse(ranked_code(repo)), repo.name.clone()));
+}
+
+/// What a post-processed repo is ranked by, without the `EXCLUDE_FROM_PER_REPO` languages but with the ones
+/// `MAX_LANGUAGES_PER_REPO` folded together
+fn ranked_code(repo: &PerRepo) -> usize {
+    total_code(&repo.languages) + repo.other.as_ref().map_or(0, |other| other.code)
+}
+
+/// Leaves the `EXCLUDE_FROM_TOTAL` languages out of a total, which the per-repo stats keep
+fn exclude_from_total(total: &mut Vec<SimpleLanguage>, config: &Config) {
+    total.retain(|lang| !config.exclude_from_total.contains(&lang.name));
+}
+
+/// Everything done to a repo's stats after the `POST_PROCESSORS`, sorting its languages by most used
+fn post_process_repo(repo: &mut PerRepo, config: &Config) {
+    repo.languages
+        .retain(|lang| !config.exclude_from_per_repo.contains(&lang.name));
+    repo.languages
+        .sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+    repo.featured = config.featured_repos.contains(&repo.name);
+    if config.prettify_names {
+        repo.display_name = Some(prettify_name(&repo.name));
+    }
+    repo.primary_language = primary_language(&repo.languages, &config.non_primary_languages);
+    if let Some(max) = config.max_languages_per_repo {
+        repo.other = fold_other_languages(&mut repo.languages, max);
+    }
+
+    for subproject in &mut repo.subprojects {
+        subproject
+            .languages
+            .retain(|lang| !config.exclude_from_per_repo.contains(&lang.name));
+        subproject
+            .languages
+            .sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+    }
+    repo.subprojects
+        .sort_by_key(|subproject| Reverse(total_code(&subproject.languages)));
+
+    finalize(&mut repo.languages, config);
+    for subproject in &mut repo.subprojects {
+        finalize(&mut subproject.languages, config);
+    }
+}
+
+/// Everything gathered from the repos while they're processed in parallel
+#[derive(Default)]
+struct Collected {
+    total: Mutex<Vec<SimpleLanguage>>,
+    per_repo: Mutex<Vec<PerRepo>>,
+    failures: Mutex<Vec<Failure>>,
+    by_year: Mutex<BTreeMap<i32, Vec<SimpleLanguage>>>,
+    /// Only with `SNAPSHOT_SINCE`/`SNAPSHOT_UNTIL`
+    dated_total: Mutex<Vec<SimpleLanguage>>,
+    test_vs_source: Mutex<TestVsSource>,
+    skipped_for_deadline: AtomicUsize,
+    /// Named by `Config::repo_label`, and without private repos unless they're anonymized
+    empty_repos: Mutex<Vec<EmptyRepo>>,
+    /// Only with `STREAMING_SAVE`, which keeps `per_repo` empty
+    spill: Option<Spill>,
+}
+
+impl Collected {
+    fn add_per_repo(&self, repo: PerRepo) {
+        let Some(spill) = &self.spill else {
+            self.per_repo.lock().unwrap().push(repo);
+            return;
+        };
+        if let Err(e) = spill.push(&repo) {
+            // Leaving it out without failing would quietly save incomplete per-repo stats
+            self.failures.lock().unwrap().push(Failure {
+                error: RepoError {
+                    repo: repo.name,
+                    phase: Phase::Analyze,
+                    message: format!("couldn't keep the per-repo stats for saving: {e}"),
+                },
+                shareable: true,
+            });
+        }
+    }
+}
+
+fn process_repo(
+    repo: models::Repository,
+    config: &Config,
+    tokei_config: &Arc<tokei::Config>,
+    cache: &RepoCache,
+    collected: &Collected,
+    downloader: &Downloader,
+) {
+    let clone_start = Instant::now();
+    let repo_path = match &config.local_dir {
+        Some(dir) => dir.to_string_lossy().into_owned(),
+        None => format!("/tmp/repo/{}", repo.name),
+    };
+    let label = config.repo_label(&repo);
+    let shareable = shareable(&repo, config);
+    if let Some((sha, analysis)) = cache.take_unpushed(&repo) {
+        println!("\"{label}\" hasn't been pushed to since it was counted at {sha}, reusing its counts without cloning");
+        collect(repo, analysis, config, collected);
+        return;
+    }
+    // The commit a tarball is of, which can't be read from its worktree
+    let mut downloaded_sha = None;
+    // A local directory is analyzed where it is
+    if config.local_dir.is_none() {
+        let method = config.download_method(&repo);
+        let (doing, done) = match method {
+            DownloadMethod::Clone => ("Cloning", "cloning"),
+            DownloadMethod::Tarball => ("Downloading", "downloading"),
+        };
+        println!(
+            "{doing}: \"{}\"; Size: {}",
+            label,
+            repo.size
+                .map(|n| human_bytes::human_bytes(n * 1000))
+                .unwrap_or_default()
+        );
+        let fetched = match method {
+            DownloadMethod::Clone => git::clone_repo(&repo, &repo_path, config)
+                .and_then(|()| match config.backfill_as_of {
+                    Some(date) => git::checkout_as_of(Path::new(&repo_path), date),
+                    None => Ok(()),
+                })
+                .map(|()| None),
+            DownloadMethod::Tarball => downloader.download(&repo, &repo_path, config).map(Some),
+        };
+        if let Err(e) = fetched.map(|sha| downloaded_sha = sha) {
+            eprintln!("Failed {done} \"{}\": {e}", label);
+            collected.failures.lock().unwrap().push(Failure {
+                error: RepoError {
+                    repo: label.clone(),
+                    phase: Phase::Clone,
+                    message: e.to_string(),
+                },
+                shareable,
+            });
+            fs::remove_dir_all(&repo_path).ok();
+            return;
+        }
+
+        println!(
+            "Done {done} \"{}\" in {:.2} seconds!",
+            label,
+            (Instant::now() - clone_start).as_secs_f64()
+        );
+    }
+
+    // Other branches change the counts without HEAD moving
+    let sha = cache.enabled().then(|| match downloaded_sha {
+        Some(sha) => Ok(sha),
+        None if config.all_branches => branches::tips(Path::new(&repo_path)),
+        None => git::head_sha(Path::new(&repo_path)),
+    });
+    let sha = match sha {
+        Some(Ok(sha)) => Some(sha),
+        Some(Err(e)) => {
+            eprintln!("Failed to read the commit of \"{label}\", not caching it: {e}");
+            None
+        }
+        None => None,
+    };
+    let analysis = match sha.as_deref().and_then(|sha| cache.take(&repo.name, sha)) {
+        Some(analysis) => {
             println!(
-                "Done cloning \"{}\" in {:.2} seconds!",
-                repo.name,
-                (Instant::now() - clone_start).as_secs_f64()
+                "Reusing the cached counts of \"{label}\" at {}",
+                sha.as_deref().unwrap_or_default()
             );
+            analysis
+        }
+        None => {
+            let Some(analysis) = analyze(&repo, &label, &repo_path, config, tokei_config) else {
+                let secs = config.analyze_timeout_secs.unwrap_or_default();
+                eprintln!("Analyzing \"{label}\" took over {secs} seconds, skipping it");
+                collected.failures.lock().unwrap().push(Failure {
+                    error: RepoError {
+                        repo: label.clone(),
+                        phase: Phase::Analyze,
+                        message: format!("analysis timed out after {secs} seconds"),
+                    },
+                    shareable,
+                });
+                remove_clone(&repo_path, config);
+                return;
+            };
+            if let Some(sha) = &sha {
+                cache.insert(&repo.name, sha, &analysis);
+            }
+            analysis
+        }
+    };
+    if let Some(sha) = &sha {
+        cache.record_pushed(&repo, sha);
+    }
+    collect(repo, analysis, config, collected);
 
-            // tokei stuff
-            let start_analyzing = Instant::now();
-            let mut languages = tokei::Languages::new();
-            println!("Analyzing \"{}\"...", repo.name);
-            languages.get_statistics(
-                &[&repo_path],
-                &["build", "package-lock.json", "pnpm-lock.yaml"],
-                &config,
-            );
+    remove_clone(&repo_path, config);
+    println!(
+        "Done with \"{}\" in {:.2} seconds!",
+        label,
+        (Instant::now() - clone_start).as_secs_f64()
+    );
+}
+
+/// `BACKFILL_MODE`, archives the total as of each date which isn't done yet, oldest first. Each date is a run
+/// over every repo with each clone checked out at its last commit by that day, one repo at a time with
+/// `BACKFILL_PAUSE_SECS` between them, and is checkpointed once it's saved, so a backfill stopped by
+/// `MAX_RUNTIME_SECS` or failures carries on from the next date when run again.
+async fn backfill(config: &Config, github: &Arc<Octocrab>) -> Result<(), Error> {
+    let start_time = Instant::now();
+    let mut checkpoint = backfill::Checkpoint::load().await;
+    let pending = checkpoint.pending(&config.backfill_dates);
+    if pending.is_empty() {
+        println!("Every BACKFILL_MODE date is already archived");
+        return Ok(());
+    }
+    println!(
+        "Backfilling {} dates, {} are already archived",
+        pending.len(),
+        config.backfill_dates.len() - pending.len()
+    );
+
+    let (repos, _) = repos_to_process(config, github).await?;
+    let tokei_config = Arc::new(tokei_config(config));
+    // Never used, repos are always cloned for their history while backfilling
+    let downloader = Downloader::new(github);
+    let deadline = config
+        .max_runtime_secs
+        .map(|secs| start_time + Duration::from_secs(secs));
+    let pause = Duration::from_secs(config.backfill_pause_secs);
+
+    for (done, date) in pending.iter().copied().enumerate() {
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
             println!(
-                "Done analyzing \"{}\" in {:.2} seconds!",
-                repo.name,
-                (Instant::now() - start_analyzing).as_secs_f64()
+                "Past MAX_RUNTIME_SECS, leaving {} dates for the next run",
+                pending.len() - done
             );
+            break;
+        }
 
-            for (ty, lang) in &languages {
-                let mut total_lock = total.lock().unwrap();
-                if let Some(total_lang) = total_lock.iter_mut().find(|lang| &lang.name == ty) {
-                    *total_lang += &SimpleLanguage::from_lang(ty, lang);
-                } else {
-                    total_lock.push(SimpleLanguage::from_lang(ty, &lang));
-                }
+        println!("{SEPARATOR}\n\nBackfilling {date}");
+        let date_start = Instant::now();
+        let config = Config {
+            backfill_as_of: Some(date),
+            ..config.clone()
+        };
+        fs::remove_dir_all("/tmp/repo").ok();
+        fs::create_dir("/tmp/repo").unwrap();
+        let collected = Collected::default();
+        let cache = RepoCache::default();
+        for (i, repo) in repos.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(pause).await;
             }
+            // Cloning and counting block, so the runtime has to be told
+            tokio::task::block_in_place(|| {
+                process_repo(
+                    repo.clone(),
+                    &config,
+                    &tokei_config,
+                    &cache,
+                    &collected,
+                    &downloader,
+                )
+            });
+        }
 
-            if !exclude_repos.contains(&repo.name.as_str()) && !repo.private.is_some_and(|p| p) {
-                // Only include in per-repo if the repo is public and not excluded
-                per_repo_stats.lock().unwrap().push(PerRepo {
-                    languages: languages
-                        .iter()
-                        .map(|(lang, stat)| SimpleLanguage::from_lang(lang, stat))
-                        .collect(),
-                    name: repo.name.clone(),
-                    href: repo.html_url.unwrap().to_string(),
-                    description: repo.description,
-                });
-            } else {
-                println!("Excluding \"{}\" from per-repo stats.", repo.name);
+        let failures = collected.failures.into_inner().unwrap();
+        if !failures.is_empty() {
+            for Failure { error, .. } in &failures {
+                eprintln!(
+                    "\"{}\" failed during {:?}: {}",
+                    error.repo, error.phase, error.message
+                );
+            }
+            let failure_percent = failure_percent(failures.len(), repos.len());
+            if failure_percent > config.max_failure_percent {
+                return Err(format!(
+                    "Too many repos failed ({failure_percent:.1}% > {}%) backfilling {date}, it's left for the next run",
+                    config.max_failure_percent
+                )
+                .into());
+            }
+        }
+
+        let mut total = collected.total.into_inner().unwrap();
+        if config
+            .post_processors
+            .contains(&BuiltInProcessor::CombineTsTsx)
+        {
+            post::combine_ts_tsx(&mut total, &config);
+        }
+        exclude_from_total(&mut total, &config);
+        total.sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+        finalize(&mut total, &config);
+        common::save_object(
+            &common::archive_key(date, common::TOTAL_STATS_OBJ_NAME),
+            &to_json(&total, &config),
+        )
+        .await?;
+        checkpoint.mark_done(date).await?;
+        println!(
+            "Backfilled {date} in {:.2} seconds",
+            (Instant::now() - date_start).as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
+
+/// The repos a run processes, largest first, and how many were listed before any were left out
+async fn repos_to_process(
+    config: &Config,
+    github: &Arc<Octocrab>,
+) -> Result<(Vec<models::Repository>, usize), Error> {
+    let mut repos = source::list_repos(config, github).await?;
+    if config.include_topics {
+        source::fill_missing_topics(github, &mut repos, config).await;
+    }
+    let listed = repos.len();
+
+    if !config.include_repos.is_empty() {
+        repos.retain(|repo| config.include_repos.contains(&repo.name));
+        println!("Only processing the {} repos in INCLUDE_REPOS", repos.len());
+    }
+
+    if let Some(min_kb) = config.min_repo_size_kb {
+        // Unknown sizes are cloned, there's no telling what's in them
+        repos.retain(|repo| match repo.size {
+            Some(size) if u64::from(size) < min_kb => {
+                println!(
+                    "Skipping \"{}\", {size}KB is under MIN_REPO_SIZE_KB",
+                    config.repo_label(repo)
+                );
+                false
             }
+            _ => true,
+        });
+    }
 
-            fs::remove_dir_all(&repo_path).unwrap();
+    if config.own_repos_only {
+        contributors::retain_own_repos(github, &mut repos, config).await?;
+    }
+
+    // Process largest repos first, with the name as a tiebreaker so the order is the same every run
+    repos.sort_unstable_by(|a, b| {
+        b.size
+            .unwrap_or_default()
+            .cmp(&a.size.unwrap_or_default())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    if let Some(max_repos) = config.max_repos {
+        if repos.len() > max_repos {
             println!(
-                "Done with \"{}\" in {:.2} seconds!",
-                repo.name,
-                (Instant::now() - clone_start).as_secs_f64()
+                "Only processing the {max_repos} largest repos, dropping {}",
+                repos.len() - max_repos
             );
+            repos.truncate(max_repos);
         }
-    });
+    }
 
-    println!(
-        "{SEPARATOR}\n\nFinished all in {:.2} seconds!!!",
-        (Instant::now() - start_time).as_secs_f64()
-    );
+    Ok((repos, listed))
+}
 
-    println!("Starting post-processing!");
-    let post_start = Instant::now();
-    let mut total = Arc::try_unwrap(total).unwrap().into_inner().unwrap();
-    let mut per_repo_stats = Arc::try_unwrap(per_repo_stats)
-        .unwrap()
-        .into_inner()
-        .unwrap();
+/// Private names only get out when they're anonymized
+fn shareable(repo: &models::Repository, config: &Config) -> bool {
+    let private = repo.private.is_some_and(|p| p);
+    !config.exclude_repos.contains(&repo.name) && (!private || config.anonymize_private)
+}
 
-    combine_ts_tsx(&mut total);
+/// Adds what was counted in a repo to everything gathered so far
+fn collect(repo: models::Repository, analysis: Analysis, config: &Config, collected: &Collected) {
+    let label = config.repo_label(&repo);
+    let excluded = config.exclude_repos.contains(&repo.name);
+    let private = repo.private.is_some_and(|p| p);
+    let shareable = shareable(&repo, config);
+    let Analysis {
+        languages,
+        test,
+        source,
+        by_year,
+        dated_total,
+        empty,
+        subprojects,
+        readme_description,
+        primary_framework,
+    } = analysis;
 
-    // Manual adjustment for code done for contract work/internship
-    total
-        .iter_mut()
-        .find(|l| l.name == LanguageType::Rust)
-        .unwrap()
-        .code += 15673;
+    {
+        let mut total = collected.total.lock().unwrap();
+        for lang in &languages {
+            add_language(&mut total, lang.clone());
+        }
+    }
 
-    total
-        .iter_mut()
-        .find(|l| l.name == LanguageType::TypeScript)
-        .unwrap()
-        .code += 4333;
+    {
+        let mut test_vs_source = collected.test_vs_source.lock().unwrap();
+        for lang in test {
+            add_language(&mut test_vs_source.test, lang);
+        }
+        for lang in source {
+            add_language(&mut test_vs_source.source, lang);
+        }
+    }
 
-    total
-        .iter_mut()
-        .find(|l| l.name == LanguageType::Java)
-        .unwrap()
-        .code += 4517;
+    if !by_year.is_empty() {
+        let mut collected_by_year = collected.by_year.lock().unwrap();
+        for (year, languages) in by_year {
+            let collected_year = collected_by_year.entry(year).or_default();
+            for lang in languages {
+                add_language(collected_year, lang);
+            }
+        }
+    }
 
-    // Sort so that the repo with the most code is at the top
-    per_repo_stats.sort_unstable_by(|a, b| total_code(&b.languages).cmp(&total_code(&a.languages)));
+    if !dated_total.is_empty() {
+        let mut collected_dated_total = collected.dated_total.lock().unwrap();
+        for lang in dated_total {
+            add_language(&mut collected_dated_total, lang);
+        }
+    }
 
-    // In each repo, sort languages by most used
-    for repo in &mut per_repo_stats {
-        combine_ts_tsx(&mut repo.languages);
-        repo.languages.sort_unstable_by(|a, b| b.code.cmp(&a.code));
+    if let Some(reason) = empty {
+        println!("\"{label}\" has no code counted: {reason:?}");
+        if shareable {
+            collected.empty_repos.lock().unwrap().push(EmptyRepo {
+                name: label.clone(),
+                reason,
+            });
+        }
     }
 
-    total.sort_unstable_by(|a, b| b.code.cmp(&a.code));
+    if !excluded && !private {
+        // Only include in per-repo if the repo is public and not excluded
+        match repo.html_url {
+            Some(href) => collected.add_per_repo(PerRepo {
+                subprojects,
+                languages,
+                name: repo.name.clone(),
+                display_name: None,
+                href: href.to_string(),
+                description: repo.description.or(readme_description),
+                primary_language: None,
+                primary_framework,
+                other: None,
+                featured: false,
+                external: false,
+                freshness: repo
+                    .pushed_at
+                    .map(|pushed_at| freshness(pushed_at, Utc::now(), config)),
+                topics: if config.include_topics {
+                    repo.topics.unwrap_or_default()
+                } else {
+                    Vec::new()
+                },
+            }),
+            None => collected.failures.lock().unwrap().push(Failure {
+                error: RepoError {
+                    repo: label.clone(),
+                    phase: Phase::Analyze,
+                    message: "repo has no html url".to_string(),
+                },
+                shareable,
+            }),
+        }
+    } else {
+        println!("Excluding \"{}\" from per-repo stats.", label);
+    }
+}
+
+/// Never deletes a `LOCAL_DIR`, only clones
+fn remove_clone(repo_path: &str, config: &Config) {
+    if config.local_dir.is_none() {
+        fs::remove_dir_all(repo_path).ok();
+    }
+}
 
+/// Counts everything in a cloned repo, or `None` when tokei runs past `ANALYZE_TIMEOUT_SECS`
+fn analyze(
+    repo: &models::Repository,
+    label: &str,
+    repo_path: &str,
+    config: &Config,
+    tokei_config: &Arc<tokei::Config>,
+) -> Option<Analysis> {
+    let start_analyzing = Instant::now();
+    println!("Analyzing \"{}\"...", label);
+    let mut ignored = ignored_paths(config);
+    let lfs_patterns = lfs::tracked_patterns(Path::new(repo_path));
+    if !lfs_patterns.is_empty() {
+        println!(
+            "\"{}\" has {} LFS tracked files",
+            label,
+            lfs::count_tracked_files(Path::new(repo_path), &lfs_patterns)
+        );
+        if config.lfs_handling == LfsHandling::Skip {
+            ignored.extend(lfs_patterns.iter().map(String::as_str));
+        }
+    }
+    let paths = only_paths(
+        label,
+        Path::new(repo_path),
+        paths_to_analyze(label, Path::new(repo_path), config),
+        config,
+    );
+    // Only when walking the whole repo, the patterns are relative to its root
+    let binary_dir_patterns = match config.skip_binary_dirs {
+        Some(percent) if paths == [Path::new(repo_path)] => {
+            binary_dirs::find(Path::new(repo_path), percent, tokei_config)
+                .into_iter()
+                // tokei matches its ignores against the full path
+                .map(|dir| format!("**{repo_path}/{}/", dir.to_string_lossy()))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    ignored.extend(binary_dir_patterns.iter().map(String::as_str));
+    let override_patterns = extensions::ignore_patterns(config);
+    let mut tokei_ignored = ignored.clone();
+    tokei_ignored.extend(override_patterns.iter().map(String::as_str));
+    let mut languages = get_statistics(&paths, &tokei_ignored, tokei_config, config)?;
+    extensions::count_overridden(
+        Path::new(repo_path),
+        &paths,
+        &ignored,
+        config,
+        tokei_config,
+        &mut languages,
+    );
+    // Only the files read from the worktree, the other branches are normalized as they're read from git
+    let recounted = encoding::recount(&mut languages, config, tokei_config);
+    if recounted > 0 {
+        println!("Counted {recounted} files of \"{label}\" again after normalizing them");
+    }
+    if config.all_branches {
+        match branches::count_other_branches(
+            Path::new(repo_path),
+            &ignored,
+            config,
+            tokei_config,
+            &mut languages,
+        ) {
+            Ok(added) => {
+                println!("Counted {added} more files from the other branches of \"{label}\"")
+            }
+            Err(e) => eprintln!("Failed to count the other branches of \"{label}\": {e}"),
+        }
+    }
+    if config.drop_largest_file_per_language {
+        for (ty, path, lines) in cap::drop_largest_files(&mut languages) {
+            let path = path.strip_prefix(repo_path).unwrap_or(&path);
+            println!(
+                "Dropped {} from \"{label}\", its largest {ty} file at {lines} lines",
+                path.display()
+            );
+        }
+    }
+    if let Some(cap) = config.per_file_line_cap {
+        let capped = cap::cap_file_lines(&mut languages, cap);
+        if capped > 0 {
+            println!("Capped {capped} files of \"{label}\" to {cap} lines");
+        }
+    }
     println!(
-        "Post-processing complete in {:.2} seconds",
-        (Instant::now() - post_start).as_secs_f64()
+        "Done analyzing \"{}\" in {:.2} seconds!",
+        label,
+        (Instant::now() - start_analyzing).as_secs_f64()
     );
 
-    common::save_stats(
-        &serde_json::to_string(&total).unwrap(),
-        &serde_json::to_string(&per_repo_stats).unwrap(),
-    )
-    .await?;
+    let mut analysis = Analysis {
+        languages: languages
+            .iter()
+            .map(|(ty, lang)| SimpleLanguage::from_lang(ty, lang))
+            .collect(),
+        ..Default::default()
+    };
 
-    println!(
-        "All processing complete in {:.2} seconds",
-        (Instant::now() - start_time).as_secs_f64()
-    );
+    for (ty, lang) in &languages {
+        for report in &lang.reports {
+            let path = report.name.strip_prefix(repo_path).unwrap_or(&report.name);
+            let split = if config.test_patterns.is_match(path) {
+                &mut analysis.test
+            } else {
+                &mut analysis.source
+            };
+            add_language(split, SimpleLanguage::from_stats(ty, &report.stats));
+        }
+    }
 
-    Ok(())
-}
+    if config.by_year || config.dated_snapshot() {
+        match git::last_touched(Path::new(repo_path)) {
+            Ok(touched) => {
+                for (ty, lang) in &languages {
+                    for report in &lang.reports {
+                        let Some(time) = report
+                            .name
+                            .strip_prefix(repo_path)
+                            .ok()
+                            .and_then(|path| touched.get(path))
+                            .and_then(|&time| DateTime::from_timestamp(time, 0))
+                        else {
+                            continue;
+                        };
+
+                        if config.by_year {
+                            add_language(
+                                analysis.by_year.entry(time.year()).or_default(),
+                                SimpleLanguage::from_stats(ty, &report.stats),
+                            );
+                        }
 
-fn total_code(languages: &[SimpleLanguage]) -> usize {
-    let mut total = 0;
+                        let date = time.date_naive();
+                        if config.dated_snapshot()
+                            && config.snapshot_since.is_none_or(|since| date >= since)
+                            && config.snapshot_until.is_none_or(|until| date <= until)
+                        {
+                            add_language(
+                                &mut analysis.dated_total,
+                                SimpleLanguage::from_stats(ty, &report.stats),
+                            );
+                        }
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to walk history of \"{}\": {e}", label),
+        }
+    }
+
+    if languages.iter().all(|(_, lang)| lang.code == 0) {
+        analysis.empty = Some(empty::classify(Path::new(repo_path)));
+    }
+
+    if config.monorepo_subdirs.contains(&repo.name) {
+        analysis.subprojects = monorepo::subprojects(Path::new(repo_path), &languages);
+    }
 
-    for lang in languages {
-        total += lang.code;
+    if config.readme_description_fallback {
+        analysis.readme_description = readme::description(Path::new(repo_path));
     }
 
-    total
+    analysis.primary_framework =
+        frameworks::primary(Path::new(repo_path), &config.framework_signatures);
+
+    Some(analysis)
 }
 
-fn combine_ts_tsx(langs: &mut Vec<SimpleLanguage>) {
-    let Some((tsx_idx, tsx)) = langs
-        .iter()
-        .enumerate()
-        .find(|(_, l)| l.name == LanguageType::Tsx)
-    else {
-        return;
+/// Runs tokei over `paths`, or gives up with `None` after `ANALYZE_TIMEOUT_SECS`. tokei can't be stopped once
+/// it's started, so with a timeout it runs on its own thread which is left to finish in the background.
+fn get_statistics(
+    paths: &[PathBuf],
+    ignored: &[&str],
+    tokei_config: &Arc<tokei::Config>,
+    config: &Config,
+) -> Option<tokei::Languages> {
+    let mut languages = tokei::Languages::new();
+    // tokei panics without any paths
+    if paths.is_empty() {
+        return Some(languages);
+    }
+    let Some(secs) = config.analyze_timeout_secs else {
+        languages.get_statistics(paths, ignored, tokei_config);
+        return Some(languages);
     };
-    let tsx = tsx.clone();
 
-    // Combine tsx and typescript into typescript
-    let Some(ts) = langs
-        .iter_mut()
-        .find(|l| l.name == LanguageType::TypeScript)
-    else {
-        return;
+    let paths = paths.to_vec();
+    let ignored = ignored.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let tokei_config = tokei_config.clone();
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let ignored = ignored.iter().map(String::as_str).collect::<Vec<_>>();
+        languages.get_statistics(&paths, &ignored, &tokei_config);
+        // Nobody is listening anymore when it timed out
+        sender.send(languages).ok();
+    });
+
+    receiver.recv_timeout(Duration::from_secs(secs)).ok()
+}
+
+/// The whole repo, or only the files changed by the last commits with `RECENT_COMMITS_ONLY`
+fn paths_to_analyze(label: &str, repo_path: &Path, config: &Config) -> Vec<PathBuf> {
+    let Some(commits) = config.recent_commits_only else {
+        return vec![repo_path.to_path_buf()];
     };
 
-    *ts += &tsx;
+    match git::changed_in_last_commits(repo_path, commits) {
+        Ok(Some(changed)) => {
+            println!(
+                "Only analyzing the {} files of \"{}\" changed in the last {commits} commits",
+                changed.len(),
+                label
+            );
+            changed
+                .into_iter()
+                .map(|path| repo_path.join(path))
+                .collect()
+        }
+        Ok(None) => {
+            println!(
+                "\"{}\" is too shallow to see the last {commits} commits, analyzing all of it",
+                label
+            );
+            vec![repo_path.to_path_buf()]
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to find recently changed files of \"{}\", analyzing all of it: {e}",
+                label
+            );
+            vec![repo_path.to_path_buf()]
+        }
+    }
+}
+
+/// Narrows `paths` down to the `ONLY_PATHS` of the repo, which might leave nothing
+fn only_paths(label: &str, repo_path: &Path, paths: Vec<PathBuf>, config: &Config) -> Vec<PathBuf> {
+    if config.only_paths.is_empty() {
+        return paths;
+    }
+
+    let only = config
+        .only_paths
+        .iter()
+        .map(|path| repo_path.join(path))
+        .collect::<Vec<_>>();
+    let paths = if paths == [repo_path] {
+        only.into_iter().filter(|path| path.exists()).collect()
+    } else {
+        paths
+            .into_iter()
+            .filter(|path| only.iter().any(|only| path.starts_with(only)))
+            .collect::<Vec<_>>()
+    };
+    if paths.is_empty() {
+        println!("\"{label}\" has nothing in ONLY_PATHS to analyze");
+    }
+
+    paths
+}
+
+/// Every stored object goes through here, so `PRETTY_JSON` and `COMBINE_NON_CODE` apply to all of them
+fn to_json(value: &impl Serialize, config: &Config) -> String {
+    if config.combine_non_code {
+        let mut value = serde_json::to_value(value).unwrap();
+        combine_non_code(&mut value);
+        return write_json(&value, config);
+    }
+
+    write_json(value, config)
+}
+
+fn write_json(value: &impl Serialize, config: &Config) -> String {
+    if config.pretty_json {
+        serde_json::to_string_pretty(value).unwrap()
+    } else {
+        serde_json::to_string(value).unwrap()
+    }
+}
+
+/// Replaces `comments` and `blanks` with their sum wherever both are, which covers every kind of counts
+fn combine_non_code(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            // Signed so the differences in `changes.json` work too
+            let counts = object
+                .get("comments")
+                .and_then(|c| c.as_i64())
+                .zip(object.get("blanks").and_then(|b| b.as_i64()));
+            if let Some((comments, blanks)) = counts {
+                object.remove("comments");
+                object.remove("blanks");
+                object.insert("non_code".to_string(), (comments + blanks).into());
+            }
+
+            object.values_mut().for_each(combine_non_code);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(combine_non_code),
+        _ => {}
+    }
+}
+
+async fn run_on_interval(config: &Config, github: &Arc<Octocrab>, interval: Duration) {
+    println!("Running every {} seconds", interval.as_secs());
+    let mut interval = tokio::time::interval(interval);
+    // A slow run shouldn't cause a burst of runs to catch up
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = run(config, github).await {
+            eprintln!("Run failed, trying again next interval: {e}");
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+pub(crate) async fn my_handler(
+    event: lambda_runtime::LambdaEvent<serde_json::Value>,
+    config: &Config,
+    github: &Arc<Octocrab>,
+) -> Result<(), Error> {
+    handle(config, github, Some(&event.payload)).await
+}
 
-    langs.swap_remove(tsx_idx);
+/// A normal run, or a `PR_DIFF_MODE` one with the refs from the event when it has them
+async fn handle(
+    config: &Config,
+    github: &Arc<Octocrab>,
+    event: Option<&serde_json::Value>,
+) -> Result<(), Error> {
+    if config.pr_diff_mode {
+        pr_diff::run(config, event, &ignored_paths(config), &tokei_config(config)).await
+    } else if !config.backfill_dates.is_empty() {
+        backfill(config, github).await
+    } else {
+        run(config, github).await
+    }
 }
 
-pub(crate) async fn my_handler(_: LambdaEvent<serde_json::Value>) -> Result<(), Error> {
-    run().await
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(name: LanguageType, code: usize) -> SimpleLanguage {
+        SimpleLanguage {
+            name,
+            display_name: None,
+            code,
+            blanks: 0,
+            comments: 0,
+            files: 1,
+            avg_lines_per_file: None,
+            comment_ratio: None,
+        }
+    }
+
+    fn repo(name: &str, languages: Vec<SimpleLanguage>) -> PerRepo {
+        PerRepo {
+            name: name.to_string(),
+            languages,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gitignored_files_are_only_counted_when_asked_to() {
+        let repo = git::tests::fixture(&[(
+            "2024-01-01T12:00:00Z",
+            &[
+                (".gitignore", "generated.rs\n"),
+                ("main.rs", "fn main() {}\n"),
+            ],
+        )]);
+        std::fs::write(repo.path().join("generated.rs"), "fn generated() {}\n").unwrap();
+
+        let rust_files = |respect_gitignore| {
+            let config = Config {
+                respect_gitignore,
+                ..config::tests::config()
+            };
+            let tokei_config = Arc::new(tokei_config(&config));
+            let languages =
+                get_statistics(&[repo.path().to_path_buf()], &[], &tokei_config, &config).unwrap();
+            languages[&LanguageType::Rust].reports.len()
+        };
+        assert_eq!(rust_files(true), 1);
+        assert_eq!(rust_files(false), 2);
+    }
+
+    #[test]
+    fn languages_are_excluded_from_the_total_and_per_repo_separately() {
+        let config = Config {
+            exclude_from_total: vec![LanguageType::Markdown],
+            exclude_from_per_repo: vec![LanguageType::Json],
+            ..config::tests::config()
+        };
+        let languages = || {
+            vec![
+                language(LanguageType::Rust, 10),
+                language(LanguageType::Markdown, 5),
+                language(LanguageType::Json, 3),
+            ]
+        };
+        let names =
+            |langs: &[SimpleLanguage]| langs.iter().map(|lang| lang.name).collect::<Vec<_>>();
+
+        let mut total = languages();
+        exclude_from_total(&mut total, &config);
+        assert_eq!(names(&total), [LanguageType::Rust, LanguageType::Json]);
+
+        let mut repo = repo("docs", languages());
+        post_process_repo(&mut repo, &config);
+        assert_eq!(
+            names(&repo.languages),
+            [LanguageType::Rust, LanguageType::Markdown]
+        );
+    }
+
+    #[test]
+    fn only_the_featured_repos_are_flagged() {
+        let config = Config {
+            featured_repos: vec!["app".to_string()],
+            ..config::tests::config()
+        };
+        let mut featured = repo("app", Vec::new());
+        post_process_repo(&mut featured, &config);
+        assert!(featured.featured);
+        let mut other = repo("apps", Vec::new());
+        post_process_repo(&mut other, &config);
+        assert!(!other.featured);
+    }
+
+    #[test]
+    fn non_code_is_combined_when_serialized() {
+        let mut lang = language(LanguageType::Rust, 10);
+        lang.comments = 3;
+        lang.blanks = 2;
+        let config = Config {
+            combine_non_code: true,
+            ..config::tests::config()
+        };
+        let json = serde_json::from_str::<serde_json::Value>(&to_json(
+            &[repo("app", vec![lang.clone()])],
+            &config,
+        ))
+        .unwrap();
+        let lang_json = &json[0]["languages"][0];
+        assert_eq!(lang_json["code"], 10);
+        assert_eq!(lang_json["non_code"], 5);
+        assert!(lang_json.get("comments").is_none() && lang_json.get("blanks").is_none());
+
+        let config = Config {
+            combine_non_code: false,
+            ..config
+        };
+        let json = serde_json::from_str::<serde_json::Value>(&to_json(&lang, &config)).unwrap();
+        assert_eq!(
+            (json["comments"].as_u64(), json["blanks"].as_u64()),
+            (Some(3), Some(2))
+        );
+        assert!(json.get("non_code").is_none());
+    }
+
+    #[test]
+    fn display_names_are_only_set_with_prettify_names() {
+        let mut repo = repo("my-cool_project", Vec::new());
+        post_process_repo(&mut repo, &config::tests::config());
+        assert_eq!(repo.display_name, None);
+
+        let config = Config {
+            prettify_names: true,
+            ..config::tests::config()
+        };
+        post_process_repo(&mut repo, &config);
+        assert_eq!(repo.name, "my-cool_project");
+        assert_eq!(repo.display_name.as_deref(), Some("My Cool Project"));
+    }
+
+    #[test]
+    fn only_the_matching_directories_are_counted() {
+        let repo = tempfile::tempdir().unwrap();
+        for path in [
+            "src/main.rs",
+            "src/nested/lib.rs",
+            "tools/gen.rs",
+            "srcs/other.rs",
+        ] {
+            let path = repo.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "fn main() {}\n").unwrap();
+        }
+        let config = Config {
+            only_paths: vec![PathBuf::from("src"), PathBuf::from("lib")],
+            ..config::tests::config()
+        };
+
+        let paths = only_paths(
+            "repo",
+            repo.path(),
+            vec![repo.path().to_path_buf()],
+            &config,
+        );
+        assert_eq!(paths, [repo.path().join("src")]);
+        let tokei_config = Arc::new(tokei_config(&config));
+        let languages = get_statistics(&paths, &[], &tokei_config, &config).unwrap();
+        assert_eq!(languages[&LanguageType::Rust].reports.len(), 2);
+
+        // Like the files changed by `RECENT_COMMITS_ONLY`
+        let changed =
+            ["src/main.rs", "tools/gen.rs", "srcs/other.rs"].map(|path| repo.path().join(path));
+        assert_eq!(
+            only_paths("repo", repo.path(), changed.to_vec(), &config),
+            [repo.path().join("src/main.rs")]
+        );
+
+        // A repo without any of them contributes nothing
+        let config = Config {
+            only_paths: vec![PathBuf::from("lib")],
+            ..config
+        };
+        assert!(only_paths(
+            "repo",
+            repo.path(),
+            vec![repo.path().to_path_buf()],
+            &config
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn folded_languages_stay_in_the_saved_total() {
+        let mut languages = vec![
+            language(LanguageType::Rust, 990),
+            language(LanguageType::Lua, 10),
+        ];
+        let other = apply_min_total_percent(&mut languages, 5.0, config::MinTotalPercentMode::Fold);
+        let total = Total { languages, other };
+        let config = config::tests::config();
+
+        let json = serde_json::from_str::<serde_json::Value>(&to_json(&total, &config)).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["name"], "Rust");
+        assert_eq!(entries[1]["name"], "Other");
+        assert_eq!(entries[1]["languages"], serde_json::json!(["Lua"]));
+        let code = entries
+            .iter()
+            .map(|entry| entry["code"].as_u64().unwrap())
+            .sum::<u64>();
+        assert_eq!(code, 1000);
+
+        // Combined like any other counts
+        let config = Config {
+            combine_non_code: true,
+            ..config
+        };
+        let json = serde_json::from_str::<serde_json::Value>(&to_json(&total, &config)).unwrap();
+        assert_eq!(json[1]["non_code"], 0);
+    }
+
+    #[test]
+    fn failure_percent_handles_no_repos() {
+        assert_eq!(failure_percent(0, 0), 0.0);
+        assert_eq!(failure_percent(1, 0), 100.0);
+        assert_eq!(failure_percent(3, 2), 100.0);
+        assert_eq!(failure_percent(1, 4), 25.0);
+    }
+
+    #[test]
+    fn ties_serialize_the_same_whatever_order_repos_finish_in() {
+        let repos = || {
+            vec![
+                repo("b", vec![language(LanguageType::Rust, 10)]),
+                repo("a", vec![language(LanguageType::Java, 10)]),
+                repo("c", vec![language(LanguageType::Rust, 20)]),
+                repo("d", vec![language(LanguageType::Go, 10)]),
+            ]
+        };
+        let mut finished = repos();
+        let mut reversed = repos();
+        reversed.reverse();
+
+        sort_per_repo(&mut finished);
+        sort_per_repo(&mut reversed);
+        let names = finished
+            .iter()
+            .map(|repo| repo.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["c", "a", "b", "d"]);
+        assert_eq!(
+            serde_json::to_vec(&finished).unwrap(),
+            serde_json::to_vec(&reversed).unwrap()
+        );
+    }
+
+    #[test]
+    fn repos_are_ranked_without_the_languages_left_out_of_them() {
+        let config = Config {
+            exclude_from_per_repo: vec![LanguageType::Json],
+            max_languages_per_repo: Some(1),
+            ..config::tests::config()
+        };
+        let mut repos = vec![
+            repo(
+                "data",
+                vec![
+                    language(LanguageType::Json, 100),
+                    language(LanguageType::Rust, 5),
+                ],
+            ),
+            repo("app", vec![language(LanguageType::Rust, 10)]),
+            // Still ranked by the languages folded into its `other`
+            repo(
+                "mixed",
+                vec![
+                    language(LanguageType::Go, 4),
+                    language(LanguageType::Lua, 4),
+                ],
+            ),
+        ];
+        for repo in &mut repos {
+            post_process_repo(repo, &config);
+        }
+        sort_per_repo(&mut repos);
+
+        let names = repos
+            .iter()
+            .map(|repo| repo.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["app", "mixed", "data"]);
+    }
 }
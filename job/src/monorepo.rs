@@ -0,0 +1,39 @@
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path},
+};
+
+use common::stats::{SimpleLanguage, Subproject};
+use tokei::Languages;
+
+use crate::post::add_language;
+
+/// Splits the counted files of a repo by their top-level directory, files right in the root aren't in any of them
+pub fn subprojects(repo_path: &Path, languages: &Languages) -> Vec<Subproject> {
+    let mut by_dir = BTreeMap::<String, Vec<SimpleLanguage>>::new();
+
+    for (ty, lang) in languages {
+        for report in &lang.reports {
+            let path = report.name.strip_prefix(repo_path).unwrap_or(&report.name);
+            let mut components = path.components();
+            let Some(Component::Normal(dir)) = components.next() else {
+                continue;
+            };
+            if components.next().is_none() {
+                continue;
+            }
+
+            add_language(
+                by_dir
+                    .entry(dir.to_string_lossy().into_owned())
+                    .or_default(),
+                SimpleLanguage::from_stats(ty, &report.stats),
+            );
+        }
+    }
+
+    by_dir
+        .into_iter()
+        .map(|(path, languages)| Subproject { path, languages })
+        .collect()
+}
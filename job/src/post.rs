@@ -0,0 +1,566 @@
+use std::{borrow::Borrow, cmp::Reverse, collections::BTreeMap};
+
+use chrono::{DateTime, Utc};
+use common::stats::{
+    total_code, Domain, Freshness, Matrix, MatrixCell, OtherLanguages, PerRepo, SimpleLanguage,
+    UNTAGGED_DOMAIN,
+};
+use tokei::LanguageType;
+
+use crate::config::{Config, MinTotalPercentMode};
+
+/// Names shown instead of tokei's own for a few languages, `LANGUAGE_ALIASES` takes precedence
+const DEFAULT_ALIASES: &[(LanguageType, &str)] = &[
+    (LanguageType::Sh, "Shell"),
+    (LanguageType::Cpp, "C++"),
+    (LanguageType::CppHeader, "C++ Header"),
+    (LanguageType::Css, "CSS"),
+    (LanguageType::Html, "HTML"),
+];
+
+/// Adds `lang` into the matching entry of `langs`, or appends it if there is none yet
+pub fn add_language(langs: &mut Vec<SimpleLanguage>, lang: SimpleLanguage) {
+    if let Some(existing) = langs.iter_mut().find(|l| l.name == lang.name) {
+        existing.merge(&lang);
+    } else {
+        langs.push(lang);
+    }
+}
+
+/// Folds TSX into TypeScript, with its code counted at `TSX_CODE_WEIGHT`. TSX without any TypeScript becomes it.
+pub fn combine_ts_tsx(langs: &mut Vec<SimpleLanguage>, config: &Config) {
+    let Some((tsx_idx, tsx)) = langs
+        .iter()
+        .enumerate()
+        .find(|(_, l)| l.name == LanguageType::Tsx)
+    else {
+        return;
+    };
+    let tsx = SimpleLanguage {
+        name: LanguageType::TypeScript,
+        display_name: None,
+        code: (tsx.code as f64 * config.tsx_code_weight).round() as usize,
+        ..tsx.clone()
+    };
+
+    // Combine tsx and typescript into typescript
+    match langs
+        .iter_mut()
+        .find(|l| l.name == LanguageType::TypeScript)
+    {
+        Some(ts) => {
+            ts.merge(&tsx);
+            langs.swap_remove(tsx_idx);
+        }
+        None => langs[tsx_idx] = tsx,
+    }
+}
+
+/// The language with the most code, skipping `non_primary` ones unless they're the only ones present
+pub fn primary_language(
+    langs: &[SimpleLanguage],
+    non_primary: &[LanguageType],
+) -> Option<LanguageType> {
+    langs
+        .iter()
+        .filter(|lang| !non_primary.contains(&lang.name))
+        .max_by_key(|lang| lang.code)
+        .or_else(|| langs.iter().max_by_key(|lang| lang.code))
+        .map(|lang| lang.name)
+}
+
+/// Pushed to within `ACTIVE_DAYS` is active, over `STALE_DAYS` ago is stale, and anything between is maintained
+pub fn freshness(pushed_at: DateTime<Utc>, now: DateTime<Utc>, config: &Config) -> Freshness {
+    let days = (now - pushed_at).num_days();
+    if days <= config.active_days {
+        Freshness::Active
+    } else if days > config.stale_days {
+        Freshness::Stale
+    } else {
+        Freshness::Maintained
+    }
+}
+
+/// Code counted towards the headline number. Without `HEADLINE_LANGUAGES`, that's everything but the
+/// `NON_PRIMARY_LANGUAGES`.
+pub fn headline_total(total: &[SimpleLanguage], config: &Config) -> usize {
+    total
+        .iter()
+        .filter(|lang| match &config.headline_languages {
+            Some(headline) => headline.contains(&lang.name),
+            None => !config.non_primary_languages.contains(&lang.name),
+        })
+        .map(|lang| lang.code)
+        .sum()
+}
+
+/// Keeps the first `max` of `langs` and folds the rest together, so `langs` should already be sorted
+pub fn fold_other_languages(langs: &mut Vec<SimpleLanguage>, max: usize) -> Option<OtherLanguages> {
+    if langs.len() <= max {
+        return None;
+    }
+
+    Some(fold(langs.drain(max..)))
+}
+
+fn fold(langs: impl IntoIterator<Item = SimpleLanguage>) -> OtherLanguages {
+    let mut other = OtherLanguages::default();
+    for lang in langs {
+        other.languages.push(lang.name);
+        other.code += lang.code;
+        other.blanks += lang.blanks;
+        other.comments += lang.comments;
+        other.files += lang.files;
+    }
+
+    other
+}
+
+/// Code written for contract work/internship, which doesn't live in any of my repos. Used without
+/// `MANUAL_ADJUSTMENTS`.
+pub const BUILT_IN_ADJUSTMENTS: &[(LanguageType, usize)] = &[
+    (LanguageType::Rust, 15673),
+    (LanguageType::TypeScript, 4333),
+    (LanguageType::Java, 4517),
+];
+
+/// Takes the languages with less than `percent` of the code out of `langs`, folding them together unless they're
+/// dropped
+pub fn apply_min_total_percent(
+    langs: &mut Vec<SimpleLanguage>,
+    percent: f64,
+    mode: MinTotalPercentMode,
+) -> Option<OtherLanguages> {
+    let total = total_code(langs);
+    if percent <= 0.0 || total == 0 {
+        return None;
+    }
+
+    let (kept, small) = std::mem::take(langs)
+        .into_iter()
+        .partition::<Vec<_>, _>(|lang| lang.code as f64 * 100.0 / total as f64 >= percent);
+    *langs = kept;
+    if small.is_empty() {
+        return None;
+    }
+
+    println!(
+        "{} languages are under MIN_TOTAL_PERCENT, {}: {}",
+        small.len(),
+        match mode {
+            MinTotalPercentMode::Fold => "folding them into other",
+            MinTotalPercentMode::Drop => "dropping them",
+        },
+        small
+            .iter()
+            .map(|lang| lang.name.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    (mode == MinTotalPercentMode::Fold).then(|| fold(small))
+}
+
+pub fn apply_manual_adjustments(
+    total: &mut Vec<SimpleLanguage>,
+    adjustments: &[(LanguageType, usize)],
+) {
+    for &(ty, code) in adjustments {
+        match total.iter_mut().find(|l| l.name == ty) {
+            Some(lang) => lang.code += code,
+            None => total.push(SimpleLanguage {
+                code,
+                files: 0,
+                ..SimpleLanguage::from_stats(&ty, &Default::default())
+            }),
+        }
+    }
+}
+
+pub fn display_name(ty: LanguageType, aliases: &BTreeMap<LanguageType, String>) -> String {
+    if let Some(alias) = aliases.get(&ty) {
+        return alias.clone();
+    }
+
+    DEFAULT_ALIASES
+        .iter()
+        .find(|(aliased, _)| *aliased == ty)
+        .map(|(_, alias)| alias.to_string())
+        .unwrap_or_else(|| ty.name().to_string())
+}
+
+/// `my-cool_project` as `My Cool Project`, for showing repo names
+pub fn prettify_name(name: &str) -> String {
+    name.split(['-', '_', ' '])
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Fills in the fields derived from the counts, once nothing is going to change them anymore.
+/// `name` stays the canonical language for everything else; `display_name` is only for showing.
+pub fn finalize(langs: &mut [SimpleLanguage], config: &Config) {
+    for lang in langs {
+        lang.display_name = Some(display_name(lang.name, &config.language_aliases));
+        lang.fill_derived();
+    }
+}
+
+/// Adds up the per-repo stats by `REPO_DOMAINS` domain, which `per_repo` is expected to be sorted and finalized
+/// for. Like the matrix, languages folded into `other` aren't in it.
+pub fn by_domain(
+    per_repo: impl IntoIterator<Item = impl Borrow<PerRepo>>,
+    config: &Config,
+) -> BTreeMap<String, Domain> {
+    let mut domains = BTreeMap::<String, Domain>::new();
+    for repo in per_repo {
+        let repo = repo.borrow();
+        let name = config
+            .repo_domains
+            .get(&repo.name)
+            .map_or(UNTAGGED_DOMAIN, String::as_str);
+        let domain = domains.entry(name.to_string()).or_default();
+        domain.repos.push(repo.name.clone());
+        domain.code += total_code(&repo.languages);
+        for lang in &repo.languages {
+            add_language(&mut domain.languages, lang.clone());
+        }
+    }
+
+    for domain in domains.values_mut() {
+        domain
+            .languages
+            .sort_unstable_by_key(|lang| (Reverse(lang.code), lang.name));
+        finalize(&mut domain.languages, config);
+    }
+    domains
+}
+
+/// Lays out the per-repo stats as a repo by language matrix. Languages folded into `other` aren't in it.
+pub fn matrix(per_repo: impl IntoIterator<Item = impl Borrow<PerRepo>>) -> Matrix {
+    // Only what the matrix needs of each repo, taken one at a time
+    let mut repos = per_repo
+        .into_iter()
+        .map(|repo| {
+            let repo = repo.borrow();
+            let languages = repo
+                .languages
+                .iter()
+                .map(|lang| (lang.name, lang.code))
+                .collect::<Vec<_>>();
+            (repo.name.clone(), total_code(&repo.languages), languages)
+        })
+        .collect::<Vec<_>>();
+    repos.sort_by_key(|&(_, code, _)| Reverse(code));
+
+    let mut language_totals = BTreeMap::<LanguageType, usize>::new();
+    for &(ty, code) in repos.iter().flat_map(|(_, _, languages)| languages) {
+        *language_totals.entry(ty).or_default() += code;
+    }
+    let mut languages = language_totals.into_iter().collect::<Vec<_>>();
+    languages.sort_by_key(|&(_, code)| Reverse(code));
+
+    let mut cells = Vec::new();
+    for (row, (_, _, repo_languages)) in repos.iter().enumerate() {
+        for (column, &(ty, _)) in languages.iter().enumerate() {
+            let code = repo_languages
+                .iter()
+                .filter(|&&(name, _)| name == ty)
+                .map(|&(_, code)| code)
+                .sum();
+            if code > 0 {
+                cells.push(MatrixCell {
+                    repo: row,
+                    language: column,
+                    code,
+                });
+            }
+        }
+    }
+
+    Matrix {
+        repo_totals: repos.iter().map(|&(_, code, _)| code).collect(),
+        repos: repos.into_iter().map(|(name, ..)| name).collect(),
+        languages: languages.iter().map(|&(ty, _)| ty).collect(),
+        language_totals: languages.iter().map(|&(_, code)| code).collect(),
+        cells,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn language(name: LanguageType, code: usize) -> SimpleLanguage {
+        SimpleLanguage {
+            code,
+            ..SimpleLanguage::from_stats(&name, &Default::default())
+        }
+    }
+
+    #[test]
+    fn aliases_are_pinned() {
+        let none = BTreeMap::new();
+        assert_eq!(display_name(LanguageType::Sh, &none), "Shell");
+        assert_eq!(display_name(LanguageType::Cpp, &none), "C++");
+        assert_eq!(display_name(LanguageType::Rust, &none), "Rust");
+
+        // `LANGUAGE_ALIASES` wins over the defaults, and only changes what's shown
+        let aliases = BTreeMap::from([(LanguageType::Sh, "Bash".to_string())]);
+        assert_eq!(display_name(LanguageType::Sh, &aliases), "Bash");
+        assert_eq!(display_name(LanguageType::Cpp, &aliases), "C++");
+
+        let mut langs = vec![SimpleLanguage::from_stats(
+            &LanguageType::Sh,
+            &Default::default(),
+        )];
+        let config = Config {
+            language_aliases: aliases,
+            ..crate::config::tests::config()
+        };
+        finalize(&mut langs, &config);
+        assert_eq!(langs[0].name, LanguageType::Sh);
+        assert_eq!(langs[0].display_name.as_deref(), Some("Bash"));
+    }
+
+    #[test]
+    fn json_doesnt_outweigh_rust_as_the_primary_language() {
+        let config = crate::config::tests::config();
+        let langs = [
+            language(LanguageType::Json, 5000),
+            language(LanguageType::Rust, 200),
+            language(LanguageType::Markdown, 300),
+        ];
+        assert_eq!(
+            primary_language(&langs, &config.non_primary_languages),
+            Some(LanguageType::Rust)
+        );
+        // Unless it's all there is
+        assert_eq!(
+            primary_language(&langs[..1], &config.non_primary_languages),
+            Some(LanguageType::Json)
+        );
+    }
+
+    #[test]
+    fn freshness_at_the_thresholds() {
+        let config = Config {
+            active_days: 30,
+            stale_days: 365,
+            ..crate::config::tests::config()
+        };
+        let now = Utc::now();
+        let pushed = |days| freshness(now - chrono::Duration::days(days), now, &config);
+
+        assert_eq!(pushed(0), Freshness::Active);
+        assert_eq!(pushed(29), Freshness::Active);
+        assert_eq!(pushed(30), Freshness::Active);
+        assert_eq!(pushed(31), Freshness::Maintained);
+        assert_eq!(pushed(364), Freshness::Maintained);
+        assert_eq!(pushed(365), Freshness::Maintained);
+        assert_eq!(pushed(366), Freshness::Stale);
+        // Only whole days count
+        assert_eq!(
+            freshness(now - chrono::Duration::hours(30 * 24 + 23), now, &config),
+            Freshness::Active
+        );
+        assert_eq!(serde_json::to_value(pushed(366)).unwrap(), "stale");
+    }
+
+    #[test]
+    fn repo_names_are_prettified() {
+        assert_eq!(prettify_name("my-cool-project"), "My Cool Project");
+        assert_eq!(prettify_name("my_cool_project"), "My Cool Project");
+        assert_eq!(prettify_name("My Cool Project"), "My Cool Project");
+        assert_eq!(prettify_name("github-me_v2"), "Github Me V2");
+        // Runs of separators don't leave empty words
+        assert_eq!(prettify_name("--my__project  "), "My Project");
+        assert_eq!(prettify_name("iOS-app"), "IOS App");
+    }
+
+    #[test]
+    fn tsx_code_is_weighted_when_combined() {
+        let config = Config {
+            tsx_code_weight: 0.5,
+            ..crate::config::tests::config()
+        };
+        let mut tsx = language(LanguageType::Tsx, 101);
+        tsx.blanks = 40;
+        tsx.comments = 10;
+        let mut langs = vec![language(LanguageType::TypeScript, 100), tsx];
+        combine_ts_tsx(&mut langs, &config);
+
+        assert_eq!(langs.len(), 1);
+        let ts = &langs[0];
+        assert_eq!(ts.name, LanguageType::TypeScript);
+        // Rounded, and only the code is weighted
+        assert_eq!(ts.code, 100 + 51);
+        assert_eq!((ts.blanks, ts.comments, ts.files), (40, 10, 2));
+    }
+
+    #[test]
+    fn tsx_is_weighted_without_any_typescript() {
+        let config = Config {
+            tsx_code_weight: 0.5,
+            ..crate::config::tests::config()
+        };
+        let mut langs = vec![
+            language(LanguageType::Rust, 10),
+            language(LanguageType::Tsx, 101),
+        ];
+        combine_ts_tsx(&mut langs, &config);
+
+        assert_eq!(langs.len(), 2);
+        assert_eq!(langs[1].name, LanguageType::TypeScript);
+        assert_eq!((langs[1].code, langs[1].files), (51, 1));
+        assert_eq!(langs[0].code, 10);
+    }
+
+    #[test]
+    fn tiny_languages_are_folded_or_dropped() {
+        let langs = || {
+            vec![
+                language(LanguageType::Rust, 990),
+                language(LanguageType::Go, 6),
+                language(LanguageType::Lua, 4),
+            ]
+        };
+        let names =
+            |langs: &[SimpleLanguage]| langs.iter().map(|lang| lang.name).collect::<Vec<_>>();
+
+        let mut folded = langs();
+        let other = apply_min_total_percent(&mut folded, 0.5, MinTotalPercentMode::Fold).unwrap();
+        // Go is 0.6%, Lua 0.4%
+        assert_eq!(names(&folded), [LanguageType::Rust, LanguageType::Go]);
+        assert_eq!(other.languages, [LanguageType::Lua]);
+        assert_eq!(other.code, 4);
+
+        let mut dropped = langs();
+        assert!(apply_min_total_percent(&mut dropped, 0.5, MinTotalPercentMode::Drop).is_none());
+        assert_eq!(names(&dropped), [LanguageType::Rust, LanguageType::Go]);
+
+        // Exactly at the threshold is kept, and 0 keeps everything
+        let mut kept = langs();
+        assert!(apply_min_total_percent(&mut kept, 0.4, MinTotalPercentMode::Fold).is_none());
+        assert!(apply_min_total_percent(&mut kept, 0.0, MinTotalPercentMode::Fold).is_none());
+        assert_eq!(kept.len(), 3);
+    }
+
+    #[test]
+    fn repos_are_aggregated_by_domain() {
+        let config = Config {
+            repo_domains: BTreeMap::from([
+                ("site".to_string(), "web".to_string()),
+                ("dashboard".to_string(), "web".to_string()),
+                ("tool".to_string(), "cli".to_string()),
+            ]),
+            ..crate::config::tests::config()
+        };
+        let repo = |name: &str, languages| PerRepo {
+            name: name.to_string(),
+            languages,
+            ..Default::default()
+        };
+        let per_repo = [
+            repo(
+                "dashboard",
+                vec![
+                    language(LanguageType::TypeScript, 300),
+                    language(LanguageType::Css, 50),
+                ],
+            ),
+            repo("site", vec![language(LanguageType::TypeScript, 100)]),
+            repo("tool", vec![language(LanguageType::Rust, 80)]),
+            repo("scratch", vec![language(LanguageType::Python, 5)]),
+        ];
+
+        let domains = by_domain(&per_repo, &config);
+        assert_eq!(
+            domains.keys().collect::<Vec<_>>(),
+            ["cli", "untagged", "web"]
+        );
+        let web = &domains["web"];
+        assert_eq!(web.repos, ["dashboard", "site"]);
+        assert_eq!(web.code, 450);
+        let web_languages = web
+            .languages
+            .iter()
+            .map(|lang| (lang.name, lang.code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            web_languages,
+            [(LanguageType::TypeScript, 400), (LanguageType::Css, 50)]
+        );
+        assert_eq!(web.languages[1].display_name.as_deref(), Some("CSS"));
+        assert_eq!(domains["cli"].code, 80);
+        assert_eq!(domains[UNTAGGED_DOMAIN].repos, ["scratch"]);
+    }
+
+    /// One of a few languages, with counts small enough that sums never overflow
+    fn counts() -> impl Strategy<Value = SimpleLanguage> {
+        let names = vec![LanguageType::Rust, LanguageType::Go, LanguageType::Json];
+        (
+            prop::sample::select(names),
+            0..1_000_000_usize,
+            0..1_000_000_usize,
+            0..1_000_000_usize,
+            0..1000_usize,
+        )
+            .prop_map(|(name, code, blanks, comments, files)| SimpleLanguage {
+                code,
+                blanks,
+                comments,
+                files,
+                ..language(name, 0)
+            })
+    }
+
+    fn totals<'a>(
+        langs: impl IntoIterator<Item = &'a SimpleLanguage>,
+    ) -> (usize, usize, usize, usize) {
+        langs
+            .into_iter()
+            .fold((0, 0, 0, 0), |(code, blanks, comments, files), lang| {
+                (
+                    code + lang.code,
+                    blanks + lang.blanks,
+                    comments + lang.comments,
+                    files + lang.files,
+                )
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn merging_is_commutative(a in counts(), b in counts()) {
+            let b = SimpleLanguage { name: a.name, ..b };
+            let mut ab = a.clone();
+            ab.merge(&b);
+            let mut ba = b.clone();
+            ba.merge(&a);
+            prop_assert_eq!(totals([&ab]), totals([&ba]));
+        }
+
+        #[test]
+        fn merging_preserves_totals(langs in prop::collection::vec(counts(), 0..20)) {
+            let mut merged = Vec::new();
+            for lang in langs.iter().cloned() {
+                add_language(&mut merged, lang);
+            }
+            // One entry per language, with everything of that language in it
+            for lang in &merged {
+                let same = langs.iter().filter(|l| l.name == lang.name);
+                prop_assert_eq!(totals([lang]), totals(same));
+            }
+            prop_assert_eq!(totals(&merged), totals(&langs));
+        }
+    }
+}
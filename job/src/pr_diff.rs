@@ -0,0 +1,183 @@
+use std::{cmp::Reverse, collections::BTreeMap, convert::Infallible, fmt::Write, path::Path};
+
+use gix::{
+    bstr::ByteSlice,
+    object::tree::diff::{change::Event, Action},
+    ObjectId,
+};
+use ignore::overrides::OverrideBuilder;
+use lambda_runtime::Error;
+use tokei::{CodeStats, LanguageType};
+
+use crate::{config::Config, post::display_name};
+
+/// How much one language changed between the base and head, negative when it went down
+#[derive(Debug, Default)]
+struct Delta {
+    code: i64,
+    comments: i64,
+    blanks: i64,
+    files: usize,
+}
+
+/// `PR_DIFF_MODE`, counts only the files changed between two refs of `LOCAL_DIR` and saves the change in each
+/// language as a markdown summary, for posting on pull requests. The refs come from the lambda event's `base` and
+/// `head` when it has them, otherwise from `PR_DIFF_BASE`/`PR_DIFF_HEAD`.
+pub async fn run(
+    config: &Config,
+    event: Option<&serde_json::Value>,
+    ignored: &[&str],
+    tokei_config: &tokei::Config,
+) -> Result<(), Error> {
+    let from_event = |name| {
+        event
+            .and_then(|event| event.get(name))
+            .and_then(|value| value.as_str())
+            .map(str::to_string)
+    };
+    let base = from_event("base")
+        .or_else(|| config.pr_diff_base.clone())
+        .ok_or("PR_DIFF_MODE needs a base ref, from PR_DIFF_BASE or the event's \"base\"")?;
+    let head = from_event("head").unwrap_or_else(|| config.pr_diff_head.clone());
+    let repo_path = config
+        .local_dir
+        .as_deref()
+        .ok_or("PR_DIFF_MODE needs LOCAL_DIR")?;
+
+    let deltas = diff(repo_path, &base, &head, ignored, tokei_config)?;
+    let summary = markdown(&base, &head, &deltas, config);
+    println!("{summary}");
+    common::save_object(common::PR_DIFF_OBJ_NAME, &summary).await?;
+
+    Ok(())
+}
+
+/// The change in each language from the files which differ between the trees of `base` and `head`
+fn diff(
+    repo_path: &Path,
+    base: &str,
+    head: &str,
+    ignored: &[&str],
+    tokei_config: &tokei::Config,
+) -> Result<BTreeMap<LanguageType, Delta>, Error> {
+    let repo = gix::open(repo_path)?;
+    let base_tree = repo.rev_parse_single(base)?.object()?.peel_to_tree()?;
+    let head_tree = repo.rev_parse_single(head)?.object()?.peel_to_tree()?;
+
+    let mut overrides = OverrideBuilder::new(repo_path);
+    for pattern in ignored {
+        overrides.add(&format!("!{pattern}"))?;
+    }
+    let overrides = overrides.build()?;
+
+    // Blobs before and after, by path
+    let mut changed = Vec::<(gix::bstr::BString, Option<ObjectId>, Option<ObjectId>)>::new();
+    base_tree
+        .changes()?
+        .track_path()
+        .track_rewrites(None)
+        .for_each_to_obtain_tree(&head_tree, |change| {
+            let (before, after) = match change.event {
+                Event::Addition { entry_mode, id } => {
+                    (None, entry_mode.is_blob().then(|| id.detach()))
+                }
+                Event::Deletion { entry_mode, id } => {
+                    (entry_mode.is_blob().then(|| id.detach()), None)
+                }
+                Event::Modification {
+                    previous_entry_mode,
+                    previous_id,
+                    entry_mode,
+                    id,
+                } => (
+                    previous_entry_mode.is_blob().then(|| previous_id.detach()),
+                    entry_mode.is_blob().then(|| id.detach()),
+                ),
+                // Not tracked, so renames are a deletion and an addition
+                Event::Rewrite { .. } => (None, None),
+            };
+            if before.is_some() || after.is_some() {
+                changed.push((change.location.to_owned(), before, after));
+            }
+            Ok::<_, Infallible>(Action::Continue)
+        })?;
+
+    let mut deltas = BTreeMap::<LanguageType, Delta>::new();
+    for (path, before, after) in changed {
+        // Only the path matters for picking the language, the file doesn't have to be checked out
+        let full_path = repo_path.join(path.to_path_lossy());
+        if overrides.matched(&full_path, false).is_ignore() {
+            continue;
+        }
+        let Some(ty) = LanguageType::from_path(&full_path, tokei_config) else {
+            continue;
+        };
+        if tokei_config
+            .types
+            .as_ref()
+            .is_some_and(|types| !types.contains(&ty))
+        {
+            continue;
+        }
+
+        let count = |id: Option<ObjectId>| -> Result<CodeStats, Error> {
+            Ok(match id {
+                Some(id) => ty.parse_from_slice(&repo.find_object(id)?.data, tokei_config),
+                None => CodeStats::default(),
+            })
+        };
+        let (before, after) = (count(before)?, count(after)?);
+        let delta = deltas.entry(ty).or_default();
+        delta.code += after.code as i64 - before.code as i64;
+        delta.comments += after.comments as i64 - before.comments as i64;
+        delta.blanks += after.blanks as i64 - before.blanks as i64;
+        delta.files += 1;
+    }
+
+    Ok(deltas)
+}
+
+/// A table of the deltas, biggest change in code first
+fn markdown(
+    base: &str,
+    head: &str,
+    deltas: &BTreeMap<LanguageType, Delta>,
+    config: &Config,
+) -> String {
+    let files = deltas.values().map(|delta| delta.files).sum::<usize>();
+    let mut summary = format!(
+        "### Lines of code\n\n`{base}`...`{head}`, {files} counted file{} changed\n",
+        if files == 1 { "" } else { "s" }
+    );
+    if deltas.is_empty() {
+        return summary;
+    }
+
+    let mut rows = deltas.iter().collect::<Vec<_>>();
+    rows.sort_by_key(|(_, delta)| Reverse(delta.code.unsigned_abs()));
+    summary.push_str(
+        "\n| Language | Code | Comments | Blanks | Files |\n| --- | ---: | ---: | ---: | ---: |\n",
+    );
+    for (&ty, delta) in rows {
+        writeln!(
+            summary,
+            "| {} | {:+} | {:+} | {:+} | {} |",
+            display_name(ty, &config.language_aliases),
+            delta.code,
+            delta.comments,
+            delta.blanks,
+            delta.files
+        )
+        .unwrap();
+    }
+    writeln!(
+        summary,
+        "| **Total** | **{:+}** | **{:+}** | **{:+}** | **{files}** |",
+        deltas.values().map(|delta| delta.code).sum::<i64>(),
+        deltas.values().map(|delta| delta.comments).sum::<i64>(),
+        deltas.values().map(|delta| delta.blanks).sum::<i64>(),
+    )
+    .unwrap();
+
+    summary
+}
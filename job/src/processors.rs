@@ -0,0 +1,81 @@
+use common::stats::{PerRepo, SimpleLanguage};
+
+use crate::{
+    config::{BuiltInProcessor, Config},
+    post::{apply_manual_adjustments, combine_ts_tsx},
+};
+
+/// A transform over the total and the per-repo stats, run in `POST_PROCESSORS` order at the start of
+/// post-processing, before anything is excluded, sorted or folded. With `STREAMING_SAVE` the total and each repo
+/// are transformed on their own, with the other side empty.
+pub trait PostProcessor {
+    fn transform(&self, total: &mut Vec<SimpleLanguage>, per_repo: &mut [PerRepo]);
+}
+
+/// Folds TSX into TypeScript, the stats other than the total and per-repo ones are combined when this runs
+struct CombineTsTsx<'a>(&'a Config);
+
+impl PostProcessor for CombineTsTsx<'_> {
+    fn transform(&self, total: &mut Vec<SimpleLanguage>, per_repo: &mut [PerRepo]) {
+        combine_ts_tsx(total, self.0);
+        for repo in per_repo {
+            combine_ts_tsx(&mut repo.languages, self.0);
+            for subproject in &mut repo.subprojects {
+                combine_ts_tsx(&mut subproject.languages, self.0);
+            }
+        }
+    }
+}
+
+/// The `MANUAL_ADJUSTMENTS` for code which can't be counted, only ever added to the total
+struct ManualAdjustments<'a> {
+    config: &'a Config,
+    enabled: bool,
+}
+
+impl<'a> ManualAdjustments<'a> {
+    fn new(config: &'a Config) -> Self {
+        let enabled = if config.disable_manual_adjustments {
+            println!("Manual adjustments are disabled, totals are purely measured");
+            false
+        } else if config.use_github_languages_api {
+            println!("Manual adjustments are in lines, not applying them to bytes");
+            false
+        } else {
+            true
+        };
+        Self { config, enabled }
+    }
+}
+
+impl PostProcessor for ManualAdjustments<'_> {
+    fn transform(&self, total: &mut Vec<SimpleLanguage>, _per_repo: &mut [PerRepo]) {
+        if self.enabled {
+            apply_manual_adjustments(total, &self.config.manual_adjustments);
+        }
+    }
+}
+
+/// The `POST_PROCESSORS`, in order
+pub fn built_in(config: &Config) -> Vec<Box<dyn PostProcessor + '_>> {
+    config
+        .post_processors
+        .iter()
+        .map(|processor| -> Box<dyn PostProcessor> {
+            match processor {
+                BuiltInProcessor::CombineTsTsx => Box::new(CombineTsTsx(config)),
+                BuiltInProcessor::ManualAdjustments => Box::new(ManualAdjustments::new(config)),
+            }
+        })
+        .collect()
+}
+
+pub fn run(
+    processors: &[Box<dyn PostProcessor + '_>],
+    total: &mut Vec<SimpleLanguage>,
+    per_repo: &mut [PerRepo],
+) {
+    for processor in processors {
+        processor.transform(total, per_repo);
+    }
+}
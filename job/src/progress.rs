@@ -0,0 +1,35 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// Counts repos as they're done, from any of the repo threads, and logs how long the rest should take
+pub struct Progress {
+    total: usize,
+    completed: AtomicUsize,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            completed: AtomicUsize::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// The estimate is the wall time per repo so far, which already accounts for repos being processed at once,
+    /// times the repos left. It gets better as more repos are done.
+    pub fn complete_one(&self) {
+        // Each call gets its own count, even when the logs come out of order
+        let completed = self.completed.fetch_add(1, Ordering::Relaxed) + 1;
+        let remaining = self.total.saturating_sub(completed);
+        let per_repo = self.start.elapsed().as_secs_f64() / completed as f64;
+        println!(
+            "Completed {completed}/{} repos, estimated {:.0} seconds remaining",
+            self.total,
+            per_repo * remaining as f64
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::{fs, path::Path};
+
+/// Descriptions taken from READMEs are cut to about this many characters
+const MAX_LEN: usize = 200;
+
+/// A description for a repo without one, from the first paragraph of its README, or its first heading when it
+/// has nothing but headings. Markdown formatting is stripped so it reads as plain text.
+pub fn description(repo_path: &Path) -> Option<String> {
+    let readme = fs::read_dir(repo_path)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|ty| ty.is_file()))
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_stem()
+                .is_some_and(|stem| stem.eq_ignore_ascii_case("readme"))
+        })
+        .min()?;
+    let readme = fs::read_to_string(readme).ok()?;
+
+    let mut heading = None;
+    let mut paragraph = Vec::new();
+    let mut in_code_block = false;
+    for line in readme.lines().map(str::trim) {
+        if line.starts_with("```") || line.starts_with("~~~") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+
+        if line.is_empty() {
+            if !paragraph.is_empty() {
+                break;
+            }
+        } else if let Some(text) = line.strip_prefix('#') {
+            if !paragraph.is_empty() {
+                break;
+            }
+            heading.get_or_insert_with(|| text.trim_start_matches('#').trim().to_string());
+        } else if !is_decoration(line) {
+            paragraph.push(line);
+        }
+    }
+
+    let text = if paragraph.is_empty() {
+        heading?
+    } else {
+        paragraph.join(" ")
+    };
+    let text = plain_text(&text);
+    (!text.is_empty()).then(|| truncate(text))
+}
+
+/// Badges, images, html, horizontal rules and the like, which make for a bad description
+fn is_decoration(line: &str) -> bool {
+    line.starts_with("[![")
+        || line.starts_with("![")
+        || line.starts_with('<')
+        || line.starts_with('|')
+        || line
+            .chars()
+            .all(|c| matches!(c, '-' | '=' | '*' | '_' | ' '))
+}
+
+/// Keeps the text of links and drops emphasis and code markers
+fn plain_text(markdown: &str) -> String {
+    let mut text = String::with_capacity(markdown.len());
+    let mut chars = markdown.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '`' => {}
+            // The link's text is kept as it goes by, only its target has to be skipped
+            '[' => {}
+            ']' if chars.peek() == Some(&'(') => {
+                for c in chars.by_ref() {
+                    if c == ')' {
+                        break;
+                    }
+                }
+            }
+            ']' => {}
+            c if c.is_control() => {}
+            c => text.push(c),
+        }
+    }
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn truncate(text: String) -> String {
+    if text.chars().count() <= MAX_LEN {
+        return text;
+    }
+
+    let cut = text.chars().take(MAX_LEN).collect::<String>();
+    // Don't end halfway through a word
+    let cut = cut
+        .rsplit_once(' ')
+        .map_or(cut.as_str(), |(start, _)| start);
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
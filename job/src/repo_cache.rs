@@ -0,0 +1,313 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use common::{
+    stats::{EmptyReason, SimpleLanguage, Subproject},
+    ObjectInfo, REPO_CACHE_PREFIX,
+};
+use futures_util::{stream, StreamExt};
+use octocrab::models;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Objects read or written at once
+const CONCURRENT_REQUESTS: usize = 8;
+
+/// Everything counted in one repo at one commit, before any post-processing
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Analysis {
+    pub languages: Vec<SimpleLanguage>,
+    pub test: Vec<SimpleLanguage>,
+    pub source: Vec<SimpleLanguage>,
+    /// Only with `BY_YEAR`
+    pub by_year: BTreeMap<i32, Vec<SimpleLanguage>>,
+    /// Only with `SNAPSHOT_SINCE`/`SNAPSHOT_UNTIL`
+    pub dated_total: Vec<SimpleLanguage>,
+    /// Only when no code was counted
+    pub empty: Option<EmptyReason>,
+    /// Only for repos in `MONOREPO_SUBDIRS`
+    pub subprojects: Vec<Subproject>,
+    /// Only with `README_DESCRIPTION_FALLBACK`
+    pub readme_description: Option<String>,
+    /// From `FRAMEWORK_SIGNATURES`
+    #[serde(default)]
+    pub primary_framework: Option<String>,
+}
+
+/// The commit a repo was counted at, and when it had last been pushed to then
+#[derive(Debug, Serialize, Deserialize)]
+struct Pushed {
+    pushed_at: DateTime<Utc>,
+    sha: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// [`settings`] of the run which counted it, the counts are only reused by runs with the same ones
+    settings: String,
+    analysis: Analysis,
+}
+
+/// `CACHE_PER_REPO_RESULTS`, analyses of repos by commit so a commit is only ever counted once, even when a
+/// repo goes back to an earlier one. Loaded before the repos are processed and saved after, so the repo
+/// threads never wait on the bucket.
+#[derive(Default)]
+pub struct RepoCache {
+    enabled: bool,
+    settings: String,
+    cached: Mutex<BTreeMap<String, Analysis>>,
+    /// Counted by this run, by key, waiting to be saved
+    fresh: Mutex<Vec<(String, String)>>,
+    /// Only with `SKIP_UNPUSHED_REPOS`, from the last run, by repo
+    pushed_before: Mutex<BTreeMap<String, Pushed>>,
+    /// Every repo this run knows the commit of, replacing `pushed_before` once saved
+    pushed_now: Mutex<BTreeMap<String, Pushed>>,
+}
+
+impl RepoCache {
+    /// The cached analyses of the repos about to be processed, leaving out any past `CACHE_TTL_DAYS` or counted
+    /// with different settings
+    pub async fn load(config: &Config, repos: &[models::Repository]) -> Self {
+        if !config.cache_per_repo_results {
+            return Self::default();
+        }
+
+        let cache = Self {
+            enabled: true,
+            settings: settings(config),
+            ..Default::default()
+        };
+        let objects = match common::list_objects(REPO_CACHE_PREFIX).await {
+            Ok(objects) => objects,
+            Err(e) => {
+                eprintln!("Failed to list the cached repo results, counting every repo: {e}");
+                return cache;
+            }
+        };
+
+        let names = repos
+            .iter()
+            .map(|repo| repo.name.as_str())
+            .collect::<BTreeSet<_>>();
+        let oldest = Utc::now() - Duration::days(config.cache_ttl_days as i64);
+        let wanted = objects.into_iter().filter(|object| {
+            object.last_modified >= oldest
+                && parse_key(&object.key).is_some_and(|(repo, _)| names.contains(repo))
+        });
+        let entries = stream::iter(wanted)
+            .map(|object| async move {
+                let entry = common::get_object(&object.key)
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|bytes| {
+                        serde_json::from_slice::<Entry>(&bytes).map_err(|e| e.to_string())
+                    });
+                (object.key, entry)
+            })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .collect::<Vec<_>>()
+            .await;
+
+        if config.skip_unpushed_repos {
+            match common::get_object(common::REPO_PUSHED_OBJ_NAME).await {
+                Ok(bytes) => match serde_json::from_slice(&bytes) {
+                    Ok(pushed) => *cache.pushed_before.lock().unwrap() = pushed,
+                    Err(e) => eprintln!(
+                        "Ignoring {}, cloning every repo: {e}",
+                        common::REPO_PUSHED_OBJ_NAME
+                    ),
+                },
+                Err(e) => println!(
+                    "No {} yet, cloning every repo: {e}",
+                    common::REPO_PUSHED_OBJ_NAME
+                ),
+            }
+        }
+
+        let mut cached = cache.cached.lock().unwrap();
+        for (key, entry) in entries {
+            match entry {
+                Ok(entry) if entry.settings == cache.settings => {
+                    cached.insert(key, entry.analysis);
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Ignoring the cached repo result {key}: {e}"),
+            }
+        }
+        println!("Loaded {} cached repo results", cached.len());
+        drop(cached);
+
+        cache
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Each repo is only processed once per run, so its analysis is handed out rather than copied
+    pub fn take(&self, repo: &str, sha: &str) -> Option<Analysis> {
+        self.cached.lock().unwrap().remove(&key(repo, sha))
+    }
+
+    /// `SKIP_UNPUSHED_REPOS`, the commit and cached analysis of a repo which GitHub says hasn't been pushed to
+    /// since it was counted, so it doesn't need cloning to know it's at the same commit
+    pub fn take_unpushed(&self, repo: &models::Repository) -> Option<(String, Analysis)> {
+        let pushed_at = repo.pushed_at?;
+        let sha = {
+            let pushed_before = self.pushed_before.lock().unwrap();
+            let before = pushed_before.get(&repo.name)?;
+            if before.pushed_at != pushed_at {
+                return None;
+            }
+            before.sha.clone()
+        };
+        let analysis = self.take(&repo.name, &sha)?;
+        self.record_pushed(repo, &sha);
+        Some((sha, analysis))
+    }
+
+    /// Remembers the commit a repo is at, as of when it was last pushed to
+    pub fn record_pushed(&self, repo: &models::Repository, sha: &str) {
+        if let Some(pushed_at) = repo.pushed_at {
+            self.pushed_now.lock().unwrap().insert(
+                repo.name.clone(),
+                Pushed {
+                    pushed_at,
+                    sha: sha.to_string(),
+                },
+            );
+        }
+    }
+
+    pub fn insert(&self, repo: &str, sha: &str, analysis: &Analysis) {
+        if !self.enabled {
+            return;
+        }
+
+        let entry = serde_json::json!({ "settings": self.settings, "analysis": analysis });
+        self.fresh
+            .lock()
+            .unwrap()
+            .push((key(repo, sha), entry.to_string()));
+    }
+
+    /// Saves what this run counted, then deletes the entries past `CACHE_TTL_DAYS` and the oldest written past
+    /// `CACHE_MAX_ENTRIES`. Failing to keep the cache never fails the run.
+    pub async fn save(self, config: &Config) {
+        if !self.enabled {
+            return;
+        }
+
+        let fresh = self.fresh.into_inner().unwrap();
+        let saved = stream::iter(fresh)
+            .map(|(key, entry)| async move {
+                let saved = common::save_object(&key, &entry).await;
+                if let Err(e) = &saved {
+                    eprintln!("Failed to cache the repo result {key}: {e}");
+                }
+                saved.is_ok()
+            })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .collect::<Vec<_>>()
+            .await;
+        println!(
+            "Cached {} new repo results",
+            saved.into_iter().filter(|&saved| saved).count()
+        );
+
+        if config.skip_unpushed_repos {
+            let pushed = self.pushed_now.into_inner().unwrap();
+            if let Err(e) = common::save_object(
+                common::REPO_PUSHED_OBJ_NAME,
+                &serde_json::to_string(&pushed).unwrap(),
+            )
+            .await
+            {
+                eprintln!("Failed to save {}: {e}", common::REPO_PUSHED_OBJ_NAME);
+            }
+        }
+
+        let mut objects = match common::list_objects(REPO_CACHE_PREFIX).await {
+            Ok(objects) => objects,
+            Err(e) => {
+                eprintln!("Failed to list the cached repo results, not pruning them: {e}");
+                return;
+            }
+        };
+        objects.sort_unstable_by_key(|object| Reverse(object.last_modified));
+        let oldest = Utc::now() - Duration::days(config.cache_ttl_days as i64);
+        let pruned = objects
+            .into_iter()
+            .enumerate()
+            .filter(|(i, object)| *i >= config.cache_max_entries || object.last_modified < oldest)
+            .map(|(_, ObjectInfo { key, .. })| key)
+            .collect::<Vec<_>>();
+        if pruned.is_empty() {
+            return;
+        }
+
+        let count = pruned.len();
+        stream::iter(pruned)
+            .map(|key| async move {
+                if let Err(e) = common::delete_object(&key).await {
+                    eprintln!("Failed to delete the cached repo result {key}: {e}");
+                }
+            })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .count()
+            .await;
+        println!("Pruned {count} cached repo results");
+    }
+}
+
+fn key(repo: &str, sha: &str) -> String {
+    format!("{REPO_CACHE_PREFIX}{repo}@{sha}.json")
+}
+
+/// The repo and commit of a key, repo names can't have an `@` so the last one splits them
+fn parse_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(REPO_CACHE_PREFIX)?
+        .strip_suffix(".json")?
+        .rsplit_once('@')
+}
+
+/// Fingerprint of every setting that changes what's counted in a repo, and the version of the job since the
+/// counting itself can change between them. FNV-1a, which unlike std's hasher is the same across builds.
+fn settings(config: &Config) -> String {
+    let settings = format!(
+        "{} {:?} {} {:?} {:?} {:?} {:?} {:?} {} {:?} {} {} {:?} {:?} {} {:?} {} {:?} {:?} {:?} {:?} {}",
+        env!("CARGO_PKG_VERSION"),
+        config.lfs_handling,
+        config.respect_gitignore,
+        config.test_patterns,
+        config.extension_overrides,
+        config.recent_commits_only,
+        config.per_file_line_cap,
+        config.skip_binary_dirs,
+        config.by_year,
+        (config.snapshot_since, config.snapshot_until),
+        config.readme_description_fallback,
+        config.separate_docs,
+        config.docs_languages,
+        config.monorepo_subdirs,
+        config.normalize_line_endings,
+        config
+            .fallback_encodings
+            .iter()
+            .map(|encoding| encoding.name())
+            .collect::<Vec<_>>(),
+        config.drop_largest_file_per_language,
+        config.only_paths,
+        config.framework_signatures,
+        config.languages,
+        config.exclude_paths,
+        config.all_branches,
+    );
+
+    format!("{:016x}", common::fnv1a(settings.as_bytes()))
+}
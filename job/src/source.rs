@@ -0,0 +1,281 @@
+use std::{future::Future, time::Duration};
+
+use octocrab::{models, Octocrab};
+use serde::Deserialize;
+
+use crate::config::Config;
+
+/// A repo given explicitly through `REPO_LIST_FILE`/`REPO_LIST_JSON` instead of listed from GitHub
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListedRepo {
+    pub name: String,
+    pub clone_url: String,
+    /// Shown in the per-repo stats, the clone url is used when missing
+    #[serde(default)]
+    pub href: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Counted in the totals but left out of the per-repo stats
+    #[serde(default)]
+    pub private: bool,
+}
+
+impl ListedRepo {
+    /// Problem with the entry which would stop it from being cloned
+    pub fn validate(&self) -> Result<(), String> {
+        gix::Url::from_bytes(self.clone_url.as_str().into())
+            .map_err(|e| format!("\"{}\" has invalid clone_url: {e}", self.name))?;
+        self.clone_url
+            .parse::<url::Url>()
+            .map_err(|e| format!("\"{}\" has invalid clone_url: {e}", self.name))?;
+        if let Some(href) = &self.href {
+            href.parse::<url::Url>()
+                .map_err(|e| format!("\"{}\" has invalid href: {e}", self.name))?;
+        }
+
+        Ok(())
+    }
+
+    /// The rest of the job works with GitHub's repo model, so fill in the parts of it which are used
+    fn into_repository(self) -> Result<models::Repository, serde_json::Error> {
+        let href = self.href.unwrap_or_else(|| self.clone_url.clone());
+        serde_json::from_value(serde_json::json!({
+            "id": 0,
+            "name": self.name,
+            "url": self.clone_url,
+            "clone_url": self.clone_url,
+            "html_url": href,
+            "description": self.description,
+            "private": self.private,
+        }))
+    }
+}
+
+/// Repos to analyze, from the static list when one is configured and GitHub otherwise
+pub async fn list_repos(
+    config: &Config,
+    github: &Octocrab,
+) -> Result<Vec<models::Repository>, lambda_runtime::Error> {
+    if let Some(dir) = &config.local_dir {
+        let name = dir.file_name().map_or_else(
+            || "local".to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        println!(
+            "Analyzing the local directory {} as \"{name}\"",
+            dir.display()
+        );
+        let repo = ListedRepo {
+            name,
+            clone_url: url::Url::from_directory_path(dir)
+                .map_err(|()| format!("LOCAL_DIR {} isn't absolute", dir.display()))?
+                .to_string(),
+            href: None,
+            description: None,
+            private: false,
+        };
+        return Ok(vec![repo.into_repository()?]);
+    }
+
+    match &config.repo_list {
+        Some(list) => {
+            println!("Using the {} repos from the static repo list", list.len());
+            Ok(list
+                .iter()
+                .cloned()
+                .map(ListedRepo::into_repository)
+                .collect::<Result<_, _>>()?)
+        }
+        None => list_github_repos(config, github).await,
+    }
+}
+
+#[derive(Deserialize)]
+struct Topics {
+    names: Vec<String>,
+}
+
+/// The repo listing usually includes topics already, anything it left out is fetched one repo at a time
+pub async fn fill_missing_topics(
+    github: &Octocrab,
+    repos: &mut [models::Repository],
+    config: &Config,
+) {
+    for repo in repos.iter_mut().filter(|repo| repo.topics.is_none()) {
+        let Some(owner) = repo.owner.as_ref().map(|owner| owner.login.clone()) else {
+            continue;
+        };
+
+        match github
+            .get::<Topics, _, ()>(format!("/repos/{owner}/{}/topics", repo.name), None)
+            .await
+        {
+            Ok(topics) => repo.topics = Some(topics.names),
+            Err(e) => eprintln!(
+                "Failed to get topics of \"{}\": {e}",
+                config.repo_label(repo)
+            ),
+        }
+    }
+}
+
+async fn list_github_repos(
+    config: &Config,
+    github: &Octocrab,
+) -> Result<Vec<models::Repository>, lambda_runtime::Error> {
+    let mut page = with_retries(config, "list repos", || {
+        github
+            .current()
+            .list_repos_for_authenticated_user()
+            .affiliation("owner")
+            .direction("desc")
+            .sort("updated")
+            .send()
+    })
+    .await?;
+
+    let mut repos =
+        Vec::with_capacity(page.items.len() * page.number_of_pages().unwrap_or(1) as usize);
+
+    loop {
+        for repo in &page {
+            if !repo.fork.is_some_and(|f| f) {
+                repos.push(repo.clone());
+            }
+        }
+
+        page = match with_retries(config, "get the next page of repos", || {
+            github.get_page::<models::Repository>(&page.next)
+        })
+        .await
+        {
+            Ok(Some(next_page)) => next_page,
+            Ok(None) => break,
+            Err(e) if config.continue_on_pagination_error => {
+                eprintln!(
+                    "Failed to get next page of repos, continuing with the {} already listed: {e}",
+                    repos.len()
+                );
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(repos)
+}
+
+/// Tries `request` up to `GITHUB_MAX_RETRIES` more times, backing off exponentially, while it fails with an error
+/// which could be gone on the next try. octocrab already retries those errors a few times on its own, but right
+/// away, which doesn't help with anything lasting longer than a blip.
+pub async fn with_retries<T, F, Fut>(
+    config: &Config,
+    what: &str,
+    mut request: F,
+) -> octocrab::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = octocrab::Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(e) if retries < config.github_max_retries && is_transient(&e) => {
+                retries += 1;
+                eprintln!(
+                    "Failed to {what}, retry {retries} of {}: {e}",
+                    config.github_max_retries
+                );
+                tokio::time::sleep(Duration::from_secs(2u64.pow(retries - 1))).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Server errors, rate limits and network errors, but not anything about the request or token
+fn is_transient(e: &octocrab::Error) -> bool {
+    match e {
+        octocrab::Error::GitHub { source, .. } => {
+            source.status_code.is_server_error() || source.status_code.as_u16() == 429
+        }
+        octocrab::Error::Hyper { .. }
+        | octocrab::Error::Service { .. }
+        | octocrab::Error::Http { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{backtrace::Backtrace, cell::Cell};
+
+    use super::*;
+
+    fn config(github_max_retries: u32) -> Config {
+        Config {
+            github_max_retries,
+            ..crate::config::tests::config()
+        }
+    }
+
+    /// Like a dropped connection
+    fn transient() -> octocrab::Error {
+        octocrab::Error::Service {
+            source: "connection reset".into(),
+            backtrace: Backtrace::disabled(),
+        }
+    }
+
+    fn not_transient() -> octocrab::Error {
+        octocrab::Error::Other {
+            source: "bad credentials".into(),
+            backtrace: Backtrace::disabled(),
+        }
+    }
+
+    /// Fails with each of `failures` in turn, then succeeds with how many tries it took
+    async fn mock(
+        config: &Config,
+        mut failures: Vec<octocrab::Error>,
+    ) -> (octocrab::Result<usize>, usize) {
+        failures.reverse();
+        let tries = Cell::new(0);
+        let result = with_retries(config, "test", || {
+            tries.set(tries.get() + 1);
+            let result = failures.pop().map_or(Ok(tries.get()), Err);
+            async move { result }
+        })
+        .await;
+        (result, tries.get())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_errors_are_retried_with_backoff() {
+        let start = tokio::time::Instant::now();
+        let (result, tries) = mock(&config(3), vec![transient()]).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(tries, 2);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        let start = tokio::time::Instant::now();
+        let (result, tries) = mock(&config(3), vec![transient(), transient()]).await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(tries, 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_run_out() {
+        let (result, tries) = mock(&config(1), vec![transient(), transient()]).await;
+        assert!(result.is_err());
+        assert_eq!(tries, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_errors_arent_retried() {
+        let (result, tries) = mock(&config(3), vec![not_transient()]).await;
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    sync::Mutex,
+};
+
+use common::stats::PerRepo;
+use lambda_runtime::Error;
+use serde::{Serialize, Serializer};
+
+/// How much of the serialized per-repo stats is handed to the store at a time
+const UPLOAD_CHUNK: usize = 1024 * 1024;
+
+/// `STREAMING_SAVE`, keeps the per-repo stats on disk from when each repo finishes until they've been saved, so
+/// they're never all in memory at once. Every file is an anonymous temporary one, gone once it's dropped.
+pub struct Spill {
+    /// A JSON line each, as each repo finishes
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Spill {
+    pub fn create() -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(tempfile::tempfile()?)),
+        })
+    }
+
+    pub fn push(&self, repo: &PerRepo) -> io::Result<()> {
+        let mut line = serde_json::to_vec(repo)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+    }
+
+    /// Post-processes the repos one at a time with `process`, which gives back the code they're sorted by, then
+    /// saves the first `max_entries` as the per-repo stats, each serialized as what `element` gives back. The output
+    /// is the same as serializing all of them at once would give. The post-processed repos are kept on disk for
+    /// the rest of the stats, read back one at a time in the same order by [`Saved::with_repos`].
+    pub async fn save<E: Serialize>(
+        self,
+        max_entries: usize,
+        pretty: bool,
+        mut process: impl FnMut(&mut PerRepo) -> usize,
+        element: impl Fn(PerRepo) -> E,
+    ) -> Result<Saved, Error> {
+        let mut spill = self.file.into_inner().unwrap().into_inner()?;
+        spill.rewind()?;
+
+        // The name is only kept while sorting, to break ties like the per-repo stats are sorted without
+        // `STREAMING_SAVE`
+        let mut index = Vec::new();
+        let mut processed = BufWriter::new(tempfile::tempfile()?);
+        let mut offset = 0;
+        for line in BufReader::new(spill).lines() {
+            let mut repo = serde_json::from_str::<PerRepo>(&line?)?;
+            let code = process(&mut repo);
+            let mut line = serde_json::to_vec(&repo)?;
+            line.push(b'\n');
+            processed.write_all(&line)?;
+            index.push((offset, code, repo.name));
+            offset += line.len() as u64;
+        }
+        index.sort_unstable_by(|a, b| (Reverse(a.1), &a.2).cmp(&(Reverse(b.1), &b.2)));
+        let saved = Saved {
+            file: processed.into_inner()?,
+            offsets: index.into_iter().map(|(offset, ..)| offset).collect(),
+        };
+
+        // Serialized to disk first, since serde writes synchronously and the store is written to asynchronously
+        let mut json = BufWriter::new(tempfile::tempfile()?);
+        saved.with_repos(|repos| {
+            let repos = Elements(RefCell::new(repos.take(max_entries).map(&element)));
+            if pretty {
+                serde_json::to_writer_pretty(&mut json, &repos)
+            } else {
+                serde_json::to_writer(&mut json, &repos)
+            }
+        })??;
+        let mut json = json.into_inner()?;
+        json.rewind()?;
+
+        let mut writer = common::ObjectWriter::new(common::PER_REPO_OBJ_NAME).await?;
+        let mut chunk = vec![0; UPLOAD_CHUNK];
+        loop {
+            let read = json.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            writer.write(&chunk[..read]).await?;
+        }
+        writer.finish().await?;
+
+        Ok(saved)
+    }
+}
+
+/// The post-processed per-repo stats of [`Spill::save`], every repo including those left out of the saved ones,
+/// by most code
+pub struct Saved {
+    file: File,
+    /// Of each repo's line in `file`, in order
+    offsets: Vec<u64>,
+}
+
+impl Saved {
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Gives `read` the repos one at a time, stopping at the first one which can't be read back, and then fails
+    /// with why
+    pub fn with_repos<T>(
+        &self,
+        read: impl FnOnce(&mut dyn Iterator<Item = PerRepo>) -> T,
+    ) -> Result<T, Error> {
+        let mut file = BufReader::new(&self.file);
+        let mut failed = None;
+        let mut line = String::new();
+        let mut repos = self.offsets.iter().map_while(|&offset| {
+            let repo = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| {
+                    line.clear();
+                    file.read_line(&mut line)
+                })
+                .map_err(Error::from)
+                .and_then(|_| Ok(serde_json::from_str::<PerRepo>(&line)?));
+            repo.map_err(|e| failed = Some(e)).ok()
+        });
+
+        let read = read(&mut repos);
+        drop(repos);
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(read),
+        }
+    }
+}
+
+/// Serializes like a `Vec` of the elements, taking them from the iterator as it goes
+struct Elements<I>(RefCell<I>);
+
+impl<I: Iterator<Item = E>, E: Serialize> Serialize for Elements<I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(&mut *self.0.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(name: &str) -> PerRepo {
+        PerRepo {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn elements_serialize_like_a_vec() {
+        for repos in [vec![], vec![repo("a")], vec![repo("a"), repo("b")]] {
+            let streamed = Elements(RefCell::new(repos.iter()));
+            assert_eq!(
+                serde_json::to_string_pretty(&streamed).unwrap(),
+                serde_json::to_string_pretty(&repos).unwrap()
+            );
+            let streamed = Elements(RefCell::new(repos.iter()));
+            assert_eq!(
+                serde_json::to_string(&streamed).unwrap(),
+                serde_json::to_string(&repos).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn saved_repos_are_read_back_in_order() {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        for name in ["first", "second", "third"] {
+            let line = format!("{}\n", serde_json::to_string(&repo(name)).unwrap());
+            offsets.push(offset);
+            offset += line.len() as u64;
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        offsets.reverse();
+        let saved = Saved { file, offsets };
+
+        let names = saved
+            .with_repos(|repos| repos.map(|repo| repo.name).collect::<Vec<_>>())
+            .unwrap();
+        assert_eq!(names, ["third", "second", "first"]);
+        // Can be read again for the next stats
+        assert_eq!(saved.with_repos(|repos| repos.count()).unwrap(), 3);
+    }
+
+    #[test]
+    fn unreadable_repos_fail_the_read() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"not json\n").unwrap();
+        let saved = Saved {
+            file,
+            offsets: vec![0],
+        };
+        assert!(saved.with_repos(|repos| repos.count()).is_err());
+    }
+}
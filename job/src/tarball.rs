@@ -0,0 +1,132 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use flate2::read::GzDecoder;
+use http_body_util::BodyExt;
+use lambda_runtime::Error;
+use octocrab::{models, Octocrab};
+use tar::{Archive, EntryType};
+use tokio::runtime::Handle;
+
+use crate::{config::Config, source::with_retries};
+
+/// Downloads repos with `GET /repos/{owner}/{repo}/tarball` instead of cloning them. Made on the runtime and
+/// handed to the rayon threads, which can't `.await` the download themselves.
+pub struct Downloader {
+    github: Arc<Octocrab>,
+    runtime: Handle,
+}
+
+impl Downloader {
+    /// Must be made inside the runtime, and only used from threads outside of it
+    pub fn new(github: &Arc<Octocrab>) -> Self {
+        Self {
+            github: github.clone(),
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Extracts the default branch into `repo_path`, without the top directory GitHub wraps it in, returning the
+    /// commit it's at. The archive is streamed to disk next to `repo_path` and deleted once it's extracted.
+    pub fn download(
+        &self,
+        repo: &models::Repository,
+        repo_path: &str,
+        config: &Config,
+    ) -> Result<String, Error> {
+        let archive_path = PathBuf::from(format!("{repo_path}.tar.gz"));
+        let downloaded = self
+            .runtime
+            .block_on(self.download_archive(repo, &archive_path, config));
+        let extracted = downloaded.and_then(|()| extract(&archive_path, Path::new(repo_path)));
+        fs::remove_file(&archive_path).ok();
+        extracted
+    }
+
+    async fn download_archive(
+        &self,
+        repo: &models::Repository,
+        archive_path: &Path,
+        config: &Config,
+    ) -> Result<(), Error> {
+        let owner = repo
+            .owner
+            .as_ref()
+            .map(|owner| &owner.login)
+            .ok_or("repo has no owner to download it from")?;
+        let reference = repo.default_branch.as_deref().unwrap_or("HEAD");
+        let res = with_retries(config, "download a tarball", || async {
+            self.github
+                .repos(owner, &repo.name)
+                .download_tarball(reference.to_string())
+                .await
+        })
+        .await?;
+        if !res.status().is_success() {
+            return Err(format!("GitHub responded with {} to the download", res.status()).into());
+        }
+
+        let mut body = res.into_body();
+        let mut file = BufWriter::new(File::create(archive_path)?);
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame?.into_data() {
+                file.write_all(&data)?;
+            }
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Unpacks the files of the archive under `repo_path`. Symlinks are left out like tokei would skip them in a
+/// clone, and so is anything which would land outside of `repo_path`.
+fn extract(archive_path: &Path, repo_path: &Path) -> Result<String, Error> {
+    let mut archive = Archive::new(GzDecoder::new(File::open(archive_path)?));
+    let mut sha = None;
+    fs::create_dir_all(repo_path)?;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        match entry.header().entry_type() {
+            // `git archive` puts the commit in the comment of the global header
+            EntryType::XGlobalHeader => {
+                for extension in entry.pax_extensions()?.into_iter().flatten() {
+                    let extension = extension?;
+                    if extension.key() == Ok("comment") {
+                        sha = extension.value().ok().map(|value| value.trim().to_string());
+                    }
+                }
+            }
+            EntryType::Regular | EntryType::Directory => {
+                let path = entry.path()?;
+                // Everything is under `{owner}-{repo}-{short sha}/`
+                let mut components = path.components();
+                components.next();
+                let relative = components.as_path();
+                if relative
+                    .components()
+                    .any(|component| !matches!(component, Component::Normal(_)))
+                {
+                    continue;
+                }
+
+                let target = repo_path.join(relative);
+                if entry.header().entry_type() == EntryType::Directory {
+                    fs::create_dir_all(&target)?;
+                } else {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    entry.unpack(&target)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    sha.ok_or_else(|| "the tarball doesn't say which commit it's of".into())
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use crate::config::Config;
+
+const ATTEMPTS: u32 = 3;
+
+/// Pushes the fresh totals to `RESULT_WEBHOOK_URL`. The stats are already saved by the time this runs,
+/// so failures are only logged.
+pub async fn send_totals(config: &Config, total_json: &str) {
+    let Some(url) = &config.result_webhook_url else {
+        return;
+    };
+
+    let client = reqwest::Client::new();
+    for attempt in 1..=ATTEMPTS {
+        let mut request = client
+            .post(url.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(total_json.to_string());
+        if let Some(secret) = &config.result_webhook_secret {
+            request = request.header("X-Webhook-Secret", secret);
+        }
+
+        match request.send().await.and_then(|res| res.error_for_status()) {
+            Ok(_) => {
+                println!("Sent totals to the result webhook");
+                return;
+            }
+            Err(e) => eprintln!("Result webhook attempt {attempt} of {ATTEMPTS} failed: {e}"),
+        }
+
+        if attempt < ATTEMPTS {
+            tokio::time::sleep(Duration::from_secs(2u64.pow(attempt - 1))).await;
+        }
+    }
+}